@@ -1,20 +1,288 @@
 
 use serde::Serialize;
-use atp_adapter_proto::atp::adapter::v1::{adapter_service_client::AdapterServiceClient, HealthRequest};
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use metrics::counter;
+use once_cell::sync::Lazy;
+use tonic::transport::{Channel, Endpoint};
+use atp_adapter_proto::atp::adapter::v1::{adapter_service_client::AdapterServiceClient, CapabilitiesRequest, HealthRequest};
+
+/// Timeouts and keepalive applied to every adapter channel.
+#[derive(Clone, Debug)]
+pub struct ChannelConfig { pub connect_timeout: Duration, pub timeout: Duration, pub keep_alive_interval: Duration }
+impl ChannelConfig {
+    /// Reads `ADAPTER_CONNECT_TIMEOUT_MS` (2000), `ADAPTER_TIMEOUT_MS` (30000) and `ADAPTER_KEEPALIVE_MS` (10000).
+    pub fn from_env() -> Self {
+        let ms = |k: &str, d: u64| Duration::from_millis(std::env::var(k).ok().and_then(|v| v.parse().ok()).unwrap_or(d));
+        ChannelConfig {
+            connect_timeout: ms("ADAPTER_CONNECT_TIMEOUT_MS", 2_000),
+            timeout: ms("ADAPTER_TIMEOUT_MS", 30_000),
+            keep_alive_interval: ms("ADAPTER_KEEPALIVE_MS", 10_000),
+        }
+    }
+}
+
+/// An adapter's stream of chunks, from its gRPC `Stream` call or a fake adapter.
+pub type ChunkStream = std::pin::Pin<Box<dyn futures_util::Stream<Item = Result<atp_adapter_proto::atp::adapter::v1::StreamChunk, tonic::Status>> + Send>>;
+
+/// Default fanout targets when `ADAPTER_ENDPOINTS` is unset (the compose adapters).
+pub const DEFAULT_ENDPOINTS: [&str; 2] = ["http://persona_adapter:7070", "http://ollama_adapter:7070"];
+
+/// Trims `ep` and drops trailing slashes, then requires an http(s) scheme and a host.
+pub fn normalize_endpoint(ep: &str) -> Result<String, String> {
+    let ep = ep.trim().trim_end_matches('/');
+    let uri: tonic::transport::Uri = ep.parse().map_err(|e| format!("{}", e))?;
+    match uri.scheme_str() { Some("http") | Some("https") => {}, Some(s) => return Err(format!("unsupported scheme {:?}", s)), None => return Err("missing scheme".into()) }
+    if uri.host().is_none_or(str::is_empty) { return Err("missing host".into()); }
+    Ok(ep.to_string())
+}
+
+/// Splits `raw` into normalized valid endpoints and `(entry, reason)` for the rejected ones.
+pub fn parse_endpoints(raw: &[String]) -> (Vec<String>, Vec<(String, String)>) {
+    let (mut ok, mut bad) = (vec![], vec![]);
+    for ep in raw {
+        match normalize_endpoint(ep) { Ok(e) => { if !ok.contains(&e) { ok.push(e); } } Err(reason) => bad.push((ep.clone(), reason)) }
+    }
+    (ok, bad)
+}
+
+/// Raw `ADAPTER_ENDPOINTS` JSON array, or [`DEFAULT_ENDPOINTS`] when unset; with fake adapters on, theirs instead.
+pub fn configured_endpoints() -> anyhow::Result<Vec<String>> {
+    if crate::fake::enabled() { return Ok(crate::fake::endpoints()); }
+    match std::env::var("ADAPTER_ENDPOINTS") {
+        Ok(s) => serde_json::from_str(&s).map_err(|e| anyhow::anyhow!("ADAPTER_ENDPOINTS must be a JSON array of URLs: {}", e)),
+        Err(_) => Ok(DEFAULT_ENDPOINTS.iter().map(|s| s.to_string()).collect()),
+    }
+}
+
+/// Startup check: logs and drops invalid endpoints, failing if none remain.
+pub fn validate_endpoints(raw: &[String]) -> anyhow::Result<Vec<String>> {
+    let (ok, bad) = parse_endpoints(raw);
+    for (ep, reason) in &bad { tracing::warn!(endpoint = %ep, %reason, "ignoring invalid adapter endpoint"); }
+    if ok.is_empty() { anyhow::bail!("no valid adapter endpoints in {:?}", raw); }
+    Ok(ok)
+}
+
+pub async fn connect(ep: &str) -> Result<AdapterServiceClient<Channel>, tonic::transport::Error> { connect_with(ep, &ChannelConfig::from_env()).await }
+pub async fn connect_with(ep: &str, cfg: &ChannelConfig) -> Result<AdapterServiceClient<Channel>, tonic::transport::Error> {
+    let channel = Endpoint::from_shared(ep.to_string())?
+        .connect_timeout(cfg.connect_timeout)
+        .timeout(cfg.timeout)
+        .http2_keep_alive_interval(cfg.keep_alive_interval)
+        .keep_alive_while_idle(true)
+        .connect().await?;
+    Ok(AdapterServiceClient::new(channel))
+}
+
+/// Router-wide token bucket capping how many adapter retries may happen, so retries can't turn an outage into a storm.
+pub struct RetryBudget { capacity: f64, refill_per_sec: f64, state: Mutex<(f64, Instant)> }
+impl RetryBudget {
+    pub fn new(capacity: u32, refill_per_sec: f64) -> Self { RetryBudget { capacity: capacity as f64, refill_per_sec, state: Mutex::new((capacity as f64, Instant::now())) } }
+    /// Takes one retry token if available.
+    pub fn try_acquire(&self) -> bool {
+        let mut st = self.state.lock().unwrap();
+        let now = Instant::now();
+        st.0 = (st.0 + now.duration_since(st.1).as_secs_f64() * self.refill_per_sec).min(self.capacity);
+        st.1 = now;
+        if st.0 < 1.0 { return false; }
+        st.0 -= 1.0;
+        true
+    }
+}
+
+fn env_num<T: std::str::FromStr>(k: &str, d: T) -> T { std::env::var(k).ok().and_then(|v| v.parse().ok()).unwrap_or(d) }
+
+/// Burst of `ADAPTER_RETRY_BUDGET` (20) retries, refilled at `ADAPTER_RETRY_RATE` (5) per second.
+pub static RETRY_BUDGET: Lazy<RetryBudget> = Lazy::new(|| RetryBudget::new(env_num("ADAPTER_RETRY_BUDGET", 20), env_num("ADAPTER_RETRY_RATE", 5.0)));
+
+/// Random delay in `[0, max)` so retries from concurrent requests don't line up.
+fn jitter(max: Duration) -> Duration {
+    let r = std::collections::hash_map::RandomState::new().build_hasher().finish();
+    Duration::from_nanos(r % (max.as_nanos() as u64).max(1))
+}
+
+/// Backoff before retry `attempt` (from 0): `base` doubled per attempt, capped at `max`.
+fn backoff(base: Duration, max: Duration, attempt: u32) -> Duration { base.saturating_mul(2u32.saturating_pow(attempt)).min(max) }
+
+/// Runs `op`, retrying failures up to `max_retries` times with jittered exponential backoff from `base`, capped at
+/// `max_backoff`. Every retry spends a token from `budget`; once it is empty the last error is returned immediately.
+pub async fn with_retries<T, E, F, Fut>(ep: &str, budget: &RetryBudget, max_retries: u32, base: Duration, max_backoff: Duration, mut op: F) -> Result<T, E>
+where F: FnMut() -> Fut, Fut: Future<Output = Result<T, E>> {
+    let mut attempt = 0;
+    loop {
+        let err = match op().await { Ok(v) => return Ok(v), Err(e) => e };
+        if attempt >= max_retries { return Err(err); }
+        if !budget.try_acquire() { counter!("adapter_retry_budget_exhausted_total", 1); return Err(err); }
+        counter!("adapter_retries_total", 1, "adapter" => ep.to_string());
+        let backoff = backoff(base, max_backoff, attempt);
+        tokio::time::sleep(backoff / 2 + jitter(backoff / 2)).await;
+        attempt += 1;
+    }
+}
+
+/// [`connect`] with up to `ADAPTER_MAX_RETRIES` (2) budgeted retries, backing off from `ADAPTER_RETRY_BACKOFF_MS` (50)
+/// up to `ADAPTER_RETRY_MAX_BACKOFF_MS` (2000).
+pub async fn connect_retrying(ep: &str) -> Result<AdapterServiceClient<Channel>, tonic::transport::Error> {
+    let base = Duration::from_millis(env_num("ADAPTER_RETRY_BACKOFF_MS", 50));
+    let max_backoff = Duration::from_millis(env_num("ADAPTER_RETRY_MAX_BACKOFF_MS", 2000));
+    with_retries(ep, &RETRY_BUDGET, env_num("ADAPTER_MAX_RETRIES", 2), base, max_backoff, || connect(ep)).await
+}
+
+/// Env var suffixes that may carry `ep`'s metadata, most specific first: host and port, then host alone,
+/// uppercased with non-alphanumerics as `_` (`http://persona_adapter:7070` → `PERSONA_ADAPTER_7070`, `PERSONA_ADAPTER`).
+fn metadata_names(ep: &str) -> Vec<String> {
+    let Ok(uri) = ep.parse::<tonic::codegen::http::Uri>() else { return vec![]; };
+    let Some(host) = uri.host() else { return vec![]; };
+    let name = |s: &str| s.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' }).collect::<String>();
+    let mut names = vec![name(host)];
+    if let Some(port) = uri.port_u16() { names.insert(0, format!("{}_{}", name(host), port)); }
+    names
+}
+
+/// Metadata for every RPC to `ep`, from `ADAPTER_HEADERS_<name>` (a JSON object of header → value; see
+/// [`metadata_names`]). Values are often credentials, so only header names are ever logged.
+pub fn metadata_for(ep: &str) -> tonic::metadata::MetadataMap { metadata_with(ep, |var| std::env::var(var).ok()) }
+
+/// [`metadata_for`], reading the `ADAPTER_HEADERS_*` variables through `env`.
+fn metadata_with(ep: &str, env: impl Fn(&str) -> Option<String>) -> tonic::metadata::MetadataMap {
+    let mut md = tonic::metadata::MetadataMap::new();
+    let Some((var, raw)) = metadata_names(ep).into_iter().find_map(|n| { let var = format!("ADAPTER_HEADERS_{n}"); env(&var).map(|v| (var, v)) }) else { return md; };
+    let Ok(headers) = serde_json::from_str::<std::collections::BTreeMap<String, String>>(&raw) else {
+        tracing::warn!(adapter = %ep, var = %var, "adapter headers are not a JSON object of strings; sending none");
+        return md;
+    };
+    for (k, v) in headers {
+        match (k.parse::<tonic::metadata::MetadataKey<tonic::metadata::Ascii>>(), v.parse::<tonic::metadata::MetadataValue<tonic::metadata::Ascii>>()) {
+            (Ok(key), Ok(value)) => { md.insert(key, value); }
+            _ => tracing::warn!(adapter = %ep, var = %var, header = %k, "skipping invalid adapter header (value redacted)"),
+        }
+    }
+    md
+}
+
+/// Every adapter RPC is built here, so configured metadata reaches estimate, stream, health and capabilities alike.
+pub fn request<T>(ep: &str, msg: T) -> tonic::Request<T> {
+    let mut req = tonic::Request::new(msg);
+    *req.metadata_mut() = metadata_for(ep);
+    req
+}
+
+/// Whether a connect failure was a timeout rather than a refusal or bad endpoint.
+pub fn is_timeout(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut cur = Some(err);
+    while let Some(e) = cur {
+        if e.downcast_ref::<std::io::Error>().is_some_and(|io| io.kind() == std::io::ErrorKind::TimedOut) || e.to_string().to_lowercase().contains("timed out") { return true; }
+        cur = e.source();
+    }
+    false
+}
+
 #[derive(Serialize)]
-pub struct AdapterHealth { pub endpoint: String, pub ok: bool, pub p95_ms: f64, pub error_rate: f64 }
+pub struct AdapterHealth { pub endpoint: String, pub ok: bool, pub p95_ms: f64, pub error_rate: f64, pub capabilities: Option<Capabilities>, pub draining: bool, pub open_streams: usize }
+
+/// What an adapter reported via the `Capabilities` RPC; empty lists and a zero context mean "no restriction".
+/// `unary_only` adapters are asked through `Complete` rather than `Stream`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Capabilities { pub task_types: Vec<String>, pub max_context_tokens: u64, pub payload_types: Vec<String>, pub languages: Vec<String>, pub unary_only: bool }
+impl Capabilities {
+    pub fn supports_task(&self, task_type: &str) -> bool { self.task_types.is_empty() || self.task_types.iter().any(|t| t.eq_ignore_ascii_case(task_type)) }
+    pub fn supports_payload(&self, payload_type: &str) -> bool { self.payload_types.is_empty() || self.payload_types.iter().any(|t| t == payload_type) }
+    /// Whether any requested language tag shares its primary subtag with a supported one (`ja-JP` matches `ja`).
+    pub fn supports_any_language(&self, requested: &[String]) -> bool {
+        let primary = |tag: &str| tag.split(['-', '_']).next().unwrap_or("").to_ascii_lowercase();
+        self.languages.is_empty() || requested.iter().any(|r| self.languages.iter().any(|l| primary(l) == primary(r)))
+    }
+}
+
+pub async fn fetch_capabilities(ep: &str) -> Result<Capabilities, String> {
+    let mut cli = connect(ep).await.map_err(|e| e.to_string())?;
+    let c = cli.capabilities(request(ep, CapabilitiesRequest{})).await.map_err(|s| s.message().to_string())?.into_inner();
+    Ok(Capabilities { task_types: c.task_types, max_context_tokens: c.max_context_tokens, payload_types: c.payload_types, languages: c.languages, unary_only: c.unary_only })
+}
+
+/// Last capabilities each endpoint reported. Adapters that haven't answered (or predate the RPC) have no entry
+/// and are treated as unrestricted.
+static CAPABILITIES: Lazy<Mutex<std::collections::HashMap<String, Capabilities>>> = Lazy::new(Default::default);
+pub fn cached_capabilities(ep: &str) -> Option<Capabilities> { CAPABILITIES.lock().unwrap().get(ep).cloned() }
+/// Drops cached entries so a later mock bound to a reused port starts unrestricted.
+#[cfg(test)]
+pub fn forget_capabilities(eps: &[String]) { let mut c = CAPABILITIES.lock().unwrap(); for ep in eps { c.remove(ep); } }
+
+/// Queries every endpoint and updates the cache; a failed query keeps the previous entry. Fake adapters have none.
+pub async fn refresh_capabilities(eps: &[String]) {
+    if crate::fake::enabled() { return; }
+    for ep in eps {
+        match fetch_capabilities(ep).await {
+            Ok(c) => { CAPABILITIES.lock().unwrap().insert(ep.clone(), c); }
+            Err(e) => { counter!("adapter_capabilities_errors_total", 1, "adapter" => ep.clone()); tracing::debug!(adapter = %ep, error = %e, "capabilities query failed"); }
+        }
+    }
+}
+
+/// Refreshes the capability and latency caches now and then every `ADAPTER_CAPABILITIES_REFRESH_SECS` (default 60).
+pub fn spawn_capabilities_refresh(eps: Vec<String>) -> tokio::task::JoinHandle<()> {
+    let every = Duration::from_secs(env_num("ADAPTER_CAPABILITIES_REFRESH_SECS", 60).max(1));
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(every);
+        loop { tick.tick().await; refresh_capabilities(&eps).await; check_endpoints(eps.clone()).await; }
+    })
+}
+
+/// Last p95 latency each endpoint's health check reported, for latency-weighted fanout.
+static P95_MS: Lazy<Mutex<std::collections::HashMap<String, f64>>> = Lazy::new(Default::default);
+pub fn cached_p95_ms() -> std::collections::HashMap<String, f64> { P95_MS.lock().unwrap().clone() }
+
+/// Endpoints taken out of rotation: new requests don't fan out to them, streams already open run to completion.
+static DRAINING: Lazy<Mutex<std::collections::HashSet<String>>> = Lazy::new(Default::default);
+pub fn is_draining(ep: &str) -> bool { DRAINING.lock().unwrap().contains(ep) }
+pub fn set_draining(ep: &str, draining: bool) {
+    let mut d = DRAINING.lock().unwrap();
+    if draining { d.insert(ep.to_string()); } else { d.remove(ep); }
+}
+
+/// Adapter streams currently open per endpoint; a draining endpoint at zero can be removed without cutting anyone off.
+static OPEN_STREAMS: Lazy<Mutex<std::collections::HashMap<String, usize>>> = Lazy::new(Default::default);
+pub fn open_streams(ep: &str) -> usize { OPEN_STREAMS.lock().unwrap().get(ep).copied().unwrap_or(0) }
+/// Counts one open stream to `ep` until dropped.
+pub struct StreamGuard(String);
+pub fn open_stream(ep: &str) -> StreamGuard { *OPEN_STREAMS.lock().unwrap().entry(ep.to_string()).or_default() += 1; StreamGuard(ep.to_string()) }
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        let mut open = OPEN_STREAMS.lock().unwrap();
+        if let Some(n) = open.get_mut(&self.0) { *n -= 1; if *n == 0 { open.remove(&self.0); } }
+    }
+}
 
 pub async fn check_endpoints(eps: Vec<String>) -> Vec<AdapterHealth> {
     let mut out = vec![];
     for ep in eps {
         let mut ok = false; let mut p95 = 0.0; let mut er = 0.0;
-        if let Ok(mut cli) = AdapterServiceClient::connect(ep.clone()).await {
-            if let Ok(resp) = cli.health(tonic::Request::new(HealthRequest{})).await {
+        if crate::fake::enabled() { ok = true; } else if let Ok(mut cli) = connect(&ep).await {
+            if let Ok(resp) = cli.health(request(&ep, HealthRequest{})).await {
                 let h = resp.into_inner();
                 ok = true; p95 = h.p95_ms; er = h.error_rate;
+                P95_MS.lock().unwrap().insert(ep.clone(), p95);
             }
         }
-        out.push(AdapterHealth{ endpoint: ep, ok, p95_ms: p95, error_rate: er });
+        let capabilities = cached_capabilities(&ep);
+        let (draining, open_streams) = (is_draining(&ep), open_streams(&ep));
+        out.push(AdapterHealth{ endpoint: ep, ok, p95_ms: p95, error_rate: er, capabilities, draining, open_streams });
     }
     out
 }
+
+#[cfg(test)]
+mod tests { use super::*;
+    #[tokio::test] async fn black_holed_adapter_fails_within_timeout() { let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap(); let ep = format!("http://{}", listener.local_addr().unwrap()); let _hold = tokio::spawn(async move { let mut conns = vec![]; while let Ok((c, _)) = listener.accept().await { conns.push(c); } }); let cfg = ChannelConfig{ connect_timeout: Duration::from_millis(200), timeout: Duration::from_millis(200), keep_alive_interval: Duration::from_secs(1) }; let started = std::time::Instant::now(); let res = match connect_with(&ep, &cfg).await { Ok(mut cli) => cli.health(tonic::Request::new(HealthRequest{})).await.map(|_| ()).map_err(|e| e.to_string()), Err(e) => Err(e.to_string()) }; assert!(res.is_err()); assert!(started.elapsed() < Duration::from_secs(2), "took {:?}", started.elapsed()); }
+    #[test] fn backoff_doubles_up_to_the_cap_without_overflow() { let (base, max) = (Duration::from_millis(50), Duration::from_secs(2)); assert_eq!([0, 1, 2].map(|a| backoff(base, max, a)), [50, 100, 200].map(Duration::from_millis)); assert_eq!(backoff(base, max, 6), max); assert_eq!(backoff(base, max, 32), max); assert_eq!(backoff(base, max, u32::MAX), max); }
+    #[tokio::test] async fn retries_stop_once_budget_is_exhausted() { let budget = RetryBudget::new(3, 0.0); let attempts = std::sync::atomic::AtomicUsize::new(0); for _ in 0..5 { let res: Result<(), &str> = with_retries("http://down", &budget, 2, Duration::from_millis(1), Duration::from_millis(4), || { attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst); async { Err("down") } }).await; assert!(res.is_err()); } assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 5 + 3); assert!(!budget.try_acquire()); }
+    #[tokio::test] async fn retry_budget_refills_over_time() { let budget = RetryBudget::new(1, 1000.0); assert!(budget.try_acquire()); tokio::time::sleep(Duration::from_millis(5)).await; assert!(budget.try_acquire()); }
+    #[test] fn valid_endpoints_are_normalized() { let raw = vec![" http://a:7070/ ".to_string(), "https://persona_adapter:7070".to_string(), "http://a:7070".to_string()]; assert_eq!(validate_endpoints(&raw).unwrap(), ["http://a:7070", "https://persona_adapter:7070"]); }
+    #[test] fn bad_endpoint_is_excluded() { let raw = vec!["http://a:7070".to_string(), "a:7070".to_string(), "ftp://b".to_string()]; let (ok, bad) = parse_endpoints(&raw); assert_eq!(ok, ["http://a:7070"]); assert_eq!(bad.len(), 2); assert_eq!(validate_endpoints(&raw).unwrap(), ["http://a:7070"]); }
+    #[test] fn all_bad_endpoints_fail_startup() { assert!(validate_endpoints(&["not a uri".to_string(), "//nohost".to_string()]).is_err()); assert!(validate_endpoints(&[]).is_err()); }
+    #[test] fn adapter_headers_are_keyed_by_endpoint_name() { assert_eq!(metadata_names("http://persona_adapter:7070"), ["PERSONA_ADAPTER_7070", "PERSONA_ADAPTER"]); let env = |var: &str| (var == "ADAPTER_HEADERS_KEYED_HOST").then(|| r#"{"x-api-key": "secret", "bad key": "x", "x-tenant-id": "t1"}"#.to_string()); let md = metadata_with("http://keyed.host:9000", env); assert_eq!((md.get("x-api-key").unwrap(), md.get("x-tenant-id").unwrap(), md.len()), (&"secret".parse::<tonic::metadata::MetadataValue<_>>().unwrap(), &"t1".parse::<tonic::metadata::MetadataValue<_>>().unwrap(), 2)); assert!(metadata_with("http://unconfigured:1", env).is_empty()); }
+    #[test] fn stream_guards_track_open_streams() { let ep = "http://guarded:7070"; let a = open_stream(ep); let b = open_stream(ep); assert_eq!(open_streams(ep), 2); drop(a); assert_eq!(open_streams(ep), 1); drop(b); assert_eq!(open_streams(ep), 0); assert!(!OPEN_STREAMS.lock().unwrap().contains_key(ep)); }
+    #[tokio::test] async fn invalid_endpoint_is_an_error() { assert!(connect("not a uri").await.is_err()); }
+}
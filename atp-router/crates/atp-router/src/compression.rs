@@ -0,0 +1,84 @@
+//! zstd payload compression for frames flagged COMPRESSED. A compressed payload's content is
+//! `{"zstd": "<base64>"}`, the zstd frame of the original content's JSON text.
+//!
+//! `ATP_ZSTD_DICT` names a dictionary trained on representative payloads (e.g. `zstd --train`); when set, it is used
+//! in both directions and templated results compress far better. Peers must share the same dictionary. Without one,
+//! plain zstd is used.
+
+use base64::Engine;
+use once_cell::sync::Lazy;
+use std::io::{self, Read};
+
+/// Decompressed content larger than this is refused, so a small frame cannot expand without bound.
+pub const MAX_DECOMPRESSED_BYTES: u64 = 16 << 20;
+const LEVEL: i32 = 3;
+
+pub struct PayloadCodec { dict: Option<Vec<u8>> }
+impl PayloadCodec {
+    pub fn new(dict: Option<Vec<u8>>) -> Self { PayloadCodec { dict } }
+    /// Loads `ATP_ZSTD_DICT`; an unreadable dictionary is logged and plain zstd used instead.
+    pub fn from_env() -> Self {
+        let dict = std::env::var("ATP_ZSTD_DICT").ok().filter(|p| !p.is_empty()).and_then(|path| match std::fs::read(&path) {
+            Ok(d) => { tracing::info!(path = %path, bytes = d.len(), "loaded zstd dictionary"); Some(d) }
+            Err(e) => { tracing::warn!(path = %path, error = %e, "zstd dictionary unreadable, compressing without one"); None }
+        });
+        Self::new(dict)
+    }
+    pub fn has_dict(&self) -> bool { self.dict.is_some() }
+    /// The compressed form of `content`.
+    pub fn compress(&self, content: &serde_json::Value) -> io::Result<serde_json::Value> {
+        let text = serde_json::to_vec(content)?;
+        let packed = match &self.dict {
+            Some(d) => zstd::bulk::Compressor::with_dictionary(LEVEL, d)?.compress(&text)?,
+            None => zstd::bulk::compress(&text, LEVEL)?,
+        };
+        Ok(serde_json::json!({"zstd": base64::engine::general_purpose::STANDARD.encode(packed)}))
+    }
+    /// The original content of a compressed payload.
+    pub fn decompress(&self, content: &serde_json::Value) -> io::Result<serde_json::Value> {
+        let encoded = content.get("zstd").and_then(|z| z.as_str()).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "content has no zstd string"))?;
+        let packed = base64::engine::general_purpose::STANDARD.decode(encoded).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        // An empty dictionary is no dictionary.
+        let text = read_capped(zstd::stream::read::Decoder::with_dictionary(packed.as_slice(), self.dict.as_deref().unwrap_or_default())?)?;
+        Ok(serde_json::from_slice(&text)?)
+    }
+}
+
+fn read_capped(r: impl Read) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    r.take(MAX_DECOMPRESSED_BYTES + 1).read_to_end(&mut out)?;
+    if out.len() as u64 > MAX_DECOMPRESSED_BYTES { return Err(io::Error::new(io::ErrorKind::InvalidData, "decompressed content too large")); }
+    Ok(out)
+}
+
+pub static CODEC: Lazy<PayloadCodec> = Lazy::new(PayloadCodec::from_env);
+
+#[cfg(test)]
+mod tests { use super::*;
+    fn samples() -> Vec<Vec<u8>> { (0..200).map(|i| serde_json::to_vec(&serde_json::json!({"title": format!("Quarterly report {i}"), "status": "complete", "summary": format!("Revenue grew {}% while costs held at {} units across all regions.", i % 17, i * 3)})).unwrap()).collect() }
+    #[test] fn dictionary_round_trip_beats_plain_zstd() {
+        let dict = zstd::dict::from_samples(&samples(), 4096).unwrap();
+        let (with, plain) = (PayloadCodec::new(Some(dict)), PayloadCodec::new(None));
+        let content = serde_json::json!({"title": "Quarterly report 977", "status": "complete", "summary": "Revenue grew 9% while costs held at 31 units across all regions."});
+        let packed = with.compress(&content).unwrap();
+        assert_eq!(with.decompress(&packed).unwrap(), content);
+        assert_eq!(plain.decompress(&plain.compress(&content).unwrap()).unwrap(), content);
+        let len = |v: &serde_json::Value| v["zstd"].as_str().unwrap().len();
+        assert!(len(&packed) < len(&plain.compress(&content).unwrap()), "{} vs plain", len(&packed));
+        assert!(plain.decompress(&packed).is_err(), "dictionary frames need the dictionary");
+    }
+    #[test] fn dictionary_loads_from_path_and_bad_input_is_rejected() {
+        let path = std::env::temp_dir().join(format!("atp-zstd-dict-{}", std::process::id()));
+        std::fs::write(&path, zstd::dict::from_samples(&samples(), 4096).unwrap()).unwrap();
+        std::env::set_var("ATP_ZSTD_DICT", &path);
+        let codec = PayloadCodec::from_env();
+        std::env::set_var("ATP_ZSTD_DICT", path.with_extension("missing"));
+        assert!(!PayloadCodec::from_env().has_dict());
+        std::env::remove_var("ATP_ZSTD_DICT");
+        let _ = std::fs::remove_file(&path);
+        assert!(codec.has_dict());
+        assert_eq!(codec.decompress(&codec.compress(&serde_json::json!("hi")).unwrap()).unwrap(), serde_json::json!("hi"));
+        assert!(codec.decompress(&serde_json::json!({"text": "hi"})).is_err());
+        assert!(codec.decompress(&serde_json::json!({"zstd": "not base64!"})).is_err());
+    }
+}
@@ -1,41 +1,418 @@
+use once_cell::sync::Lazy;
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+
+/// How two answers are compared when grouping them into consensus clusters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SimilarityMetric {
+    /// Cosine similarity of normalized hashed bag-of-words embeddings.
+    #[default]
+    Cosine,
+    /// Jaccard index of normalized token sets.
+    Jaccard,
+    /// Dot product of hashed term counts over the smaller answer's self-dot, capped at 1: roughly the share of
+    /// the shorter answer's tokens that the other repeats. Identical answers score 1 however short they are.
+    Dot,
+}
+impl SimilarityMetric {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "cosine" => Some(Self::Cosine),
+            "jaccard" => Some(Self::Jaccard),
+            "dot" => Some(Self::Dot),
+            _ => None,
+        }
+    }
+    /// Grouping threshold for this metric, used when no explicit threshold is configured.
+    pub fn default_threshold(self) -> f32 {
+        match self { Self::Cosine => 0.85, Self::Jaccard => 0.7, Self::Dot => 0.8 }
+    }
+}
+
+/// How groups with equal scores are ordered in `ranked`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TieBreak {
+    /// Keep group creation order.
+    #[default]
+    Index,
+    /// Cheapest group first, by the lowest `usd_micros` among its members.
+    Cost,
+    /// Most confident group first, by the highest adapter confidence among its members.
+    Confidence,
+}
+impl TieBreak {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "index" => Some(Self::Index),
+            "cost" => Some(Self::Cost),
+            "confidence" => Some(Self::Confidence),
+            _ => None,
+        }
+    }
+}
+
+/// What the router knows about the adapter that produced one final.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FinalMeta { pub usd_micros: Option<u64>, pub confidence: Option<f32> }
+
+#[derive(Clone, Debug, Default)]
+pub struct ConsensusConfig {
+    pub metric: SimilarityMetric,
+    /// Minimum similarity for an answer to join a group.
+    pub threshold: Option<f32>,
+    /// Compare JSON finals by canonical `path=value` leaves instead of as flat text.
+    pub structured: bool,
+    pub tie_break: TieBreak,
+    /// When set, replaces the fixed threshold with one that tightens as the number of finals grows.
+    pub adaptive: Option<AdaptiveThreshold>,
+    /// Reproducible mode for audits: finals are sorted before grouping and per-final metadata is ignored, so the
+    /// result depends only on the multiset of texts. Indices then refer to the sorted order.
+    pub audit: bool,
+    /// Tokens of each final that are hashed; `None` means [`DEFAULT_MAX_TOKENS`].
+    pub max_tokens: Option<usize>,
+}
+impl ConsensusConfig {
+    pub fn threshold(&self) -> f32 { self.threshold.unwrap_or(self.metric.default_threshold()) }
+    pub fn max_tokens(&self) -> usize { self.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS) }
+    /// Threshold used to group `n` finals: the fixed one, or the adaptive bound for `n`.
+    pub fn threshold_for(&self, n: usize) -> f32 { self.adaptive.map(|a| a.at(n)).unwrap_or_else(|| self.threshold()) }
+}
+
+/// Interpolates linearly from `loose` at two finals to `strict` at `strict_at` finals and beyond.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+pub struct AdaptiveThreshold { pub loose: f32, pub strict: f32, pub strict_at: usize }
+impl AdaptiveThreshold {
+    /// Parses `loose:strict[:strict_at]` (e.g. `0.6:0.9:10`); `strict_at` defaults to 10.
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.trim().split(':');
+        let loose: f32 = parts.next()?.trim().parse().ok()?;
+        let strict: f32 = parts.next()?.trim().parse().ok()?;
+        let strict_at = match parts.next() { Some(n) => n.trim().parse().ok()?, None => 10 };
+        (parts.next().is_none() && loose.is_finite() && strict.is_finite() && strict_at > 2).then_some(Self { loose, strict, strict_at })
+    }
+    pub fn at(&self, n: usize) -> f32 {
+        let t = (n.clamp(2, self.strict_at) - 2) as f32 / (self.strict_at - 2) as f32;
+        self.loose + (self.strict - self.loose) * t
+    }
+}
+
+/// Per-final token budget. Consensus is lossy beyond it: only the first tokens of a longer final are compared,
+/// so two answers differing only past the cap group together, but one huge final cannot stall grouping.
+pub const DEFAULT_MAX_TOKENS: usize = 4096;
+
+/// Tolerance below the threshold that still counts as a match, absorbing float summation noise.
+pub const SIMILARITY_EPSILON: f32 = 1e-4;
 
 fn normalize(s: &str) -> String { s.to_lowercase().replace(|c: char| !c.is_alphanumeric() && c!=' ', " ") }
-fn embed(s: &str, dim: usize) -> Vec<f32> {
+/// Lowercased alphanumeric runs, as `normalize` then whitespace splitting would give, stopping after `max` so the
+/// rest of a huge final is never scanned.
+fn text_tokens(s: &str, max: usize) -> Vec<String> {
+    let mut out = vec![];
+    for run in s.split(|c: char| !c.is_alphanumeric()).filter(|r| !r.is_empty()) {
+        for t in run.to_lowercase().split(|c: char| !c.is_alphanumeric()).filter(|t| !t.is_empty()) {
+            if out.len() == max { return out; }
+            out.push(t.to_string());
+        }
+    }
+    out
+}
+
+/// Parses a final as a JSON object/array, unwrapping one level of string encoding (adapters send `content_json`).
+fn parse_structured(s: &str) -> Option<serde_json::Value> {
+    let v: serde_json::Value = serde_json::from_str(s).ok()?;
+    let v = match v { serde_json::Value::String(inner) => serde_json::from_str(&inner).ok()?, v => v };
+    (v.is_object() || v.is_array()).then_some(v)
+}
+/// Flattens JSON into `path=value` leaves with numbers and string text normalized, so key order, `1` vs `1.0` and casing don't matter.
+fn json_leaves(v: &serde_json::Value, path: &str, out: &mut Vec<String>) {
+    match v {
+        serde_json::Value::Object(m) => for (k, child) in m { json_leaves(child, &format!("{}/{}", path, k), out) },
+        serde_json::Value::Array(a) => for (i, child) in a.iter().enumerate() { json_leaves(child, &format!("{}/{}", path, i), out) },
+        serde_json::Value::Number(n) => out.push(format!("{}={}", path, n.as_f64().map(|f| f.to_string()).unwrap_or_else(|| n.to_string()))),
+        serde_json::Value::String(s) => out.push(format!("{}={}", path, normalize(s).split_whitespace().collect::<Vec<_>>().join(" "))),
+        other => out.push(format!("{}={}", path, other)),
+    }
+}
+fn tokens(s: &str, structured: bool, max: usize) -> Vec<String> {
+    match structured.then(|| parse_structured(s)).flatten() {
+        Some(v) => { let mut leaves = vec![]; json_leaves(&v, "", &mut leaves); leaves.sort(); leaves.truncate(max); leaves }
+        None => text_tokens(s, max),
+    }
+}
+
+/// Version of the tokenize/hash/embed scheme. Bump whenever a change could move any answer between groups
+/// (tokenization, hash constants, dimension, normalization), so consumers can tell results apart.
+pub const EMBED_VERSION: u32 = 3;
+
+fn term_counts(tokens: &[String], dim: usize) -> Vec<f32> {
     let mut v = vec![0f32; dim];
-    for token in normalize(s).split_whitespace() {
+    for token in tokens {
         let mut x: u64 = 1469598103934665603;
         for b in token.as_bytes() { x ^= *b as u64; x = x.wrapping_mul(1099511628211); }
         let idx = (x % dim as u64) as usize;
         v[idx] += 1.0;
     }
+    v
+}
+fn embed(tokens: &[String], dim: usize) -> Vec<f32> {
+    let mut v = term_counts(tokens, dim);
     let n = (v.iter().map(|x| x*x).sum::<f32>()).sqrt().max(1e-6);
     for x in &mut v { *x /= n; }
     v
 }
-fn cosine(a: &[f32], b: &[f32]) -> f32 { a.iter().zip(b).map(|(x,y)| x*y).sum() }
+fn dot(a: &[f32], b: &[f32]) -> f32 { a.iter().zip(b).map(|(x,y)| *x as f64 * *y as f64).sum::<f64>() as f32 }
+/// [`SimilarityMetric::Dot`] of two term-count vectors.
+fn overlap(a: &[f32], b: &[f32]) -> f32 {
+    let shorter = dot(a, a).min(dot(b, b));
+    if shorter == 0.0 { 0.0 } else { (dot(a, b) / shorter).min(1.0) }
+}
+fn jaccard(a: &HashSet<String>, b: &HashSet<String>) -> f32 {
+    let union = a.union(b).count();
+    if union == 0 { return 0.0; }
+    a.intersection(b).count() as f32 / union as f32
+}
 
+enum Features { Dense(Vec<f32>), Counts(Vec<f32>), Tokens(HashSet<String>) }
+fn features(s: &str, cfg: &ConsensusConfig, dim: usize) -> Features {
+    let tokens = tokens(s, cfg.structured, cfg.max_tokens());
+    match cfg.metric {
+        SimilarityMetric::Cosine => Features::Dense(embed(&tokens, dim)),
+        SimilarityMetric::Dot => Features::Counts(term_counts(&tokens, dim)),
+        SimilarityMetric::Jaccard => Features::Tokens(tokens.into_iter().collect()),
+    }
+}
+fn similarity(a: &Features, b: &Features) -> f32 {
+    match (a, b) {
+        (Features::Dense(x), Features::Dense(y)) => dot(x, y),
+        (Features::Counts(x), Features::Counts(y)) => overlap(x, y),
+        (Features::Tokens(x), Features::Tokens(y)) => jaccard(x, y),
+        _ => 0.0,
+    }
+}
+
+/// A group's representative answer together with the group's share of all finals.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct Representative { pub index: usize, pub text: String, pub score: f32, pub group_size: usize }
+
+#[derive(serde::Serialize)]
 pub struct ConsensusResult {
     pub finals: Vec<String>,
     pub representatives: Vec<(usize, String)>,
     pub groups: Vec<Vec<usize>>,
     pub scores: Vec<f32>,
+    /// One entry per group, highest score first (ties ordered by `ConsensusConfig::tie_break`).
+    pub ranked: Vec<Representative>,
+    /// [`EMBED_VERSION`] of the scheme that produced `groups`.
+    pub embed_version: u32,
 }
-pub fn compute(finals_json: &[String]) -> ConsensusResult {
+/// Greedily groups finals: each answer joins the earliest-created group whose representative it matches
+/// within `SIMILARITY_EPSILON` of the threshold, otherwise it starts a new group.
+pub fn compute(finals_json: &[String], cfg: &ConsensusConfig) -> ConsensusResult { compute_with(finals_json, &[], cfg) }
+
+/// [`compute`] with per-final adapter metadata (aligned with `finals_json`) for cost/confidence tie-breaks.
+pub fn compute_with(finals_json: &[String], meta: &[FinalMeta], cfg: &ConsensusConfig) -> ConsensusResult {
+    if cfg.audit {
+        let mut sorted = finals_json.to_vec();
+        sorted.sort();
+        return compute_with(&sorted, &[], &ConsensusConfig { audit: false, tie_break: TieBreak::Index, ..cfg.clone() });
+    }
     let dim = 128;
-    let mut vecs = vec![]; let mut finals = vec![];
+    let threshold = cfg.threshold_for(finals_json.len()) - SIMILARITY_EPSILON;
+    let mut feats = vec![]; let mut finals = vec![];
     for s in finals_json {
-        vecs.push(embed(s, dim));
+        feats.push(features(s, cfg, dim));
         finals.push(s.clone());
     }
     let mut groups: Vec<Vec<usize>> = vec![]; let mut reps: Vec<usize> = vec![];
-    for i in 0..vecs.len() {
+    for i in 0..feats.len() {
         let mut placed = false;
         for (gidx, rep) in reps.iter().enumerate() {
-            if cosine(&vecs[i], &vecs[*rep]) >= 0.85 { groups[gidx].push(i); placed = true; break; }
+            if similarity(&feats[i], &feats[*rep]) >= threshold { groups[gidx].push(i); placed = true; break; }
         }
         if !placed { reps.push(i); groups.push(vec![i]); }
     }
-    let scores = groups.iter().map(|g| (g.len() as f32) / (finals.len().max(1) as f32)).collect();
+    let scores: Vec<f32> = groups.iter().map(|g| (g.len() as f32) / (finals.len().max(1) as f32)).collect();
     let representatives = reps.iter().map(|i| (*i, finals[*i].clone())).collect();
-    ConsensusResult { finals, representatives, groups, scores }
+    let mut ranked: Vec<Representative> = reps.iter().zip(&groups).zip(&scores)
+        .map(|((i, g), score)| Representative { index: *i, text: finals[*i].clone(), score: *score, group_size: g.len() })
+        .collect();
+    let group_of = |rep: &Representative| &groups[reps.iter().position(|r| *r == rep.index).unwrap_or(0)];
+    let min_cost = |rep: &Representative| group_of(rep).iter().filter_map(|i| meta.get(*i).and_then(|m| m.usd_micros)).min().unwrap_or(u64::MAX);
+    let max_conf = |rep: &Representative| group_of(rep).iter().filter_map(|i| meta.get(*i).and_then(|m| m.confidence)).fold(f32::NEG_INFINITY, f32::max);
+    ranked.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| match cfg.tie_break {
+        TieBreak::Index => std::cmp::Ordering::Equal,
+        TieBreak::Cost => min_cost(a).cmp(&min_cost(b)),
+        TieBreak::Confidence => max_conf(b).total_cmp(&max_conf(a)),
+    }));
+    ConsensusResult { finals, representatives, groups, scores, ranked, embed_version: EMBED_VERSION }
+}
+
+/// Pluggable agreement logic (numeric averaging, code-diff similarity, ...) used by the router in place of
+/// [`compute_with`]; install one with `RouterBuilder::consensus`. Like frame transforms it is process-wide.
+pub trait ConsensusFn: Send + Sync {
+    /// `meta` is aligned with `finals` when the router knows the producing adapters, and empty otherwise.
+    fn compute(&self, finals: &[String], meta: &[FinalMeta], cfg: &ConsensusConfig) -> ConsensusResult;
+}
+
+/// The built-in similarity grouping, [`compute_with`].
+pub struct BuiltIn;
+impl ConsensusFn for BuiltIn {
+    fn compute(&self, finals: &[String], meta: &[FinalMeta], cfg: &ConsensusConfig) -> ConsensusResult { compute_with(finals, meta, cfg) }
+}
+
+static CONSENSUS_FN: Lazy<RwLock<Arc<dyn ConsensusFn>>> = Lazy::new(|| RwLock::new(Arc::new(BuiltIn)));
+
+/// Replaces the consensus function for every later request.
+pub fn install(f: impl ConsensusFn + 'static) { install_shared(Arc::new(f)) }
+pub(crate) fn install_shared(f: Arc<dyn ConsensusFn>) { *CONSENSUS_FN.write().unwrap() = f; }
+
+/// Runs the installed consensus function.
+pub(crate) fn run(finals: &[String], meta: &[FinalMeta], cfg: &ConsensusConfig) -> ConsensusResult {
+    let f = CONSENSUS_FN.read().unwrap().clone();
+    f.compute(finals, meta, cfg)
+}
+
+/// How one group moved between a provisional result and the final one.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct GroupChange {
+    /// `grew`, `shrank`, `unchanged`, `merged`, `appeared` or `disappeared`.
+    pub change: &'static str,
+    pub provisional_groups: Vec<usize>,
+    pub final_group: Option<usize>,
+    pub from: f32,
+    pub to: f32,
+}
+
+/// Compares provisional and final groups by the answers they share. An answer keeps its identity when the final
+/// holds the same text at the same index, which holds because finals are only ever appended or replaced in place.
+pub fn stability(provisional: &ConsensusResult, fin: &ConsensusResult) -> Vec<GroupChange> {
+    let final_group_of = |i: usize| -> Option<usize> {
+        if fin.finals.get(i) != provisional.finals.get(i) { return None; }
+        fin.groups.iter().position(|g| g.contains(&i))
+    };
+    let mut changes = vec![];
+    let mut matched_prov = HashSet::new();
+    for (fg, to) in fin.scores.iter().copied().enumerate() {
+        let mut prov: Vec<usize> = provisional.groups.iter().enumerate()
+            .filter(|(_, pg)| pg.iter().any(|i| final_group_of(*i) == Some(fg)))
+            .map(|(p, _)| p).collect();
+        prov.dedup();
+        let from: f32 = prov.iter().map(|p| provisional.scores[*p]).sum();
+        let change = match prov.len() {
+            0 => "appeared",
+            1 if to > from + SIMILARITY_EPSILON => "grew",
+            1 if to + SIMILARITY_EPSILON < from => "shrank",
+            1 => "unchanged",
+            _ => "merged",
+        };
+        matched_prov.extend(prov.iter().copied());
+        changes.push(GroupChange { change, provisional_groups: prov, final_group: Some(fg), from, to });
+    }
+    for (p, score) in provisional.scores.iter().enumerate().filter(|(p, _)| !matched_prov.contains(p)) {
+        changes.push(GroupChange { change: "disappeared", provisional_groups: vec![p], final_group: None, from: *score, to: 0.0 });
+    }
+    changes
+}
+
+/// A ranked representative that differs between two results; `None` where that side has no group at this rank.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct RepresentativeChange { pub rank: usize, pub before: Option<String>, pub after: Option<String> }
+
+/// Offline comparison of two consensus runs (e.g. before and after a threshold or embedding change), aligned by rank.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct ConsensusDiff {
+    pub groups_before: usize,
+    pub groups_after: usize,
+    pub representative_changes: Vec<RepresentativeChange>,
+    /// `after - before` score per rank, for ranks present in both results.
+    pub score_deltas: Vec<f32>,
+}
+
+impl ConsensusResult {
+    /// Representatives of groups scoring at least `min_score`; smaller groups still count toward every score.
+    pub fn representatives_at_least(&self, min_score: f32) -> Vec<(usize, String)> {
+        self.representatives.iter().zip(&self.scores).filter(|(_, s)| **s + SIMILARITY_EPSILON >= min_score).map(|(r, _)| r.clone()).collect()
+    }
+    /// What changed going from `self` to `other`.
+    pub fn diff(&self, other: &ConsensusResult) -> ConsensusDiff {
+        let text = |r: &ConsensusResult, rank: usize| r.ranked.get(rank).map(|rep| rep.text.clone());
+        let representative_changes = (0..self.ranked.len().max(other.ranked.len()))
+            .map(|rank| RepresentativeChange { rank, before: text(self, rank), after: text(other, rank) })
+            .filter(|c| c.before != c.after)
+            .collect();
+        let score_deltas = self.ranked.iter().zip(&other.ranked).map(|(a, b)| b.score - a.score).collect();
+        ConsensusDiff { groups_before: self.groups.len(), groups_after: other.groups.len(), representative_changes, score_deltas }
+    }
+}
+
+#[cfg(test)]
+mod tests { use super::*; use proptest::prelude::*;
+    const A: &str = "the quick brown fox jumps high";
+    const B: &str = "the quick brown fox jumps far";
+    #[test] fn cosine_narrowly_misses_near_duplicates() { let c = dot(&embed(&text_tokens(A, DEFAULT_MAX_TOKENS), 128), &embed(&text_tokens(B, DEFAULT_MAX_TOKENS), 128)); assert!(c < 0.85 && c > 0.8, "cosine {c}"); assert_eq!(compute(&[A.into(), B.into()], &ConsensusConfig::default()).groups.len(), 2); }
+    #[test] fn jaccard_groups_near_duplicates() { let cfg = ConsensusConfig{ metric: SimilarityMetric::Jaccard, threshold: None, ..Default::default() }; let r = compute(&[A.into(), B.into()], &cfg); assert_eq!(r.groups, vec![vec![0, 1]]); assert_eq!(r.scores, vec![1.0]); }
+    #[test] fn thresholds_are_per_metric() { assert_eq!(ConsensusConfig::default().threshold(), 0.85); let dot_cfg = ConsensusConfig{ metric: SimilarityMetric::Dot, threshold: None, ..Default::default() }; assert_eq!(compute(&[A.into(), B.into()], &dot_cfg).groups.len(), 1); let strict = ConsensusConfig{ metric: SimilarityMetric::Dot, threshold: Some(0.9), ..Default::default() }; assert_eq!(compute(&[A.into(), B.into()], &strict).groups.len(), 2); }
+    #[test] fn diff_reports_merge_of_two_groups() { let finals: Vec<String> = vec![A.into(), B.into()]; let split = compute(&finals, &ConsensusConfig::default()); let merged = compute(&finals, &ConsensusConfig{ metric: SimilarityMetric::Jaccard, ..Default::default() }); let d = split.diff(&merged); assert_eq!((d.groups_before, d.groups_after), (2, 1)); assert_eq!(d.representative_changes, vec![RepresentativeChange{ rank: 1, before: Some(B.into()), after: None }]); assert_eq!(d.score_deltas, vec![0.5]); assert_eq!(serde_json::to_value(&d).unwrap()["groups_after"], 1); let same = merged.diff(&merged); assert!(same.representative_changes.is_empty() && same.score_deltas == vec![0.0]); }
+    #[test] fn representatives_below_min_score_are_omitted() { let finals: Vec<String> = ["answer forty two", "answer forty two", "paris capital france"].iter().map(|s| s.to_string()).collect(); let r = compute(&finals, &ConsensusConfig::default()); assert_eq!(r.representatives_at_least(0.5), vec![(0, finals[0].clone())]); assert_eq!(r.representatives_at_least(1.0 / 3.0), r.representatives); assert_eq!(r.scores.len(), 2); }
+    #[test] fn near_threshold_match_is_tolerated() { let a = embed(&text_tokens(A, DEFAULT_MAX_TOKENS), 128); let b = embed(&text_tokens(B, DEFAULT_MAX_TOKENS), 128); let c = dot(&a, &b); let cfg = ConsensusConfig{ metric: SimilarityMetric::Cosine, threshold: Some(c + SIMILARITY_EPSILON / 2.0), ..Default::default() }; assert_eq!(compute(&[A.into(), B.into()], &cfg).groups.len(), 1); }
+    proptest! { #[test] fn prop_shuffled_identical_finals_keep_group_count(picks in prop::collection::vec(0usize..6, 1..24).prop_shuffle(), metric in prop_oneof![Just(SimilarityMetric::Cosine), Just(SimilarityMetric::Jaccard), Just(SimilarityMetric::Dot)]) { const POOL: [&str; 6] = ["paris capital france", "answer forty two", "water boils hundred celsius", "balanced binary search tree", "42", "yes indeed"]; let finals: Vec<String> = picks.iter().map(|i| POOL[*i].to_string()).collect(); let mut sorted = finals.clone(); sorted.sort(); let cfg = ConsensusConfig{ metric, threshold: None, ..Default::default() }; let distinct = { let mut d = picks.clone(); d.sort(); d.dedup(); d.len() }; prop_assert_eq!(compute(&finals, &cfg).groups.len(), distinct); prop_assert_eq!(compute(&sorted, &cfg).groups.len(), distinct); } }
+    #[test] fn equal_score_groups_ordered_by_cost() { let finals: Vec<String> = ["paris capital france", "answer forty two", "water boils hundred celsius"].iter().map(|s| s.to_string()).collect(); let meta = [FinalMeta{ usd_micros: Some(900), confidence: Some(0.9) }, FinalMeta{ usd_micros: Some(100), confidence: Some(0.2) }, FinalMeta{ usd_micros: None, confidence: Some(0.5) }]; let order = |tie_break| compute_with(&finals, &meta, &ConsensusConfig{ tie_break, ..Default::default() }).ranked.iter().map(|r| r.index).collect::<Vec<_>>(); assert_eq!(order(TieBreak::Cost), [1, 0, 2]); assert_eq!(order(TieBreak::Confidence), [0, 2, 1]); assert_eq!(order(TieBreak::Index), [0, 1, 2]); }
+    #[test] fn tie_break_never_outranks_higher_score() { let finals: Vec<String> = ["answer forty two", "paris capital france", "paris capital france"].iter().map(|s| s.to_string()).collect(); let meta = [FinalMeta{ usd_micros: Some(1), confidence: None }, FinalMeta{ usd_micros: Some(500), confidence: None }, FinalMeta::default()]; let r = compute_with(&finals, &meta, &ConsensusConfig{ tie_break: TieBreak::Cost, ..Default::default() }); assert_eq!(r.ranked[0].index, 1); }
+    #[test] fn stability_reports_merged_groups() { let finals: Vec<String> = [A, B].iter().map(|s| s.to_string()).collect(); let prov = compute(&finals, &ConsensusConfig::default()); assert_eq!(prov.groups.len(), 2); let mut more = finals.clone(); more.push("an unrelated third answer".into()); let fin = compute(&more, &ConsensusConfig{ metric: SimilarityMetric::Jaccard, ..Default::default() }); assert_eq!(fin.groups, vec![vec![0, 1], vec![2]]); let report = stability(&prov, &fin); assert_eq!(report[0], GroupChange{ change: "merged", provisional_groups: vec![0, 1], final_group: Some(0), from: 1.0, to: fin.scores[0] }); assert_eq!((report[1].change, report[1].final_group), ("appeared", Some(1))); assert_eq!(report.len(), 2); }
+    #[test] fn stability_tracks_growth_and_loss() { let prov = compute(&["x y z".into(), "p q r".into()], &ConsensusConfig::default()); let fin = compute(&["x y z".into(), "replaced".into(), "x y z".into()], &ConsensusConfig::default()); let report = stability(&prov, &fin); let kinds: Vec<_> = report.iter().map(|c| c.change).collect(); assert_eq!(kinds, ["grew", "appeared", "disappeared"]); assert_eq!(report[2].provisional_groups, [1]); }
+    #[test] fn audit_mode_is_independent_of_order_and_metadata() {
+        let mut finals: Vec<String> = ["paris capital france", "answer forty two", "Paris capital France!", "water boils hundred celsius", "answer forty two"].iter().map(|s| s.to_string()).collect();
+        let cfg = ConsensusConfig{ tie_break: TieBreak::Confidence, audit: true, ..Default::default() };
+        let baseline = serde_json::to_string(&compute(&finals, &cfg)).unwrap();
+        for k in 1..finals.len() {
+            finals.rotate_left(k);
+            finals.swap(0, k);
+            let meta: Vec<FinalMeta> = (0..finals.len()).map(|i| FinalMeta{ usd_micros: Some((i * 37 % 11) as u64), confidence: Some(i as f32 / 10.0) }).collect();
+            assert_eq!(serde_json::to_string(&compute_with(&finals, &meta, &cfg)).unwrap(), baseline);
+        }
+    }
+    #[test] fn structured_groups_key_reordered_json() { let cfg = ConsensusConfig{ structured: true, ..Default::default() }; let a = serde_json::to_string(r#"{"tool":"search","args":{"q":"Rust","limit":10}}"#).unwrap(); let b = serde_json::to_string(r#"{ "args": {"limit": 10.0, "q": "rust"}, "tool": "search" }"#).unwrap(); assert_eq!(compute(&[a, b], &cfg).groups, vec![vec![0, 1]]); }
+    #[test] fn structured_distinguishes_swapped_values() { let a = r#"{"from":"alice","to":"bob"}"#.to_string(); let b = r#"{"from":"bob","to":"alice"}"#.to_string(); assert_eq!(compute(&[a.clone(), b.clone()], &ConsensusConfig::default()).groups.len(), 1); assert_eq!(compute(&[a, b], &ConsensusConfig{ structured: true, ..Default::default() }).groups.len(), 2); }
+    #[test] fn structured_falls_back_to_text_for_non_json() { let cfg = ConsensusConfig{ structured: true, ..Default::default() }; assert_eq!(compute(&["\"Paris is the capital\"".into(), "\"paris is the capital!\"".into()], &cfg).groups.len(), 1); }
+    #[test] fn ranked_representatives_carry_group_scores() { let finals: Vec<String> = ["lone answer here", "the answer is 42", "The answer is 42!", "the answer is 42"].iter().map(|s| s.to_string()).collect(); let r = compute(&finals, &ConsensusConfig::default()); assert_eq!(r.ranked.len(), r.groups.len()); for rep in &r.ranked { let g = r.groups.iter().position(|g| g[0] == rep.index).unwrap(); assert_eq!(rep.score, r.scores[g]); assert_eq!(rep.group_size, r.groups[g].len()); assert_eq!(rep.text, finals[rep.index]); } assert_eq!((r.ranked[0].index, r.ranked[0].score, r.ranked[0].group_size), (1, 0.75, 3)); assert_eq!(r.ranked[1].index, 0); }
+    #[test] fn dot_groups_identical_short_answers() {
+        let finals: Vec<String> = ["42", "42", "43", "yes", "yes"].iter().map(|s| s.to_string()).collect();
+        let r = compute(&finals, &ConsensusConfig{ metric: SimilarityMetric::Dot, ..Default::default() });
+        assert_eq!(r.groups, vec![vec![0, 1], vec![2], vec![3, 4]]);
+        let counts = |s: &str| term_counts(&text_tokens(s, DEFAULT_MAX_TOKENS), 128);
+        assert_eq!(overlap(&counts("yes"), &counts("yes yes yes")), 1.0, "capped at 1");
+        assert_eq!(overlap(&counts(""), &counts("yes")), 0.0);
+    }
+    #[test] fn parse_metric_names() { assert_eq!(SimilarityMetric::parse(" Jaccard "), Some(SimilarityMetric::Jaccard)); assert_eq!(SimilarityMetric::parse("dot"), Some(SimilarityMetric::Dot)); assert_eq!(SimilarityMetric::parse("euclid"), None); }
+    #[test] fn adaptive_threshold_loosens_for_few_answers() {
+        const X: &str = "the capital of france is paris"; const Y: &str = "paris is the french capital city";
+        let sim = dot(&embed(&text_tokens(X, DEFAULT_MAX_TOKENS), 128), &embed(&text_tokens(Y, DEFAULT_MAX_TOKENS), 128));
+        let adaptive = AdaptiveThreshold { loose: sim - 0.05, strict: sim + 0.05, strict_at: 6 };
+        assert_eq!(AdaptiveThreshold::parse(&format!("{}:{}:6", adaptive.loose, adaptive.strict)), Some(adaptive));
+        assert!(AdaptiveThreshold::parse("0.6").is_none() && AdaptiveThreshold::parse("0.6:0.9:2").is_none());
+        let fixed = ConsensusConfig{ threshold: Some(0.85), ..Default::default() };
+        let cfg = ConsensusConfig{ adaptive: Some(adaptive), ..fixed.clone() };
+        assert_eq!(compute(&[X.into(), Y.into()], &fixed).groups.len(), 2);
+        assert_eq!(compute(&[X.into(), Y.into()], &cfg).groups, vec![vec![0, 1]]);
+        let many: Vec<String> = [X, Y, "answer forty two", "water boils at one hundred", "binary search tree", "rust borrow checker"].iter().map(|s| s.to_string()).collect();
+        let r = compute(&many, &cfg);
+        assert_eq!(cfg.threshold_for(many.len()), adaptive.strict);
+        assert_eq!(r.groups.len(), 6);
+    }
+    #[test] fn huge_final_is_embedded_within_token_budget() { let huge = "lorem ipsum dolor ".repeat(1_000_000); assert_eq!(text_tokens(&huge, 64).len(), 64); let cfg = ConsensusConfig{ max_tokens: Some(64), ..Default::default() }; let finals: Vec<String> = vec![huge.clone(), format!("{huge} tail"), A.into()]; let r = compute(&finals, &cfg); assert_eq!(r.groups, vec![vec![0, 1], vec![2]]); assert_eq!(ConsensusConfig::default().max_tokens(), DEFAULT_MAX_TOKENS); }
+    #[test] fn results_carry_embed_version() { let r = compute(&[A.into()], &ConsensusConfig::default()); assert_eq!(r.embed_version, EMBED_VERSION); assert_eq!(serde_json::to_value(&r).unwrap()["embed_version"], EMBED_VERSION); }
+    /// Pins grouping for a fixed set; if this breaks, the embedding changed and `EMBED_VERSION` must be bumped with the new expectation.
+    #[test] fn golden_grouping_for_embed_version_3() {
+        assert_eq!(EMBED_VERSION, 3);
+        let finals: Vec<String> = ["The answer is 42.", "the answer is 42", "Answer: 42", "Paris is the capital of France", "paris is the capital of france!", "water boils at 100 C", "The answer is forty-two"].iter().map(|s| s.to_string()).collect();
+        let cosine = compute(&finals, &ConsensusConfig::default());
+        assert_eq!(cosine.groups, vec![vec![0, 1], vec![2], vec![3, 4], vec![5], vec![6]]);
+        let jaccard = compute(&finals, &ConsensusConfig{ metric: SimilarityMetric::Jaccard, ..Default::default() });
+        assert_eq!(jaccard.groups, vec![vec![0, 1], vec![2], vec![3, 4], vec![5], vec![6]]);
+        let dot = compute(&finals, &ConsensusConfig{ metric: SimilarityMetric::Dot, ..Default::default() });
+        assert_eq!(dot.groups, vec![vec![0, 1, 2], vec![3, 4], vec![5], vec![6]], "an answer contained in another groups with it");
+        let v = embed(&text_tokens(&finals[0], DEFAULT_MAX_TOKENS), 128);
+        let buckets: Vec<usize> = v.iter().enumerate().filter(|(_, x)| **x != 0.0).map(|(i, _)| i).collect();
+        assert_eq!(buckets, vec![6, 37, 55, 69]);
+    }
 }
@@ -0,0 +1,82 @@
+//! Opt-in JSON Schema validation of request `payload.content`, keyed by `meta.task_type`.
+//!
+//! `ATP_CONTENT_SCHEMA_DIR` names a directory of `<task_type>.json` schemas, loaded once at startup. A request whose
+//! task type has a schema is rejected on ingest when its content does not match; task types without one, and
+//! requests without a task type, are not validated. Unset, nothing is validated. Embedders can [`install`] a
+//! registry instead (`RouterBuilder::content_schemas`); like transforms it is process-wide.
+
+use jsonschema::JSONSchema;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+/// One way content failed its schema: where in the content, which schema keyword, and why.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct Violation { pub path: String, pub schema_path: String, pub message: String }
+
+#[derive(Default)]
+pub struct ContentSchemas { by_task: HashMap<String, JSONSchema> }
+impl ContentSchemas {
+    /// Compiles every `*.json` schema in `dir`; a file that is unreadable or not a valid schema is logged and skipped.
+    pub fn load(dir: &Path) -> std::io::Result<Self> {
+        let mut by_task = HashMap::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") { continue; }
+            let Some(task_type) = path.file_stem().and_then(|s| s.to_str()).map(str::to_string) else { continue; };
+            let compiled = std::fs::read(&path).map_err(|e| e.to_string())
+                .and_then(|raw| serde_json::from_slice::<serde_json::Value>(&raw).map_err(|e| e.to_string()))
+                .and_then(|schema| JSONSchema::compile(&schema).map_err(|e| e.to_string()));
+            match compiled {
+                Ok(schema) => { by_task.insert(task_type, schema); }
+                Err(e) => tracing::warn!(path = %path.display(), error = %e, "content schema skipped"),
+            }
+        }
+        Ok(ContentSchemas { by_task })
+    }
+    /// Loads `ATP_CONTENT_SCHEMA_DIR`; an unreadable directory is logged and nothing is validated.
+    pub fn from_env() -> Self {
+        let Some(dir) = std::env::var("ATP_CONTENT_SCHEMA_DIR").ok().filter(|d| !d.is_empty()) else { return Self::default(); };
+        match Self::load(Path::new(&dir)) {
+            Ok(s) => { tracing::info!(dir = %dir, schemas = s.by_task.len(), "loaded content schemas"); s }
+            Err(e) => { tracing::warn!(dir = %dir, error = %e, "content schema directory unreadable, not validating"); Self::default() }
+        }
+    }
+    pub fn len(&self) -> usize { self.by_task.len() }
+    pub fn is_empty(&self) -> bool { self.by_task.is_empty() }
+    /// Checks `content` against the schema registered for `task_type`, if any.
+    pub fn validate(&self, task_type: Option<&str>, content: &serde_json::Value) -> Result<(), Vec<Violation>> {
+        let Some(schema) = task_type.and_then(|t| self.by_task.get(t)) else { return Ok(()); };
+        schema.validate(content).map_err(|errors| errors.map(|e| Violation { path: e.instance_path.to_string(), schema_path: e.schema_path.to_string(), message: e.to_string() }).collect())
+    }
+}
+
+static CONTENT_SCHEMAS: Lazy<RwLock<Arc<ContentSchemas>>> = Lazy::new(|| RwLock::new(Arc::new(ContentSchemas::from_env())));
+
+/// Replaces the registry loaded from `ATP_CONTENT_SCHEMA_DIR`.
+pub fn install(schemas: ContentSchemas) { *CONTENT_SCHEMAS.write().unwrap() = Arc::new(schemas); }
+/// [`ContentSchemas::validate`] against the installed registry.
+pub fn validate(task_type: Option<&str>, content: &serde_json::Value) -> Result<(), Vec<Violation>> {
+    let schemas = CONTENT_SCHEMAS.read().unwrap().clone();
+    schemas.validate(task_type, content)
+}
+
+#[cfg(test)]
+mod tests { use super::*;
+    #[test] fn loads_schemas_by_file_stem_and_reports_paths() {
+        let dir = std::env::temp_dir().join(format!("atp-content-schemas-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("summarize.json"), r#"{"type":"object","properties":{"text":{"type":"string","maxLength":5}}}"#).unwrap();
+        std::fs::write(dir.join("broken.json"), r#"{"type": 7}"#).unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a schema").unwrap();
+        let schemas = ContentSchemas::load(&dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(schemas.len(), 1, "the invalid schema and the non-json file are skipped");
+        assert!(schemas.validate(Some("summarize"), &serde_json::json!({"text": "short"})).is_ok());
+        let errs = schemas.validate(Some("summarize"), &serde_json::json!({"text": "far too long"})).unwrap_err();
+        assert_eq!((errs[0].path.as_str(), errs[0].schema_path.as_str()), ("/text", "/properties/text/maxLength"));
+        assert!(schemas.validate(Some("broken"), &serde_json::json!(1)).is_ok() && schemas.validate(None, &serde_json::json!(1)).is_ok());
+        assert!(ContentSchemas::load(&dir).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+//! OpenMetrics exemplars linking histogram samples to the trace that produced them.
+//!
+//! The Prometheus exporter has no exemplar support, so the most recent traced observation per
+//! series is kept here and spliced into the rendered `/metrics` text as `# {trace_id="..."} value`.
+//! The trace id is the OpenTelemetry one, so it is only known when the subscriber has a
+//! `tracing-opentelemetry` layer. Exemplars are OpenMetrics-only: `/metrics` adds them, with the
+//! OpenMetrics content type and `# EOF`, only for scrapers that accept `application/openmetrics-text`.
+
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+#[derive(Clone, Debug)]
+struct Exemplar { metric: String, labels: Vec<(String, String)>, trace_id: String, value: f64 }
+
+static EXEMPLARS: Lazy<Mutex<HashMap<String, Exemplar>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Exemplars ride along with the OTLP tracing path: they are on once [`otlp_tracer`] has built a tracer.
+pub fn enabled() -> bool { ENABLED.load(Ordering::Relaxed) }
+
+/// Builds a batching OTLP/gRPC span exporter for `endpoint`, installs its provider globally (which also keeps it
+/// alive), and returns the tracer for a `tracing_opentelemetry` layer. Must run inside a Tokio runtime.
+pub fn otlp_tracer(endpoint: &str) -> Result<opentelemetry_sdk::trace::Tracer, opentelemetry::trace::TraceError> {
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::WithExportConfig;
+    let provider = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint))
+        .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+    let tracer = provider.tracer("atp-router");
+    opentelemetry::global::set_tracer_provider(provider);
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(tracer)
+}
+
+pub const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Whether a scrape's `Accept` header asks for OpenMetrics, the only format that can carry exemplars.
+pub fn wants_openmetrics(accept: Option<&str>) -> bool {
+    accept.is_some_and(|a| a.split(',').any(|t| t.split(';').next().is_some_and(|m| m.trim().eq_ignore_ascii_case("application/openmetrics-text"))))
+}
+
+/// Keeps `value` as the exemplar for `metric{labels}` when the current span belongs to a valid trace.
+pub fn record(metric: &str, labels: &[(&str, &str)], value: f64) {
+    use opentelemetry::trace::TraceContextExt;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    if !enabled() { return; }
+    let trace_id = tracing::Span::current().context().span().span_context().trace_id();
+    if trace_id != opentelemetry::trace::TraceId::INVALID { store(metric, labels, trace_id.to_string(), value); }
+}
+
+fn store(metric: &str, labels: &[(&str, &str)], trace_id: String, value: f64) {
+    let labels: Vec<(String, String)> = labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    let series = format!("{}{:?}", metric, labels);
+    EXEMPLARS.lock().unwrap().insert(series, Exemplar { metric: metric.to_string(), labels, trace_id, value });
+}
+
+/// Returns the bucket upper bound if `line` is a `{metric}_bucket` sample carrying all of `labels`.
+fn bucket_le(line: &str, metric: &str, labels: &[(String, String)]) -> Option<f64> {
+    let (name, rest) = line.split_once('{')?;
+    let (series, _) = rest.split_once('}')?;
+    if name.strip_suffix("_bucket") != Some(metric) { return None; }
+    if !labels.iter().all(|(k, v)| series.split(',').any(|l| l == format!("{}=\"{}\"", k, v))) { return None; }
+    let le = series.split(',').find_map(|l| l.strip_prefix("le=\""))?.trim_end_matches('"');
+    if le == "+Inf" { Some(f64::INFINITY) } else { le.parse().ok() }
+}
+
+/// Appends each stored exemplar to the smallest histogram bucket of its series that contains it, and ends the
+/// exposition with `# EOF` as OpenMetrics requires.
+pub fn annotate(rendered: &str) -> String {
+    let exemplars: Vec<Exemplar> = EXEMPLARS.lock().unwrap().values().cloned().collect();
+    let mut lines: Vec<String> = rendered.lines().map(str::to_string).collect();
+    for ex in exemplars {
+        let target = lines.iter().enumerate()
+            .filter_map(|(i, l)| bucket_le(l, &ex.metric, &ex.labels).filter(|le| *le >= ex.value).map(|le| (i, le)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(i, _)| i);
+        if let Some(i) = target { lines[i] = format!("{} # {{trace_id=\"{}\"}} {}", lines[i], ex.trace_id, ex.value); }
+    }
+    lines.push("# EOF".into());
+    let mut out = lines.join("\n");
+    out.push('\n');
+    out
+}
+
+#[cfg(test)]
+mod tests { use super::*;
+    const RENDERED: &str = "router_request_duration_ms_bucket{qos=\"gold\",outcome=\"completed\",le=\"10\"} 0\nrouter_request_duration_ms_bucket{qos=\"gold\",outcome=\"completed\",le=\"50\"} 1\nrouter_request_duration_ms_bucket{qos=\"gold\",outcome=\"completed\",le=\"+Inf\"} 1\nrouter_request_duration_ms_bucket{qos=\"silver\",outcome=\"completed\",le=\"50\"} 4\n";
+    #[tokio::test] async fn active_span_exemplar_is_rendered_on_matching_bucket() {
+        use opentelemetry::trace::TraceContextExt;
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+        use tracing_subscriber::layer::SubscriberExt;
+        let tracer = otlp_tracer("http://127.0.0.1:4317").unwrap();
+        assert!(enabled());
+        let _sub = tracing::subscriber::set_default(tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer)));
+        let span = tracing::info_span!("process_request");
+        let trace_id = span.context().span().span_context().trace_id();
+        assert_ne!(trace_id, opentelemetry::trace::TraceId::INVALID);
+        span.in_scope(|| record("router_request_duration_ms", &[("qos", "gold"), ("outcome", "completed")], 42.0));
+        let out = annotate(RENDERED);
+        assert!(out.contains(&format!("le=\"50\"}} 1 # {{trace_id=\"{:032x}\"}} 42", trace_id)), "{out}");
+        assert_eq!(out.matches("# {trace_id=").count(), 1);
+        assert!(out.ends_with("\n# EOF\n"));
+    }
+    #[tokio::test] async fn no_exemplar_without_a_trace() {
+        let _tracer = otlp_tracer("http://127.0.0.1:4317").unwrap();
+        let _sub = tracing::subscriber::set_default(tracing_subscriber::registry());
+        tracing::info_span!("untraced").in_scope(|| record("router_untraced_ms", &[("qos", "gold")], 1.0));
+        assert_eq!(annotate("router_untraced_ms_bucket{qos=\"gold\",le=\"+Inf\"} 1"), "router_untraced_ms_bucket{qos=\"gold\",le=\"+Inf\"} 1\n# EOF\n");
+    }
+    #[test] fn openmetrics_is_negotiated_from_accept() {
+        assert!(wants_openmetrics(Some("application/openmetrics-text; version=1.0.0,text/plain;version=0.0.4;q=0.5")));
+        assert!(!wants_openmetrics(Some("text/plain;version=0.0.4")) && !wants_openmetrics(None));
+    }
+}
@@ -0,0 +1,59 @@
+//! `ATP_FAKE_ADAPTERS=true`: canned, deterministic adapters that never touch the network, so the whole request path
+//! (ack, partials, provisional, final) runs offline for front-end work and demos.
+//!
+//! `ATP_FAKE_ANSWERS` is a JSON array with one final answer per fake adapter; the default has two agreeing answers
+//! and one dissenting, so consensus has groups to form. Each adapter streams a partial (the first half of its answer)
+//! and then its final, with chunks `ATP_FAKE_DELAY_MS` (default 50) apart times its position, so finals always arrive
+//! in configuration order. Estimates and reported usage are derived from the prompt and answer lengths.
+
+use crate::adapters::ChunkStream;
+use atp_adapter_proto::atp::adapter::v1::StreamChunk;
+use futures_util::StreamExt;
+use std::time::Duration;
+
+const DEFAULT_ANSWERS: [&str; 3] = ["Paris is the capital of France.", "Paris is the capital of France.", "Lyon is the capital of France."];
+/// Fake usage is priced at this many micro-dollars per token.
+const USD_MICROS_PER_TOKEN: u64 = 2;
+
+pub fn enabled() -> bool { matches!(std::env::var("ATP_FAKE_ADAPTERS").ok().as_deref(), Some("1") | Some("true")) }
+
+/// The configured answers; an unparseable or empty `ATP_FAKE_ANSWERS` falls back to the defaults.
+fn answers() -> Vec<String> {
+    std::env::var("ATP_FAKE_ANSWERS").ok().and_then(|v| serde_json::from_str::<Vec<String>>(&v).ok()).filter(|a| !a.is_empty())
+        .unwrap_or_else(|| DEFAULT_ANSWERS.map(String::from).to_vec())
+}
+
+/// One endpoint per answer. They are valid URLs, so allowlists, drains and metrics treat them like real adapters.
+pub fn endpoints() -> Vec<String> { (0..answers().len()).map(|i| format!("http://fake-adapter-{i}")).collect() }
+
+/// The answer and position of a fake endpoint.
+fn lookup(ep: &str) -> Option<(usize, String)> {
+    let i: usize = ep.strip_prefix("http://fake-adapter-")?.parse().ok()?;
+    answers().get(i).map(|a| (i, a.clone()))
+}
+
+fn tokens(text: &str) -> u64 { text.split_whitespace().count() as u64 }
+
+/// `(tokens, usd_micros)`, as an adapter's estimate RPC would answer.
+pub fn estimate(ep: &str, prompt_json: &str) -> (u64, u64) {
+    let t = tokens(prompt_json) + lookup(ep).map(|(_, a)| tokens(&a)).unwrap_or(0);
+    (t, t * USD_MICROS_PER_TOKEN)
+}
+
+/// The chunks `ep` streams: a partial, then the final carrying the answer's usage.
+pub fn chunks(ep: &str) -> Vec<StreamChunk> {
+    let Some((_, answer)) = lookup(ep) else { return vec![]; };
+    let words: Vec<&str> = answer.split_whitespace().collect();
+    let chunk = |ty: &str, text: String, out_tokens: u64| StreamChunk {
+        r#type: ty.into(), content_json: text, confidence: 0.9, more: true, flags: vec![],
+        partial_in_tokens: 0, partial_out_tokens: out_tokens, partial_usd_micros: out_tokens * USD_MICROS_PER_TOKEN,
+    };
+    vec![chunk("agent.result.partial", words[..words.len().div_ceil(2)].join(" "), 0), chunk("agent.result.final", answer.clone(), tokens(&answer))]
+}
+
+/// [`chunks`] as an adapter stream, paced by `ATP_FAKE_DELAY_MS`.
+pub fn stream(ep: &str) -> ChunkStream {
+    let delay = std::env::var("ATP_FAKE_DELAY_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(50u64);
+    let gap = Duration::from_millis(delay * (lookup(ep).map(|(i, _)| i as u64).unwrap_or(0) + 1));
+    Box::pin(futures_util::stream::iter(chunks(ep)).then(move |c| async move { tokio::time::sleep(gap).await; Ok(c) }))
+}
@@ -0,0 +1,107 @@
+//! Which adapters a request fans out to. `All` (the default) sends every request to every eligible adapter;
+//! `Weighted` samples `k` of them, favoring the cheaper or faster ones, to trade some quality for spend across
+//! many requests.
+//!
+//! Weights are inverse to the chosen basis: this request's usd estimate for `Cost`, the cached health p95 for
+//! `Latency`. Adapters without data get the mean weight of those with it, so they are neither starved nor favored.
+
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WeightBasis { #[default] Cost, Latency }
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FanoutStrategy { #[default] All, Weighted { k: usize, basis: WeightBasis } }
+impl FanoutStrategy {
+    /// Parses `all` or `weighted:<k>[:cost|latency]`; the basis defaults to cost.
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.trim().split(':');
+        match parts.next()? {
+            "all" => parts.next().is_none().then_some(Self::All),
+            "weighted" => {
+                let k: usize = parts.next()?.trim().parse().ok().filter(|k| *k > 0)?;
+                let basis = match parts.next().map(str::trim) { None | Some("cost") => WeightBasis::Cost, Some("latency") => WeightBasis::Latency, Some(_) => return None };
+                parts.next().is_none().then_some(Self::Weighted { k, basis })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// SplitMix64: small, seedable and good enough to spread load; not for anything security-sensitive.
+pub struct SplitMix64(u64);
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self { SplitMix64(seed) }
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+    /// Uniform in the open interval (0, 1).
+    pub fn next_f64(&mut self) -> f64 { ((self.next_u64() >> 11) as f64 + 0.5) / (1u64 << 53) as f64 }
+}
+
+/// Shared sampler, seeded from `ATP_FANOUT_SEED` when set so a run's selections can be replayed.
+static RNG: Lazy<Mutex<SplitMix64>> = Lazy::new(|| {
+    let seed = std::env::var("ATP_FANOUT_SEED").ok().and_then(|s| s.parse().ok())
+        .unwrap_or_else(|| std::collections::hash_map::RandomState::new().build_hasher().finish());
+    Mutex::new(SplitMix64::new(seed))
+});
+
+/// `ATP_FANOUT`; unset or unparseable means `All`.
+pub fn strategy() -> FanoutStrategy { std::env::var("ATP_FANOUT").ok().and_then(|s| FanoutStrategy::parse(&s)).unwrap_or_default() }
+
+/// Inverse weights for `eps` from per-endpoint measurements; larger measurements (cost, latency) weigh less.
+pub fn inverse_weights(eps: &[String], measured: &HashMap<String, f64>) -> Vec<f64> {
+    let known: Vec<f64> = eps.iter().filter_map(|ep| measured.get(ep)).map(|m| 1.0 / (m.max(0.0) + 1.0)).collect();
+    let neutral = if known.is_empty() { 1.0 } else { known.iter().sum::<f64>() / known.len() as f64 };
+    eps.iter().map(|ep| measured.get(ep).map(|m| 1.0 / (m.max(0.0) + 1.0)).unwrap_or(neutral)).collect()
+}
+
+/// Draws `k` distinct endpoints with probability proportional to their weights (Efraimidis–Spirakis), keeping
+/// their configured order. Returns every endpoint when `k` covers them all.
+pub fn weighted_sample(eps: &[String], weights: &[f64], k: usize, rng: &mut SplitMix64) -> Vec<String> {
+    if k >= eps.len() { return eps.to_vec(); }
+    let mut keyed: Vec<(f64, usize)> = weights.iter().enumerate().map(|(i, w)| (rng.next_f64().powf(1.0 / w.max(f64::MIN_POSITIVE)), i)).collect();
+    keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+    let mut picked: Vec<usize> = keyed.into_iter().take(k).map(|(_, i)| i).collect();
+    picked.sort_unstable();
+    picked.into_iter().map(|i| eps[i].clone()).collect()
+}
+
+/// Applies `strategy` to the eligible endpoints, given this request's `(tokens, usd_micros)` estimates and the
+/// cached p95 latencies.
+pub fn select(strategy: FanoutStrategy, eps: Vec<String>, estimates: &HashMap<String, (u64, u64)>, p95_ms: &HashMap<String, f64>) -> Vec<String> {
+    let FanoutStrategy::Weighted { k, basis } = strategy else { return eps; };
+    let weights = match basis {
+        WeightBasis::Cost => inverse_weights(&eps, &estimates.iter().map(|(ep, (_, usd))| (ep.clone(), *usd as f64)).collect()),
+        WeightBasis::Latency => inverse_weights(&eps, p95_ms),
+    };
+    weighted_sample(&eps, &weights, k, &mut RNG.lock().unwrap())
+}
+
+#[cfg(test)]
+mod tests { use super::*;
+    fn eps(n: usize) -> Vec<String> { (0..n).map(|i| format!("http://a{i}:7070")).collect() }
+    #[test] fn strategies_parse() { assert_eq!(FanoutStrategy::parse("all"), Some(FanoutStrategy::All)); assert_eq!(FanoutStrategy::parse("weighted:2"), Some(FanoutStrategy::Weighted{ k: 2, basis: WeightBasis::Cost })); assert_eq!(FanoutStrategy::parse("weighted:1:latency"), Some(FanoutStrategy::Weighted{ k: 1, basis: WeightBasis::Latency })); assert!(FanoutStrategy::parse("weighted:0").is_none() && FanoutStrategy::parse("weighted:2:speed").is_none() && FanoutStrategy::parse("first").is_none()); }
+    #[test] fn seeded_draws_favor_cheaper_adapters() {
+        let eps = eps(3);
+        let usd: HashMap<String, f64> = [(eps[0].clone(), 10.0), (eps[1].clone(), 100.0), (eps[2].clone(), 1000.0)].into();
+        let weights = inverse_weights(&eps, &usd);
+        let mut rng = SplitMix64::new(7);
+        let mut hits = [0usize; 3];
+        for _ in 0..2000 { for ep in weighted_sample(&eps, &weights, 1, &mut rng) { hits[eps.iter().position(|e| *e == ep).unwrap()] += 1; } }
+        assert!(hits[0] > hits[1] && hits[1] > hits[2], "{hits:?}");
+        assert!(hits[0] > 1500, "{hits:?}");
+        let again: Vec<Vec<String>> = { let mut rng = SplitMix64::new(7); (0..5).map(|_| weighted_sample(&eps, &weights, 2, &mut rng)).collect() };
+        let mut rng = SplitMix64::new(7);
+        assert_eq!(again, (0..5).map(|_| weighted_sample(&eps, &weights, 2, &mut rng)).collect::<Vec<_>>());
+    }
+    #[test] fn unmeasured_adapters_get_the_mean_weight() { let eps = eps(3); let w = inverse_weights(&eps, &[(eps[0].clone(), 0.0), (eps[1].clone(), 1.0)].into()); assert_eq!(w, vec![1.0, 0.5, 0.75]); assert_eq!(inverse_weights(&eps, &HashMap::new()), vec![1.0; 3]); }
+    #[test] fn k_covering_all_keeps_every_adapter() { let eps = eps(2); assert_eq!(select(FanoutStrategy::Weighted{ k: 5, basis: WeightBasis::Latency }, eps.clone(), &HashMap::new(), &HashMap::new()), eps); assert_eq!(select(FanoutStrategy::All, eps.clone(), &HashMap::new(), &HashMap::new()), eps); }
+}
@@ -0,0 +1,3829 @@
+//! ATP router core: frame ingest, weighted lane scheduling, window admission, adapter fanout and consensus.
+//!
+//! [`RouterBuilder`] assembles the HTTP/WebSocket surface so the router can be embedded in-process.
+
+use axum::{routing::{get}, Router, extract::{Query, ws::{WebSocketUpgrade, WebSocket, Message}}};
+use std::collections::{HashMap, VecDeque};
+use futures_util::{StreamExt, SinkExt};
+use serde_json::json;
+use std::time::Duration;
+use axum::response::{IntoResponse, Response};
+use atp_schema::{Frame, Window, Meta, Finding, merge_findings, fragment_text_frame, content_checksum, is_known_flag, Flag, Qos, DEFAULT_MAX_FRAGMENT_BYTES};
+use tokio::time::{Instant};
+use tokio::sync::{mpsc, RwLock};
+use metrics::{counter, histogram, gauge};
+use metrics_exporter_prometheus::PrometheusBuilder;
+use once_cell::sync::Lazy;
+use tracing::Instrument;
+use tokio_util::sync::CancellationToken;
+
+mod adapters;
+pub mod compression;
+pub mod consensus;
+pub mod content_schema;
+mod exemplars;
+mod fake;
+pub mod fanout;
+mod metrics_json;
+pub mod replay;
+pub mod transform;
+
+#[derive(Default)]
+struct WindowState { inflight: u32, tokens: u64, usd: u64, backpressure: VecDeque<Instant> }
+/// How long a backpressure mark keeps its window under pressure.
+const PRESSURE_WINDOW: Duration = Duration::from_secs(2);
+type SessionKey = String;
+#[derive(Default)]
+struct WindowTable { inner: RwLock<HashMap<SessionKey, WindowState>> }
+/// Window occupancy at the moment admission was refused, naming the dimension that overflowed.
+#[derive(Debug, Clone, serde::Serialize)]
+struct Utilization { saturated: &'static str, inflight: u32, max_parallel: u32, tokens_used: u64, max_tokens: u64, usd_used: u64, max_usd: u64 }
+impl WindowTable {
+    async fn admit(&self, key: &str, w: &Window, est_tokens: u64, est_usd: u64) -> Result<(), Utilization> {
+        let mut map = self.inner.write().await;
+        let e = map.entry(key.to_string()).or_default();
+        let saturated = if e.inflight >= w.max_parallel { Some("parallel") }
+            else if e.tokens + est_tokens > w.max_tokens { Some("tokens") }
+            else if e.usd + est_usd > w.max_usd_micros { Some("usd") }
+            else { None };
+        if let Some(saturated) = saturated {
+            return Err(Utilization { saturated, inflight: e.inflight, max_parallel: w.max_parallel, tokens_used: e.tokens, max_tokens: w.max_tokens, usd_used: e.usd, max_usd: w.max_usd_micros });
+        }
+        e.inflight += 1; e.tokens += est_tokens; e.usd += est_usd; Ok(())
+    }
+    async fn ack(&self, key: &str, est_tokens: u64, est_usd: u64) {
+        let mut map = self.inner.write().await;
+        if let Some(e) = map.get_mut(key) {
+            e.inflight = e.inflight.saturating_sub(1);
+            e.tokens = e.tokens.saturating_sub(est_tokens);
+            e.usd = e.usd.saturating_sub(est_usd);
+        }
+    }
+    /// Replaces a `reserved` share of the key's reservation with what was actually `observed`.
+    async fn true_up(&self, key: &str, reserved: (u64, u64), observed: (u64, u64)) {
+        let mut map = self.inner.write().await;
+        if let Some(e) = map.get_mut(key) {
+            e.tokens = e.tokens.saturating_sub(reserved.0) + observed.0;
+            e.usd = e.usd.saturating_sub(reserved.1) + observed.1;
+        }
+    }
+    async fn mark_backpressure(&self, key: &str) {
+        let mut map = self.inner.write().await;
+        if let Some(e) = map.get_mut(key) {
+            while e.backpressure.front().is_some_and(|t| t.elapsed() >= PRESSURE_WINDOW) { e.backpressure.pop_front(); }
+            e.backpressure.push_back(Instant::now());
+        }
+    }
+    /// Backpressure marks on `key` within the last [`PRESSURE_WINDOW`]; zero means the window is not under pressure.
+    async fn pressure(&self, key: &str) -> usize {
+        let map = self.inner.read().await;
+        map.get(key).map(|e| e.backpressure.iter().filter(|t| t.elapsed() < PRESSURE_WINDOW).count()).unwrap_or(0)
+    }
+}
+static GLOBAL_WINDOWS: Lazy<WindowTable> = Lazy::new(|| WindowTable { inner: RwLock::new(HashMap::new()) });
+
+/// An admitted request's share of its `GLOBAL_WINDOWS` entry. Call `release`; if the request task unwinds
+/// instead, `Drop` acks the share on a spawned task and counts the panic, so the slot can't leak.
+/// `tenant` is set when the same share was also admitted against the tenant's aggregate in `TENANT_WINDOWS`.
+struct Reservation { key: SessionKey, tenant: Option<String>, held: (u64, u64), released: bool }
+impl Reservation {
+    fn new(key: &str, tenant: Option<String>, tokens: u64, usd: u64) -> Self { Reservation { key: key.to_string(), tenant, held: (tokens, usd), released: false } }
+    async fn release(mut self) { self.released = true; release_share(&self.key, self.tenant.as_deref(), self.held).await; }
+}
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if self.released { return; }
+        if std::thread::panicking() { counter!("router_request_panics_total", 1); }
+        let (key, tenant, held) = (std::mem::take(&mut self.key), self.tenant.take(), self.held);
+        if let Ok(rt) = tokio::runtime::Handle::try_current() { rt.spawn(async move { release_share(&key, tenant.as_deref(), held).await; }); }
+    }
+}
+async fn release_share(key: &str, tenant: Option<&str>, held: (u64, u64)) {
+    GLOBAL_WINDOWS.ack(key, held.0, held.1).await;
+    if let Some(t) = tenant { TENANT_WINDOWS.ack(t, held.0, held.1).await; }
+}
+
+/// Aggregate occupancy per tenant across all of its streams, checked against [`tenant_window`].
+static TENANT_WINDOWS: Lazy<WindowTable> = Lazy::new(WindowTable::default);
+/// Tenant of a request: `meta.tenant_id`, else a `tenant:<id>` entry in `meta.data_scope`.
+fn tenant_id(meta: &Meta) -> Option<String> {
+    meta.tenant_id.clone().filter(|t| !t.is_empty())
+        .or_else(|| meta.data_scope.iter().flatten().find_map(|s| s.strip_prefix("tenant:")).filter(|t| !t.is_empty()).map(str::to_string))
+}
+/// Per-tenant aggregate cap (`ATP_TENANT_MAX_PARALLEL`, `ATP_TENANT_MAX_TOKENS`, `ATP_TENANT_MAX_USD_MICROS`);
+/// `None` when none is set, unset dimensions are unbounded.
+fn tenant_window() -> Option<Window> {
+    let var = |k: &str| std::env::var(k).ok().and_then(|v| v.trim().parse::<u64>().ok());
+    let (p, t, u) = (var("ATP_TENANT_MAX_PARALLEL"), var("ATP_TENANT_MAX_TOKENS"), var("ATP_TENANT_MAX_USD_MICROS"));
+    if p.is_none() && t.is_none() && u.is_none() { return None; }
+    Some(Window { max_parallel: p.map_or(u32::MAX, |p| p.min(u32::MAX as u64) as u32), max_tokens: t.unwrap_or(u64::MAX), max_usd_micros: u.unwrap_or(u64::MAX) })
+}
+
+/// Highest `msg_seq` seen per `session:stream`, with idle entries evicted so abandoned streams don't accumulate.
+type SeqMap = HashMap<SessionKey, (u64, Instant)>;
+struct SeqTracker { inner: std::sync::Mutex<(SeqMap, Instant)>, idle: Duration }
+impl SeqTracker {
+    fn new(idle: Duration) -> Self { SeqTracker { inner: std::sync::Mutex::new((HashMap::new(), Instant::now())), idle } }
+    /// Records `seq` for `key`; returns the previous high-water mark when `seq` regresses below it.
+    fn observe(&self, key: &str, seq: u64) -> Result<(), u64> {
+        let mut guard = self.inner.lock().unwrap();
+        let (map, last_sweep) = &mut *guard;
+        let now = Instant::now();
+        if now.duration_since(*last_sweep) >= self.idle / 4 { map.retain(|_, (_, seen)| now.duration_since(*seen) < self.idle); *last_sweep = now; }
+        let e = map.entry(key.to_string()).or_insert((seq, now));
+        e.1 = now;
+        if seq < e.0 { return Err(e.0); }
+        e.0 = seq;
+        Ok(())
+    }
+}
+static MSG_SEQS: Lazy<SeqTracker> = Lazy::new(|| SeqTracker::new(Duration::from_secs(std::env::var("ATP_MSG_SEQ_IDLE_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(600))));
+fn strict_msg_seq() -> bool { matches!(std::env::var("ATP_STRICT_MSG_SEQ").ok().as_deref(), Some("1") | Some("true")) }
+/// Regressions are rejected in strict mode and otherwise only counted.
+fn check_msg_seq(tracker: &SeqTracker, frame: &Frame, strict: bool) -> Result<(), serde_json::Value> {
+    match tracker.observe(&format!("{}:{}", frame.session_id, frame.stream_id), frame.msg_seq) {
+        Ok(()) => Ok(()),
+        Err(last) => {
+            counter!("router_msg_seq_regressions_total", 1);
+            if strict { Err(json!({"error":"msg_seq_regression","last":last})) } else { Ok(()) }
+        }
+    }
+}
+fn strict_flags() -> bool { matches!(std::env::var("ATP_STRICT_FLAGS").ok().as_deref(), Some("1") | Some("true")) }
+/// Dedups and sorts the frame's flags; unknown flags are rejected in strict mode and otherwise kept and counted.
+fn normalize_flags(frame: &mut Frame, strict: bool) -> Result<(), serde_json::Value> {
+    let unknown = frame.flags.iter().filter(|f| !is_known_flag(f)).count();
+    if unknown > 0 { counter!("router_unknown_flags_total", unknown as u64); }
+    frame.normalize_flags(strict).map_err(|e| json!({"error":"unknown_flag","flag":e.0}))
+}
+
+/// Frames emitted for a request after its ack, kept so a client that reconnects with the
+/// `resume_token` from that ack gets what it missed and the rest of the stream.
+/// `live` is dropped once the request finishes so the registry never keeps a connection's channel open.
+struct ResumeEntry { frames: VecDeque<(u64, String)>, touched: Instant, live: Option<mpsc::Sender<String>> }
+struct ResumeRegistry { inner: std::sync::Mutex<HashMap<String, ResumeEntry>>, max_frames: usize, ttl: Duration }
+impl ResumeRegistry {
+    fn new(max_frames: usize, ttl: Duration) -> Self { ResumeRegistry { inner: std::sync::Mutex::new(HashMap::new()), max_frames, ttl } }
+    fn evict_expired(&self, map: &mut HashMap<String, ResumeEntry>) { let ttl = self.ttl; map.retain(|_, e| e.touched.elapsed() < ttl); }
+    fn open(&self, live: mpsc::Sender<String>) -> String {
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        let mut map = self.inner.lock().unwrap();
+        self.evict_expired(&mut map);
+        map.insert(token.clone(), ResumeEntry { frames: VecDeque::new(), touched: Instant::now(), live: Some(live) });
+        token
+    }
+    /// Buffers `text` and returns the sender of the connection currently attached to `token`.
+    fn record(&self, token: &str, msg_seq: u64, text: &str) -> Option<mpsc::Sender<String>> {
+        let mut map = self.inner.lock().unwrap();
+        let e = map.get_mut(token)?;
+        if e.frames.len() == self.max_frames { e.frames.pop_front(); counter!("router_resume_frames_dropped_total", 1); }
+        e.frames.push_back((msg_seq, text.to_string()));
+        e.touched = Instant::now();
+        e.live.clone()
+    }
+    fn finish(&self, token: &str) { if let Some(e) = self.inner.lock().unwrap().get_mut(token) { e.live = None; } }
+    /// True while the request behind `token` is still running but the connection attached to it has closed.
+    fn detached(&self, token: &str) -> bool {
+        self.inner.lock().unwrap().get(token).and_then(|e| e.live.as_ref()).is_some_and(|live| live.is_closed())
+    }
+    /// Re-attaches `token` to `live`: buffered frames with a `msg_seq` above `after` are delivered first,
+    /// then frames the request keeps emitting. Returns false for unknown or expired tokens.
+    fn resume(&self, token: &str, after: Option<u64>, live: mpsc::Sender<String>) -> bool {
+        let mut map = self.inner.lock().unwrap();
+        self.evict_expired(&mut map);
+        let Some(e) = map.get_mut(token) else { return false; };
+        let missed: Vec<String> = e.frames.iter().filter(|(seq, _)| after.is_none_or(|a| *seq > a)).map(|(_, t)| t.clone()).collect();
+        // Forwarding through a fresh channel keeps the backlog ahead of frames emitted while it drains.
+        let (fwd_tx, mut fwd_rx) = mpsc::channel::<String>(128);
+        if e.live.is_some() { e.live = Some(fwd_tx); }
+        e.touched = Instant::now();
+        tokio::spawn(async move {
+            for f in missed { if live.send(f).await.is_err() { return; } }
+            while let Some(f) = fwd_rx.recv().await { if live.send(f).await.is_err() { return; } }
+        });
+        true
+    }
+}
+static RESUME: Lazy<ResumeRegistry> = Lazy::new(|| ResumeRegistry::new(
+    std::env::var("ATP_RESUME_BUFFER_FRAMES").ok().and_then(|v| v.parse().ok()).unwrap_or(256),
+    Duration::from_secs(std::env::var("ATP_RESUME_TTL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(30)),
+));
+
+/// Stamp `payload.checksum` on every outgoing payload, not just fragments (`ATP_PAYLOAD_CHECKSUMS`).
+fn payload_checksums() -> bool { matches!(std::env::var("ATP_PAYLOAD_CHECKSUMS").ok().as_deref(), Some("1") | Some("true")) }
+
+/// How long a request keeps running after its client disconnects, so the client can resume, before it is cancelled
+/// (`ATP_DISCONNECT_GRACE_MS`, default 2s).
+fn disconnect_grace() -> Duration { Duration::from_millis(std::env::var("ATP_DISCONNECT_GRACE_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(2000)) }
+
+/// Sends a request's post-ack frames, fragmenting them and routing through its resume buffer. Requests that arrived
+/// COMPRESSED get their replies' content compressed the same way. A failed send means the client is gone: unless it
+/// resumes within [`disconnect_grace`], `cancel` stops the request.
+struct Outbox { token: String, base_seq: u64, frag_limit: usize, compress: bool, cancel: CancellationToken, detaching: std::sync::Arc<std::sync::atomic::AtomicBool> }
+impl Outbox {
+    fn open(reply_tx: mpsc::Sender<String>, base_seq: u64, compress: bool, cancel: CancellationToken) -> Self {
+        Outbox { token: RESUME.open(reply_tx), base_seq, frag_limit: max_fragment_bytes(), compress, cancel, detaching: Default::default() }
+    }
+    fn client_gone(&self) {
+        if self.detaching.swap(true, std::sync::atomic::Ordering::SeqCst) { return; }
+        let (token, cancel, detaching) = (self.token.clone(), self.cancel.clone(), self.detaching.clone());
+        tokio::spawn(async move {
+            tokio::time::sleep(disconnect_grace()).await;
+            if RESUME.detached(&token) {
+                tracing::info!("client disconnected without resuming, cancelling request");
+                counter!("router_client_gone_total", 1);
+                cancel.cancel();
+            }
+            detaching.store(false, std::sync::atomic::Ordering::SeqCst);
+        });
+    }
+    async fn send(&self, msg: &serde_json::Value) {
+        let seq = msg["msg_seq"].as_u64().unwrap_or(self.base_seq);
+        let mut msg = std::borrow::Cow::Borrowed(msg);
+        if is_last_hop(&msg) { mark_last_hop(msg.to_mut()); }
+        if self.compress && msg["payload"].get("content").is_some() {
+            match compression::CODEC.compress(&msg["payload"]["content"]) {
+                Ok(packed) => {
+                    let m = msg.to_mut();
+                    m["payload"]["content"] = packed;
+                    if let Some(flags) = m["flags"].as_array_mut() { flags.push(json!(Flag::Compressed.as_str())); flags.sort_by(|a, b| a.as_str().cmp(&b.as_str())); }
+                }
+                Err(e) => tracing::warn!(error = %e, "reply compression failed, sending uncompressed"),
+            }
+        }
+        if payload_checksums() && msg["payload"].get("content").is_some() {
+            if let Ok(c) = content_checksum(&msg["payload"]["content"]) { msg.to_mut()["payload"]["checksum"] = json!(c); }
+        }
+        let session_id = msg["session_id"].as_str().map(str::to_string);
+        for out in fragment_outgoing(&msg, self.frag_limit) {
+            if let Some(sid) = &session_id { OBSERVERS.publish(sid, &out); }
+            if let Some(tx) = RESUME.record(&self.token, seq, &out) { if tx.send(out).await.is_err() { self.client_gone(); } }
+        }
+    }
+}
+impl Drop for Outbox { fn drop(&mut self) { RESUME.finish(&self.token); } }
+
+/// A reply leaving with TTL 0 would be rejected by the next hop; it is still sent, but annotated and counted.
+fn is_last_hop(msg: &serde_json::Value) -> bool { msg["ttl"].as_u64() == Some(0) && msg.get("control.status").is_none() }
+fn mark_last_hop(msg: &mut serde_json::Value) {
+    if !is_last_hop(msg) { return; }
+    msg["control.status"] = json!("TTL_LAST_HOP");
+    counter!("router_ttl_last_hop_total", 1);
+}
+
+/// Read-only taps on a session's outgoing frames for `/ws/observe`; a session nobody observes has no channel.
+struct ObserverRegistry { inner: std::sync::Mutex<HashMap<String, tokio::sync::broadcast::Sender<String>>>, capacity: usize }
+impl ObserverRegistry {
+    fn subscribe(&self, session_id: &str) -> tokio::sync::broadcast::Receiver<String> {
+        self.inner.lock().unwrap().entry(session_id.to_string()).or_insert_with(|| tokio::sync::broadcast::channel(self.capacity).0).subscribe()
+    }
+    fn publish(&self, session_id: &str, text: &str) {
+        let mut map = self.inner.lock().unwrap();
+        let Some(tx) = map.get(session_id) else { return; };
+        if tx.send(text.to_string()).is_err() { map.remove(session_id); }
+    }
+}
+/// Frames buffered per observed session before a slow observer starts missing them (`ATP_OBSERVE_BUFFER`, default 256).
+static OBSERVERS: Lazy<ObserverRegistry> = Lazy::new(|| ObserverRegistry { inner: Default::default(), capacity: std::env::var("ATP_OBSERVE_BUFFER").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(256) });
+
+/// Handles `control.resume` (`{"resume_token": ..., "last_msg_seq": N}`); returns an error reply on failure.
+fn resume_stream(frame: &Frame, live: mpsc::Sender<String>) -> Option<serde_json::Value> {
+    let Some(token) = frame.payload.content.get("resume_token").and_then(|t| t.as_str()) else { return Some(json!({"error":"resume_token_required"})); };
+    if !RESUME.resume(token, frame.payload.content.get("last_msg_seq").and_then(|s| s.as_u64()), live) { return Some(json!({"error":"resume_unknown_token"})); }
+    counter!("router_resumes_total", 1);
+    None
+}
+
+/// Cancellation tokens of in-flight requests, keyed by [`inflight_key`] and tagged with the connection that submitted
+/// them, so a client can abort its own streams and no one else's.
+type InflightMap = HashMap<SessionKey, Vec<(u64, Option<u64>, CancellationToken, Instant)>>;
+#[derive(Default)]
+struct InflightRegistry { next_id: std::sync::atomic::AtomicU64, inner: std::sync::Mutex<InflightMap> }
+impl InflightRegistry {
+    fn register(&'static self, key: &str, conn: Option<u64>) -> InflightGuard {
+        let id = self.next_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let token = CancellationToken::new();
+        self.inner.lock().unwrap().entry(key.to_string()).or_default().push((id, conn, token.clone(), Instant::now()));
+        InflightGuard { registry: self, key: key.to_string(), id, token }
+    }
+    /// Cancels every in-flight request on `key` submitted by `conn`, returning how many were signalled.
+    fn cancel(&self, key: &str, conn: Option<u64>) -> usize {
+        let map = self.inner.lock().unwrap();
+        map.get(key).map(|v| v.iter().filter(|(_, c, _, _)| *c == conn).inspect(|(_, _, t, _)| t.cancel()).count()).unwrap_or(0)
+    }
+    /// Age of the longest-running in-flight request, or zero when none are running.
+    fn oldest_age(&self) -> Duration {
+        self.inner.lock().unwrap().values().flatten().map(|(_, _, _, at)| at.elapsed()).max().unwrap_or_default()
+    }
+}
+struct InflightGuard { registry: &'static InflightRegistry, key: SessionKey, id: u64, token: CancellationToken }
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        let mut map = self.registry.inner.lock().unwrap();
+        if let Some(v) = map.get_mut(&self.key) { v.retain(|(id, _, _, _)| *id != self.id); if v.is_empty() { map.remove(&self.key); } }
+    }
+}
+static INFLIGHT: Lazy<InflightRegistry> = Lazy::new(InflightRegistry::default);
+fn inflight_key(frame: &Frame) -> String { format!("{}:{}:{}", frame.meta.tenant_id.as_deref().unwrap_or(""), frame.session_id, frame.stream_id) }
+
+/// The most recent top consensus confidences, kept per phase (`provisional`, `final`) for `GET /consensus/confidence`.
+struct ConfidenceLog { cap: usize, inner: std::sync::Mutex<HashMap<&'static str, VecDeque<f64>>> }
+impl ConfidenceLog {
+    fn push(&self, phase: &'static str, confidence: f64) {
+        let mut map = self.inner.lock().unwrap();
+        let window = map.entry(phase).or_default();
+        if window.len() >= self.cap { window.pop_front(); }
+        window.push_back(confidence);
+    }
+    /// Count, mean and nearest-rank quantiles over each phase's window; the low tail shows systemic weak agreement.
+    fn summary(&self) -> serde_json::Value {
+        let map = self.inner.lock().unwrap();
+        let phase = |name: &str| {
+            let mut v: Vec<f64> = map.get(name).map(|w| w.iter().copied().collect()).unwrap_or_default();
+            if v.is_empty() { return json!({"count": 0}); }
+            v.sort_by(f64::total_cmp);
+            let q = |p: f64| v[((p * v.len() as f64).ceil() as usize).clamp(1, v.len()) - 1];
+            json!({"count": v.len(), "mean": v.iter().sum::<f64>() / v.len() as f64, "min": v[0], "p10": q(0.10), "p50": q(0.50), "p90": q(0.90), "max": v[v.len() - 1]})
+        };
+        json!({"window": self.cap, "provisional": phase("provisional"), "final": phase("final")})
+    }
+}
+static CONFIDENCE: Lazy<ConfidenceLog> = Lazy::new(|| ConfidenceLog { cap: std::env::var("ATP_CONFIDENCE_WINDOW").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(1000), inner: Default::default() });
+/// Records a top consensus confidence in the gauge, the per-phase histogram and the rolling window.
+fn record_confidence(phase: &'static str, confidence: f32) {
+    gauge!("router_consensus_confidence", confidence as f64);
+    histogram!("router_consensus_confidence_hist", confidence as f64, "phase" => phase);
+    CONFIDENCE.push(phase, confidence as f64);
+}
+
+static CONSENSUS_CFG: Lazy<consensus::ConsensusConfig> = Lazy::new(|| consensus::ConsensusConfig {
+    metric: std::env::var("CONSENSUS_METRIC").ok().and_then(|m| consensus::SimilarityMetric::parse(&m)).unwrap_or_default(),
+    threshold: std::env::var("CONSENSUS_THRESHOLD").ok().and_then(|t| t.parse().ok()),
+    structured: std::env::var("CONSENSUS_STRUCTURED").ok().as_deref() == Some("true"),
+    tie_break: std::env::var("CONSENSUS_TIE_BREAK").ok().and_then(|t| consensus::TieBreak::parse(&t)).unwrap_or_default(),
+    adaptive: std::env::var("CONSENSUS_ADAPTIVE_THRESHOLD").ok().and_then(|t| consensus::AdaptiveThreshold::parse(&t)),
+    audit: std::env::var("CONSENSUS_AUDIT").ok().as_deref() == Some("true"),
+    max_tokens: std::env::var("CONSENSUS_MAX_TOKENS").ok().and_then(|n| n.parse().ok()).filter(|n| *n > 0),
+});
+
+#[derive(Clone, Debug)]
+enum Lane { Gold, Silver, Bronze }
+/// Weighted round-robin share of scheduler turns per lane.
+const LANE_WEIGHTS: [(Lane, usize); 3] = [(Lane::Gold, 5), (Lane::Silver, 3), (Lane::Bronze, 1)];
+impl Lane {
+    fn as_str(&self) -> &'static str { match self { Lane::Gold => "gold", Lane::Silver => "silver", Lane::Bronze => "bronze" } }
+}
+fn lane_from_qos(q: &str) -> Option<Lane> {
+    Qos::parse(q).map(|q| match q { Qos::Gold => Lane::Gold, Qos::Silver => Lane::Silver, Qos::Bronze => Lane::Bronze })
+}
+fn per_lane_windows() -> bool { matches!(std::env::var("ATP_PER_LANE_WINDOWS").ok().as_deref(), Some("1") | Some("true")) }
+/// Window accounting key, scoped by tenant when the request names one; when `per_lane` is set each QoS lane
+/// of a stream gets an independent budget.
+fn window_key(frame: &Frame, per_lane: bool) -> SessionKey {
+    let base = match tenant_id(&frame.meta) {
+        Some(t) => format!("{}/{}:{}", t, frame.session_id, frame.stream_id),
+        None => format!("{}:{}", frame.session_id, frame.stream_id),
+    };
+    if per_lane { format!("{}:{}", base, lane_from_qos(&frame.qos).unwrap_or(Lane::Bronze).as_str()) } else { base }
+}
+/// Org-wide ceiling on client windows (`ATP_ORG_MAX_PARALLEL`, `ATP_ORG_MAX_TOKENS`, `ATP_ORG_MAX_USD_MICROS`);
+/// unset dimensions are unbounded.
+fn org_window() -> Window {
+    fn var<T: std::str::FromStr>(k: &str, unbounded: T) -> T { std::env::var(k).ok().and_then(|v| v.trim().parse().ok()).unwrap_or(unbounded) }
+    Window { max_parallel: var("ATP_ORG_MAX_PARALLEL", u32::MAX), max_tokens: var("ATP_ORG_MAX_TOKENS", u64::MAX), max_usd_micros: var("ATP_ORG_MAX_USD_MICROS", u64::MAX) }
+}
+/// Server default window (`ATP_DEFAULT_MAX_PARALLEL`, `ATP_DEFAULT_MAX_TOKENS`, `ATP_DEFAULT_MAX_USD_MICROS`) merged
+/// into every frame's window, so clients that omit or overstate theirs still get a baseline budget.
+fn default_window() -> anyhow::Result<Window> {
+    fn var<T: std::str::FromStr + PartialOrd + Default>(k: &str, unbounded: T) -> anyhow::Result<T> {
+        let Ok(v) = std::env::var(k) else { return Ok(unbounded); };
+        match v.trim().parse::<T>() {
+            Ok(n) if n > T::default() => Ok(n),
+            _ => anyhow::bail!("{} must be a positive integer, got {:?}", k, v),
+        }
+    }
+    Ok(Window { max_parallel: var("ATP_DEFAULT_MAX_PARALLEL", u32::MAX)?, max_tokens: var("ATP_DEFAULT_MAX_TOKENS", u64::MAX)?, max_usd_micros: var("ATP_DEFAULT_MAX_USD_MICROS", u64::MAX)? })
+}
+/// Validates the default window env at startup; see `default_window`.
+pub fn load_default_window() -> anyhow::Result<Window> { default_window() }
+/// The OTLP tracer for `main`'s `tracing_opentelemetry` layer; installing it also turns on `/metrics` exemplars.
+pub fn otlp_tracer(endpoint: &str) -> anyhow::Result<opentelemetry_sdk::trace::Tracer> { Ok(exemplars::otlp_tracer(endpoint)?) }
+/// What a lane's requests do while their window is under backpressure. `Ecn` marks (serves with an ECN notice)
+/// under mild pressure and drops under severe pressure; see [`ecn_severe_marks`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PressureAction { Proceed, Delay(u64), Drop, Ecn }
+/// Per-lane pressure behavior from `ATP_PRESSURE_POLICY` (e.g. `bronze=ecn,silver=delay:500,gold=proceed`);
+/// lanes not listed, or listed with an unparseable action, keep that default.
+fn pressure_action(lane: &Lane) -> PressureAction {
+    let default = match lane { Lane::Gold => PressureAction::Proceed, Lane::Silver => PressureAction::Delay(500), Lane::Bronze => PressureAction::Ecn };
+    let Ok(policy) = std::env::var("ATP_PRESSURE_POLICY") else { return default; };
+    policy.split(',').filter_map(|kv| kv.split_once('='))
+        .find(|(l, _)| l.trim().eq_ignore_ascii_case(lane.as_str()))
+        .and_then(|(_, a)| match a.trim().to_lowercase().as_str() {
+            "proceed" => Some(PressureAction::Proceed),
+            "drop" => Some(PressureAction::Drop),
+            "ecn" => Some(PressureAction::Ecn),
+            a => a.strip_prefix("delay:").and_then(|ms| ms.parse().ok()).map(PressureAction::Delay),
+        })
+        .unwrap_or(default)
+}
+/// Backpressure marks within [`PRESSURE_WINDOW`] at which pressure counts as severe (`ATP_ECN_SEVERE_MARKS`, default 3).
+fn ecn_severe_marks() -> usize { std::env::var("ATP_ECN_SEVERE_MARKS").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(3) }
+fn strict_qos() -> bool { matches!(std::env::var("ATP_STRICT_QOS").ok().as_deref(), Some("1") | Some("true")) }
+/// Unknown qos values are rejected in strict mode and otherwise fall back to Bronze (counted).
+fn resolve_lane(q: &str, strict: bool) -> Result<Lane, serde_json::Value> {
+    match lane_from_qos(q) {
+        Some(l) => Ok(l),
+        None if strict => Err(json!({"error":"unknown_qos","value":q})),
+        None => { counter!("router_qos_unknown_total", 1); Ok(Lane::Bronze) }
+    }
+}
+/// `slot` is the submitting connection's stream slot, if it has a cap; it frees when the item is dropped.
+#[derive(Clone)]
+/// `conn` is the [`ConnState::id`] of the connection that submitted the request, if any; only it may abort the request.
+struct WorkItem { frame: Frame, reply_tx: mpsc::Sender<String>, slot: Option<std::sync::Arc<tokio::sync::OwnedSemaphorePermit>>, conn: Option<u64> }
+struct Scheduler { gold: mpsc::Sender<WorkItem>, silver: mpsc::Sender<WorkItem>, bronze: mpsc::Sender<WorkItem>, stats: std::sync::Arc<SchedStats>, permits: std::sync::Arc<tokio::sync::Semaphore>, max_inflight: usize }
+/// Cumulative counters kept by the lane loop for `/debug/scheduler`.
+#[derive(Default)]
+struct SchedStats { turns: std::sync::atomic::AtomicU64, dispatched: [std::sync::atomic::AtomicU64; 3] }
+impl Lane {
+    fn index(&self) -> usize { match self { Lane::Gold => 0, Lane::Silver => 1, Lane::Bronze => 2 } }
+}
+/// Router-wide cap on concurrently running requests (`ATP_MAX_INFLIGHT`, default 1024).
+fn max_inflight() -> usize { std::env::var("ATP_MAX_INFLIGHT").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(1024) }
+static SCHED: Lazy<Scheduler> = Lazy::new(|| Scheduler::spawn(max_inflight(), |item| dispatch(item).instrument(tracing::info_span!("dispatch"))));
+impl Scheduler {
+    /// Starts the weighted lane loop; a permit is taken before dequeuing, so items wait in their lane while the router is at capacity.
+    /// Each turn takes from the next lane in weighted rotation that has work queued; when every lane is empty the loop
+    /// parks until any lane receives an item instead of polling.
+    fn spawn<F, Fut>(max_inflight: usize, handler: F) -> Scheduler
+    where F: Fn(WorkItem) -> Fut + Send + 'static, Fut: std::future::Future<Output = ()> + Send + 'static
+    {
+        let permits = std::sync::Arc::new(tokio::sync::Semaphore::new(max_inflight));
+        let (g_tx, mut g_rx) = mpsc::channel::<WorkItem>(256);
+        let (s_tx, mut s_rx) = mpsc::channel::<WorkItem>(256);
+        let (b_tx, mut b_rx) = mpsc::channel::<WorkItem>(256);
+        let stats = std::sync::Arc::new(SchedStats::default());
+        let loop_stats = stats.clone();
+        let ready_permits = permits.clone();
+        tokio::spawn(async move {
+            let mut order: VecDeque<Lane> = LANE_WEIGHTS.iter().flat_map(|(l, w)| std::iter::repeat_n(l.clone(), *w)).collect();
+            loop {
+                let Ok(permit) = permits.clone().acquire_owned().await else { return; };
+                gauge!("router_inflight_permits_available", permits.available_permits() as f64);
+                let mut picked = None;
+                for _ in 0..order.len() {
+                    let Some(l) = order.pop_front() else { break; };
+                    order.push_back(l.clone());
+                    loop_stats.turns.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    let ready = match l { Lane::Gold => g_rx.try_recv().ok(), Lane::Silver => s_rx.try_recv().ok(), Lane::Bronze => b_rx.try_recv().ok() };
+                    if let Some(item) = ready { picked = Some((l, item)); break; }
+                }
+                let (l, item) = match picked {
+                    Some(p) => p,
+                    None => tokio::select! {
+                        biased;
+                        Some(item) = g_rx.recv() => (Lane::Gold, item),
+                        Some(item) = s_rx.recv() => (Lane::Silver, item),
+                        Some(item) = b_rx.recv() => (Lane::Bronze, item),
+                        else => return,
+                    },
+                };
+                loop_stats.dispatched[l.index()].fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                let fut = handler(item);
+                let permits = permits.clone();
+                tokio::spawn(async move {
+                    fut.await;
+                    drop(permit);
+                    gauge!("router_inflight_permits_available", permits.available_permits() as f64);
+                });
+            }
+        });
+        Scheduler { gold: g_tx, silver: s_tx, bronze: b_tx, stats, permits: ready_permits, max_inflight }
+    }
+
+    /// Point-in-time view of lane depths, weights, rotation position and cumulative dispatches.
+    fn snapshot(&self) -> serde_json::Value {
+        let depth = |tx: &mpsc::Sender<WorkItem>| tx.max_capacity() - tx.capacity();
+        let rotation: usize = LANE_WEIGHTS.iter().map(|(_, w)| w).sum();
+        let turns = self.stats.turns.load(std::sync::atomic::Ordering::Relaxed);
+        let lanes: serde_json::Map<String, serde_json::Value> = LANE_WEIGHTS.iter().map(|(l, w)| {
+            let tx = match l { Lane::Gold => &self.gold, Lane::Silver => &self.silver, Lane::Bronze => &self.bronze };
+            (l.as_str().to_string(), json!({"depth": depth(tx), "weight": w, "dispatched": self.stats.dispatched[l.index()].load(std::sync::atomic::Ordering::Relaxed)}))
+        }).collect();
+        json!({"lanes": lanes, "rotation": {"position": turns % rotation as u64, "length": rotation}, "turns": turns})
+    }
+}
+
+/// Lane depth at which `/readyz` reports the router unready (`ATP_READY_MAX_QUEUE_DEPTH`, default 192 of 256).
+fn ready_max_queue_depth() -> usize { std::env::var("ATP_READY_MAX_QUEUE_DEPTH").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(192) }
+/// Readiness of `sched`: unready (503) while any lane holds `max_depth` or more queued items or every in-flight permit is taken.
+fn readiness(sched: &Scheduler, max_depth: usize) -> (axum::http::StatusCode, String) {
+    let inflight = sched.max_inflight - sched.permits.available_permits();
+    let deepest = [(&sched.gold, Lane::Gold), (&sched.silver, Lane::Silver), (&sched.bronze, Lane::Bronze)].into_iter()
+        .map(|(tx, l)| (tx.max_capacity() - tx.capacity(), l)).max_by_key(|(d, _)| *d).unwrap_or((0, Lane::Bronze));
+    let reason = if deepest.0 >= max_depth { Some("queue_depth") } else if inflight >= sched.max_inflight { Some("inflight_saturated") } else { None };
+    let body = json!({"ready": reason.is_none(), "reason": reason, "inflight": inflight, "max_inflight": sched.max_inflight, "deepest_lane": deepest.1.as_str(), "depth": deepest.0});
+    (if reason.is_some() { axum::http::StatusCode::SERVICE_UNAVAILABLE } else { axum::http::StatusCode::OK }, body.to_string())
+}
+/// `GET /readyz`: whether this instance should be sent new work; `/healthz` only says the process is alive.
+async fn readyz_route() -> (axum::http::StatusCode, String) { readiness(&SCHED, ready_max_queue_depth()) }
+
+/// `GET /debug/scheduler`; 404 unless `ATP_DEBUG_SCHEDULER` is set, since it exposes router internals.
+async fn debug_scheduler_route() -> Response {
+    if !matches!(std::env::var("ATP_DEBUG_SCHEDULER").ok().as_deref(), Some("1") | Some("true")) { return axum::http::StatusCode::NOT_FOUND.into_response(); }
+    ([(axum::http::header::CONTENT_TYPE, "application/json")], SCHED.snapshot().to_string()).into_response()
+}
+
+const REQUEST_DURATION_BUCKETS_MS: [f64; 11] = [5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0];
+const CONFIDENCE_BUCKETS: [f64; 10] = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 1.0];
+static PROM: Lazy<metrics_exporter_prometheus::PrometheusHandle> = Lazy::new(|| PrometheusBuilder::new()
+    .set_buckets_for_metric(metrics_exporter_prometheus::Matcher::Full("router_request_duration_ms".into()), &REQUEST_DURATION_BUCKETS_MS).expect("buckets")
+    .set_buckets_for_metric(metrics_exporter_prometheus::Matcher::Full("router_consensus_confidence_hist".into()), &CONFIDENCE_BUCKETS).expect("buckets")
+    .set_buckets_for_metric(metrics_exporter_prometheus::Matcher::Full("router_phase_duration_ms".into()), &REQUEST_DURATION_BUCKETS_MS).expect("buckets")
+    .install_recorder().expect("install"));
+/// Refreshed on each scrape, since an age keeps growing between request events.
+fn record_oldest_inflight() { gauge!("router_oldest_inflight_ms", INFLIGHT.oldest_age().as_secs_f64() * 1000.0); }
+/// `GET /metrics`: Prometheus text, or OpenMetrics with exemplars when enabled and the scraper accepts it.
+async fn metrics_handler(headers: axum::http::HeaderMap) -> Response {
+    record_oldest_inflight();
+    let rendered = PROM.render();
+    let accept = headers.get(axum::http::header::ACCEPT).and_then(|v| v.to_str().ok());
+    if exemplars::enabled() && exemplars::wants_openmetrics(accept) {
+        return ([(axum::http::header::CONTENT_TYPE, exemplars::OPENMETRICS_CONTENT_TYPE)], exemplars::annotate(&rendered)).into_response();
+    }
+    ([(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")], rendered).into_response()
+}
+/// `GET /metrics/json`: the same snapshot as `/metrics`, for pollers that only read JSON.
+async fn metrics_json_route() -> Response {
+    record_oldest_inflight();
+    ([(axum::http::header::CONTENT_TYPE, "application/json")], metrics_json::render(&PROM.render()).to_string()).into_response()
+}
+async fn explain_route()->String{ "[]".into() }
+/// `GET /consensus/confidence`: rolling summary of recent provisional and final consensus confidences.
+async fn confidence_route() -> Response {
+    ([(axum::http::header::CONTENT_TYPE, "application/json")], CONFIDENCE.summary().to_string()).into_response()
+}
+
+/// Upper bound on candidate answers accepted by `POST /consensus` (grouping is quadratic).
+const MAX_CONSENSUS_FINALS: usize = 1024;
+#[derive(serde::Deserialize)]
+struct ConsensusRequest { finals: Vec<String>, threshold: Option<f32>, metric: Option<String>, #[serde(default)] structured: bool, #[serde(default)] audit: bool }
+async fn consensus_route(body: Result<axum::Json<ConsensusRequest>, axum::extract::rejection::JsonRejection>) -> (axum::http::StatusCode, String) {
+    let bad = |reason: String| (axum::http::StatusCode::BAD_REQUEST, json!({"error":"invalid_request","reason":reason}).to_string());
+    let axum::Json(req) = match body { Ok(b) => b, Err(e) => return bad(e.body_text()) };
+    if req.finals.is_empty() || req.finals.len() > MAX_CONSENSUS_FINALS { return bad(format!("finals must contain 1..={} answers", MAX_CONSENSUS_FINALS)); }
+    if let Some(t) = req.threshold { if !t.is_finite() { return bad("threshold must be finite".into()); } }
+    let metric = match req.metric.as_deref().map(consensus::SimilarityMetric::parse) {
+        None => consensus::SimilarityMetric::default(),
+        Some(Some(m)) => m,
+        Some(None) => return bad("metric must be one of cosine, jaccard, dot".into()),
+    };
+    let cfg = consensus::ConsensusConfig { metric, threshold: req.threshold, structured: req.structured, audit: req.audit, ..Default::default() };
+    let result = consensus::run(&req.finals, &[], &cfg);
+    (axum::http::StatusCode::OK, serde_json::to_string(&result).unwrap_or_else(|_| "{}".into()))
+}
+async fn version_route()->String{ version_info().to_string() }
+
+/// Valid, normalized fanout targets; invalid entries were already reported by [`load_adapter_endpoints`].
+fn adapter_endpoints() -> Vec<String> {
+    adapters::parse_endpoints(&adapters::configured_endpoints().unwrap_or_default()).0
+}
+
+/// Keeps adapter capabilities for `eps` cached (see `ADAPTER_CAPABILITIES_REFRESH_SECS`) for capability-aware fanout.
+pub fn spawn_capabilities_refresh(eps: Vec<String>) -> tokio::task::JoinHandle<()> { adapters::spawn_capabilities_refresh(eps) }
+/// Validates `ADAPTER_ENDPOINTS` once at startup, logging invalid entries and failing if none are usable.
+pub fn load_adapter_endpoints() -> anyhow::Result<Vec<String>> { adapters::validate_endpoints(&adapters::configured_endpoints()?) }
+
+/// Endpoint patterns a request may target via `meta.trace.adapters`; defaults to the global endpoint list.
+/// See [`endpoint_matches`]: `http://*.internal:7070` admits any internal host on that port, but a `*` never spans
+/// into a path, userinfo, another port or, except as a leading `*.`, another host label.
+fn adapter_allowlist() -> Vec<String> {
+    std::env::var("ADAPTER_ALLOWLIST").ok()
+        .and_then(|s| serde_json::from_str::<Vec<String>>(&s).ok())
+        .unwrap_or_else(adapter_endpoints)
+}
+
+/// Drops endpoints whose cached capabilities exclude the request's task, payload type or every one of its
+/// `meta.languages` (absent or empty means any language); uncached ones stay.
+fn capable_endpoints(eps: Vec<String>, frame: &Frame) -> Result<Vec<String>, serde_json::Value> {
+    let task = frame.meta.task_type.as_deref();
+    let languages = frame.meta.languages.as_deref().filter(|l| !l.is_empty());
+    if eps.is_empty() { return Ok(eps); }
+    let capable: Vec<String> = eps.into_iter().filter(|ep| adapters::cached_capabilities(ep).is_none_or(|c| task.is_none_or(|t| c.supports_task(t)) && c.supports_payload(&frame.payload.r#type) && languages.is_none_or(|l| c.supports_any_language(l)))).collect();
+    if capable.is_empty() { return Err(json!({"error":"no_capable_adapter","task_type":task,"payload_type":frame.payload.r#type,"languages":languages})); }
+    Ok(capable)
+}
+
+/// Drops endpoints an operator is draining; fails only when every candidate is draining.
+fn undrained_endpoints(eps: Vec<String>) -> Result<Vec<String>, serde_json::Value> {
+    if eps.is_empty() { return Ok(eps); }
+    let active: Vec<String> = eps.into_iter().filter(|ep| !adapters::is_draining(ep)).collect();
+    if active.is_empty() { return Err(json!({"error":"all_adapters_draining"})); }
+    Ok(active)
+}
+
+/// Matches `ep` against an allowlist pattern. A `*` stands for characters of one host label or port (no `.`), so
+/// `http://10.0.0.*:7070` cannot match `http://10.0.0.1.attacker.example:7070`; only a `*.` that starts the host
+/// (`http://*.internal`) spans subdomains.
+fn endpoint_matches(pattern: &str, ep: &str) -> bool {
+    fn go(p: &[u8], e: &[u8], at_host_start: bool) -> bool {
+        match p.split_first() {
+            None => e.is_empty(),
+            Some((b'*', rest)) => {
+                let spans_labels = at_host_start && rest.first() == Some(&b'.');
+                let ok = |c: u8| c.is_ascii_alphanumeric() || matches!(c, b'-' | b'_') || (spans_labels && c == b'.');
+                (0..=e.len()).take_while(|&i| i == 0 || ok(e[i - 1])).any(|i| go(rest, &e[i..], false))
+            }
+            Some((c, rest)) => e.first() == Some(c) && go(rest, &e[1..], false),
+        }
+    }
+    match pattern.split_once("://").zip(ep.split_once("://")) {
+        Some(((ps, ph), (es, eh))) => go(ps.as_bytes(), es.as_bytes(), false) && go(ph.as_bytes(), eh.as_bytes(), true),
+        None => go(pattern.as_bytes(), ep.as_bytes(), false),
+    }
+}
+
+/// Resolves the fanout targets for one request, honoring an allowlisted per-request override.
+fn request_endpoints(meta: &Meta) -> Result<Vec<String>, serde_json::Value> {
+    let Some(requested) = meta.trace.as_ref().and_then(|t| t.get("adapters")).and_then(|a| a.as_array()) else { return Ok(adapter_endpoints()); };
+    let allow = adapter_allowlist();
+    let mut out = vec![];
+    for ep in requested {
+        // Drains, cached capabilities and metric labels are keyed by the normalized form, so match and fan out on that.
+        let normalized = match ep.as_str().map(adapters::normalize_endpoint) {
+            Some(Ok(n)) => n,
+            Some(Err(reason)) => return Err(json!({"error":"invalid_adapter","adapter":ep,"reason":reason})),
+            None => return Err(json!({"error":"adapter_not_allowed","adapter":ep})),
+        };
+        if !allow.iter().any(|a| endpoint_matches(a, &normalized)) { return Err(json!({"error":"adapter_not_allowed","adapter":ep})); }
+        if !out.contains(&normalized) { out.push(normalized); }
+    }
+    if out.is_empty() { return Err(json!({"error":"adapter_not_allowed","adapter":[]})); }
+    Ok(out)
+}
+
+/// Build and key runtime configuration, for confirming what is actually deployed.
+fn version_info() -> serde_json::Value {
+    let env_set = |k: &str| std::env::var(k).is_ok();
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_sha": option_env!("GIT_SHA"),
+        "adapter_endpoints": adapter_endpoints().len(),
+        "lane_weights": LANE_WEIGHTS.iter().map(|(l, w)| (l.as_str(), *w)).collect::<HashMap<_, _>>(),
+        "consensus": {"metric": format!("{:?}", CONSENSUS_CFG.metric).to_lowercase(), "threshold": CONSENSUS_CFG.threshold(), "tie_break": format!("{:?}", CONSENSUS_CFG.tie_break).to_lowercase(), "adaptive": CONSENSUS_CFG.adaptive, "audit": CONSENSUS_CFG.audit},
+        "fanout": format!("{:?}", fanout::strategy()).to_lowercase(),
+        "features": {
+            "wire_memory": std::env::var("FEATURE_WIRE_MEMORY").ok().as_deref() == Some("true"),
+            "opa": env_set("OPA_URL"),
+            "otlp": env_set("OTEL_EXPORTER_OTLP_ENDPOINT"),
+        },
+    })
+}
+async fn ws_handler(Query(params): Query<HashMap<String, String>>, req: axum::extract::Request, default_window: Window) -> Response {
+    let lines = match params.get("framing").map(|f| Framing::parse(f)) {
+        None => false,
+        Some(Some(f)) => f == Framing::Lines,
+        Some(None) => return (axum::http::StatusCode::BAD_REQUEST, json!({"error":"unknown_framing"}).to_string()).into_response(),
+    };
+    if ws_deflate_enabled() && offers_deflate(req.headers()) { return deflate_upgrade(req, lines, default_window); }
+    let (mut parts, _) = req.into_parts();
+    match <WebSocketUpgrade as axum::extract::FromRequestParts<()>>::from_request_parts(&mut parts, &()).await {
+        Ok(ws) => ws.on_upgrade(move |socket| handle_socket(socket, lines, default_window)),
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+/// Whether `/ws` accepts a client's `permessage-deflate` offer (`ATP_WS_DEFLATE`). Off by default.
+fn ws_deflate_enabled() -> bool { matches!(std::env::var("ATP_WS_DEFLATE").ok().as_deref(), Some("1") | Some("true")) }
+
+fn offers_deflate(headers: &axum::http::HeaderMap) -> bool {
+    headers.get_all(axum::http::header::SEC_WEBSOCKET_EXTENSIONS).iter().filter_map(|v| v.to_str().ok()).flat_map(|v| v.split(','))
+        .any(|ext| ext.split(';').next().is_some_and(|name| name.trim().eq_ignore_ascii_case("permessage-deflate")))
+}
+
+/// Upgrades through soketto, whose connections implement `permessage-deflate` (tungstenite's reject compressed
+/// frames). The socket is then served like any other; compression stays below the message layer.
+fn deflate_upgrade(mut req: axum::extract::Request, lines: bool, default_window: Window) -> Response {
+    let mut server = soketto::handshake::http::Server::new();
+    server.add_extension(Box::new(soketto::extension::deflate::Deflate::new(soketto::Mode::Server)));
+    let mut resp = match server.receive_request(&req) {
+        Ok(resp) => resp.map(|()| axum::body::Body::empty()),
+        Err(e) => return (axum::http::StatusCode::BAD_REQUEST, json!({"error":"invalid_upgrade","reason":e.to_string()}).to_string()).into_response(),
+    };
+    if resp.headers().get(axum::http::header::SEC_WEBSOCKET_EXTENSIONS).is_some_and(|v| v.is_empty()) { resp.headers_mut().remove(axum::http::header::SEC_WEBSOCKET_EXTENSIONS); }
+    let on_upgrade = hyper::upgrade::on(&mut req);
+    tokio::spawn(async move {
+        let Ok(upgraded) = on_upgrade.await else { return; };
+        let io = tokio_util::compat::TokioAsyncReadCompatExt::compat(hyper_util::rt::TokioIo::new(upgraded));
+        let (sender, receiver) = server.into_builder(io).finish();
+        serve_socket(soketto_sink(sender), soketto_stream(receiver), lines, default_window).await
+    });
+    resp
+}
+
+/// A soketto sender as a sink of [`Message`]s. Pings and pongs carry at most 125 bytes; larger ones are dropped.
+fn soketto_sink<T: futures_util::AsyncRead + futures_util::AsyncWrite + Unpin + Send + 'static>(tx: soketto::Sender<T>) -> std::pin::Pin<Box<dyn futures_util::Sink<Message, Error = soketto::connection::Error> + Send>> {
+    use soketto::data::ByteSlice125;
+    Box::pin(futures_util::sink::unfold(tx, |mut tx, msg: Message| async move {
+        match msg {
+            Message::Text(text) => tx.send_text_owned(text).await?,
+            Message::Binary(data) => tx.send_binary(data).await?,
+            Message::Ping(data) => if let Ok(data) = ByteSlice125::try_from(&data[..]) { tx.send_ping(data).await? },
+            Message::Pong(data) => if let Ok(data) = ByteSlice125::try_from(&data[..]) { tx.send_pong(data).await? },
+            Message::Close(_) => tx.close().await?,
+        }
+        tx.flush().await?;
+        Ok(tx)
+    }))
+}
+
+/// A soketto receiver as a stream of [`Message`]s; it ends after the first error.
+fn soketto_stream<T: futures_util::AsyncRead + futures_util::AsyncWrite + Unpin + Send + 'static>(rx: soketto::Receiver<T>) -> std::pin::Pin<Box<dyn futures_util::Stream<Item = Result<Message, soketto::connection::Error>> + Send>> {
+    Box::pin(futures_util::stream::unfold(Some(rx), |rx| async move {
+        let mut rx = rx?;
+        let mut data = vec![];
+        let msg = match rx.receive(&mut data).await {
+            Ok(soketto::Incoming::Data(soketto::Data::Text(_))) => Message::Text(String::from_utf8_lossy(&data).into_owned()),
+            Ok(soketto::Incoming::Data(soketto::Data::Binary(_))) => Message::Binary(data),
+            Ok(soketto::Incoming::Pong(pong)) => Message::Pong(pong.to_vec()),
+            Ok(soketto::Incoming::Closed(_)) => Message::Close(None),
+            Err(e) => return Some((Err(e), None)),
+        };
+        Some((Ok(msg), Some(rx)))
+    }))
+}
+
+/// Whether a request OPA couldn't decide on is denied (`ATP_OPA_FAIL`): `open` (default) allows it, `closed`
+/// denies it, `high-risk` denies it only when `meta.risk` is `high`.
+fn opa_fail_closed(meta: &Meta) -> bool {
+    match std::env::var("ATP_OPA_FAIL").ok().as_deref().map(str::trim) {
+        Some("closed") => true,
+        Some("high-risk") => meta.risk.as_deref().is_some_and(|r| r.eq_ignore_ascii_case("high")),
+        _ => false,
+    }
+}
+
+fn opa_allow(meta: &Meta) -> bool {
+    let Ok(url) = std::env::var("OPA_URL") else { return true; };
+    let client = reqwest::blocking::Client::new();
+    let input = json!({"meta": meta});
+    let endpoint = format!("{}/v1/data/atp/policy/allow", url.trim_end_matches('/'));
+    let decision = client.post(endpoint).json(&json!({"input":input})).send().ok()
+        .and_then(|resp| resp.json::<serde_json::Value>().ok())
+        .map(|v| v.get("result").and_then(|r| r.as_bool()));
+    match decision {
+        Some(Some(allow)) => allow,
+        // Unreachable OPA, a non-JSON reply, or no boolean `result` (policy undefined for this input).
+        _ if opa_fail_closed(meta) => { counter!("router_opa_fail_closed_total", 1); false }
+        _ => { counter!("router_opa_fail_open_total", 1); true }
+    }
+}
+
+/// Per-endpoint `(tokens, usd_micros)` estimates; adapters that fail to estimate are omitted.
+async fn estimate_costs(endpoints: &[String], prompt_json: &str) -> HashMap<String, (u64, u64)> {
+    use atp_adapter_proto::atp::adapter::v1::EstimateRequest;
+    if fake::enabled() { return endpoints.iter().map(|ep| (ep.clone(), fake::estimate(ep, prompt_json))).collect(); }
+    let mut tasks = vec![];
+    for ep in endpoints.iter() {
+        let epc = ep.clone();
+        let p = prompt_json.to_string();
+        tasks.push(tokio::spawn(async move {
+            match adapters::connect(&epc).await {
+                Ok(mut cli) => {
+                    let req = adapters::request(&epc, EstimateRequest{ stream_id: "s".into(), task_type: "generic".into(), prompt_json: p });
+                    match cli.estimate(req).await {
+                        Ok(r) => { let e = r.into_inner(); Ok::<(u64,u64),String>((e.in_tokens + e.out_tokens, e.usd_micros)) }
+                        Err(e) => Err(format!("estimate rpc: {}", e))
+                    }
+                }
+                Err(e) => Err(format!("connect: {}", e))
+            }
+        }));
+    }
+    let mut out = HashMap::new();
+    for (ep, t) in endpoints.iter().zip(tasks) {
+        if let Ok(Ok(est)) = t.await { out.insert(ep.clone(), est); }
+    }
+    out
+}
+/// Summed estimate, or `None` when adapters were asked but none answered (unknown cost, not zero cost).
+fn total_cost(endpoints: &[String], estimates: &HashMap<String, (u64, u64)>) -> Option<(u64, u64)> {
+    if !endpoints.is_empty() && estimates.is_empty() { return None; }
+    Some(estimates.values().fold((0, 0), |(t, u), (et, eu)| (t + et, u + eu)))
+}
+/// Conservative `(tokens, usd_micros)` admitted against when no adapter could estimate
+/// (`ATP_FALLBACK_EST_TOKENS`, default 4096; `ATP_FALLBACK_EST_USD_MICROS`, default 100000).
+fn fallback_estimate() -> (u64, u64) {
+    let var = |k: &str, d: u64| std::env::var(k).ok().and_then(|v| v.trim().parse::<u64>().ok()).unwrap_or(d);
+    (var("ATP_FALLBACK_EST_TOKENS", 4096), var("ATP_FALLBACK_EST_USD_MICROS", 100_000))
+}
+
+/// Tracing target for admission decisions, tunable on its own (e.g. `RUST_LOG=info,atp_router::admission=warn`).
+const ADMISSION: &str = "atp_router::admission";
+
+fn busy_payload(util: &Utilization) -> serde_json::Value {
+    let mut busy = json!({"control.status":"BUSY","suggested_wait_ms":200});
+    if let (Some(obj), Ok(serde_json::Value::Object(u))) = (busy.as_object_mut(), serde_json::to_value(util)) { obj.extend(u); }
+    busy
+}
+
+fn record_request_duration(started: Instant, qos: &str, outcome: &'static str) {
+    let ms = started.elapsed().as_secs_f64() * 1000.0;
+    histogram!("router_request_duration_ms", ms, "qos" => qos.to_string(), "outcome" => outcome);
+    exemplars::record("router_request_duration_ms", &[("qos", qos), ("outcome", outcome)], ms);
+}
+
+/// Whether the final frame carries the request's phase durations under `timing` (`ATP_FINAL_TIMING`).
+fn final_timing() -> bool { matches!(std::env::var("ATP_FINAL_TIMING").ok().as_deref(), Some("1") | Some("true")) }
+
+/// Boundaries between a request's phases. Each phase is timed from the previous boundary, so the phases of a
+/// completed request add up to its duration; a phase the request never reaches (e.g. no adapter sent a partial) is skipped.
+struct PhaseTimer { last: Instant, phases: Vec<(&'static str, f64)> }
+impl PhaseTimer {
+    fn new(started: Instant) -> Self { PhaseTimer { last: started, phases: vec![] } }
+    fn reached(&self, phase: &str) -> bool { self.phases.iter().any(|(p, _)| *p == phase) }
+    /// Ends `phase` now, recording it in `router_phase_duration_ms{phase}`; a phase already ended is left alone.
+    fn mark(&mut self, phase: &'static str) {
+        if self.reached(phase) { return; }
+        let now = Instant::now();
+        let ms = now.duration_since(self.last).as_secs_f64() * 1000.0;
+        histogram!("router_phase_duration_ms", ms, "phase" => phase);
+        self.phases.push((phase, ms));
+        self.last = now;
+    }
+    fn to_json(&self) -> serde_json::Value { self.phases.iter().map(|(p, ms)| (format!("{p}_ms"), json!(ms))).collect::<serde_json::Map<_, _>>().into() }
+}
+
+fn max_fragment_bytes() -> usize { std::env::var("ATP_MAX_FRAGMENT_BYTES").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(DEFAULT_MAX_FRAGMENT_BYTES) }
+
+/// Splits an outgoing frame whose payload content exceeds `limit` bytes into MORE-flagged text fragments.
+/// String content is chunked as-is, anything else as its JSON text; FIN moves to the last fragment and
+/// fields outside the schema (e.g. `adapter`) are copied onto every fragment.
+fn fragment_outgoing(msg: &serde_json::Value, limit: usize) -> Vec<String> {
+    let text = match &msg["payload"]["content"] { serde_json::Value::String(s) => s.clone(), other => other.to_string() };
+    if text.len() <= limit { return vec![msg.to_string()]; }
+    let Ok(mut base) = serde_json::from_value::<Frame>(msg.clone()) else { return vec![msg.to_string()]; };
+    let fin = base.has_flag(Flag::Fin);
+    base.flags.retain(|f| f != "FIN");
+    let mut frags = fragment_text_frame(base, &text, limit);
+    if let (true, Some(last)) = (fin, frags.last_mut()) { last.flags.push("FIN".into()); let _ = last.normalize_flags(false); last.checksum = last.compute_checksum().ok(); }
+    counter!("router_fragmented_frames_total", 1);
+    frags.into_iter().map(|f| {
+        let mut v = serde_json::to_value(f).unwrap_or_default();
+        copy_missing(&mut v, msg);
+        copy_missing(&mut v["payload"], &msg["payload"]);
+        v.to_string()
+    }).collect()
+}
+
+fn copy_missing(dst: &mut serde_json::Value, src: &serde_json::Value) {
+    if let (Some(d), Some(s)) = (dst.as_object_mut(), src.as_object()) { for (k, v) in s { d.entry(k.clone()).or_insert_with(|| v.clone()); } }
+}
+
+fn record_adapter_outcome(ep: &str, outcome: &'static str) { counter!("adapter_stream_total", 1, "adapter" => ep.to_string(), "outcome" => outcome); }
+/// `timeout` for deadline/channel-timeout statuses, `rpc_error` otherwise.
+fn status_outcome(status: &tonic::Status) -> &'static str {
+    if status.code() == tonic::Code::DeadlineExceeded || status.message().contains("Timeout expired") { "timeout" } else { "rpc_error" }
+}
+
+/// Whole-request budget from `meta.trace.deadline_ms`; on expiry the router finalizes over the finals it has.
+fn request_deadline(meta: &Meta) -> Option<Duration> {
+    meta.trace.as_ref().and_then(|t| t.get("deadline_ms")).and_then(|d| d.as_u64()).map(Duration::from_millis)
+}
+
+/// Consensus settings for one request. `meta.trace.consensus_threshold` replaces `base`'s threshold, and any adaptive
+/// schedule, for that request only; a value outside 0..=1, where every metric's similarities fall, is ignored with a warning.
+fn request_consensus_cfg<'a>(meta: &Meta, base: &'a consensus::ConsensusConfig) -> std::borrow::Cow<'a, consensus::ConsensusConfig> {
+    let Some(raw) = meta.trace.as_ref().and_then(|t| t.get("consensus_threshold")) else { return std::borrow::Cow::Borrowed(base); };
+    match raw.as_f64().map(|t| t as f32).filter(|t| (0.0..=1.0).contains(t)) {
+        Some(t) => std::borrow::Cow::Owned(consensus::ConsensusConfig { threshold: Some(t), adaptive: None, ..base.clone() }),
+        None => {
+            tracing::warn!(value = %raw, "ignoring meta.trace.consensus_threshold outside 0..=1");
+            std::borrow::Cow::Borrowed(base)
+        }
+    }
+}
+
+/// Responding adapters needed for a non-degraded final (`ATP_MIN_QUORUM`): a count such as `2`, or a
+/// fraction of the fanout such as `0.5` (rounded up). Defaults to 1.
+fn min_quorum(fanout: usize) -> usize {
+    let Some(raw) = std::env::var("ATP_MIN_QUORUM").ok() else { return 1; };
+    let q = match raw.trim().parse::<usize>() {
+        Ok(n) => n,
+        Err(_) => raw.trim().parse::<f64>().ok().filter(|f| (0.0..=1.0).contains(f)).map(|f| (f * fanout as f64).ceil() as usize).unwrap_or(1),
+    };
+    q.max(1)
+}
+
+/// Cap on finals considered for consensus per request (`ATP_MAX_FINALS`), bounding memory and O(n²) grouping.
+fn max_finals() -> usize { std::env::var("ATP_MAX_FINALS").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(32) }
+
+/// Adds a final unless `cap` are already held; then it only replaces the least confident one, and only if it
+/// is more confident (unknown confidence ranks lowest). Returns whether it was kept.
+fn admit_final(finals: &mut Vec<String>, sources: &mut Vec<(Option<String>, Option<f32>)>, cap: usize, text: String, source: (Option<String>, Option<f32>)) -> bool {
+    if finals.len() < cap { finals.push(text); sources.push(source); return true; }
+    counter!("finals_truncated_total", 1);
+    let conf = |c: Option<f32>| c.unwrap_or(f32::NEG_INFINITY);
+    let Some((weakest, _)) = sources.iter().enumerate().min_by(|a, b| conf(a.1.1).total_cmp(&conf(b.1.1))) else { return false; };
+    if conf(source.1) <= conf(sources[weakest].1) { return false; }
+    finals[weakest] = text;
+    sources[weakest] = source;
+    true
+}
+
+/// Applies an RFC 7386 JSON merge patch to `target` in place.
+fn merge_patch(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    let serde_json::Value::Object(p) = patch else { *target = patch.clone(); return; };
+    if !target.is_object() { *target = json!({}); }
+    let t = target.as_object_mut().expect("object");
+    for (k, v) in p {
+        if v.is_null() { t.remove(k); } else { merge_patch(t.entry(k.clone()).or_insert(serde_json::Value::Null), v); }
+    }
+}
+
+/// Adapter content shaped as `{"findings": [...]}`.
+#[derive(serde::Deserialize)]
+struct FindingsContent { findings: Vec<Finding> }
+fn adapter_findings(content_json: &str) -> Option<Vec<Finding>> { serde_json::from_str::<FindingsContent>(content_json).ok().map(|c| c.findings) }
+
+/// Upper bound on sub-requests in one `batch` payload.
+const MAX_BATCH_ITEMS: usize = 64;
+
+/// Capacity for running batch items beside the one the batch's own permit covers: up to `want` extra items, each
+/// holding a router-wide in-flight permit and, when the connection caps its streams, a stream slot. Only capacity
+/// free right now is taken, so a batch never waits on other requests; with none free its items run one at a time.
+fn batch_extra_permits(inflight: Option<&std::sync::Arc<tokio::sync::Semaphore>>, slot: Option<&tokio::sync::OwnedSemaphorePermit>, want: usize) -> Vec<[Option<tokio::sync::OwnedSemaphorePermit>; 2]> {
+    let mut extra = vec![];
+    while extra.len() < want {
+        let Ok(global) = inflight.map(|s| s.clone().try_acquire_owned()).transpose() else { break; };
+        let Ok(conn) = slot.map(|p| p.semaphore().clone().try_acquire_owned()).transpose() else { break; };
+        extra.push([global, conn]);
+    }
+    extra
+}
+
+/// Routes each element of a `batch` payload (`{"batch": [...]}`) as its own sub-request and answers with one
+/// `agent.result.batch` FIN frame. An element `{"id": .., "type": .., "content": ..}` keeps its `id` for correlation
+/// and its payload `type` (default `text`); any other element is the sub-request's `text` content itself and is
+/// correlated by its index. Items share the router's in-flight cap (`inflight`) and the connection's stream cap.
+async fn process_batch(item: WorkItem, inflight: Option<std::sync::Arc<tokio::sync::Semaphore>>) {
+    let frame = item.frame;
+    let items = match frame.payload.content.get("batch").and_then(|b| b.as_array()) {
+        Some(items) if !items.is_empty() && items.len() <= MAX_BATCH_ITEMS => items.clone(),
+        _ => { let _ = item.reply_tx.send(json!({"error":"invalid_batch","max_items":MAX_BATCH_ITEMS}).to_string()).await; return; }
+    };
+    counter!("router_batch_items_total", items.len() as u64);
+    let extra = batch_extra_permits(inflight.as_ref(), item.slot.as_deref(), items.len() - 1);
+    let concurrency = 1 + extra.len();
+    let subs = items.into_iter().enumerate().map(|(i, el)| {
+        let (id, ty, content) = match el.get("content") {
+            Some(c) => (el.get("id").cloned().unwrap_or(json!(i)), el.get("type").and_then(|t| t.as_str()).unwrap_or("text").to_string(), c.clone()),
+            None => (json!(i), "text".to_string(), el),
+        };
+        let mut sub = frame.clone();
+        sub.payload.r#type = ty;
+        sub.payload.content = content;
+        async move {
+            // Replies are drained while the sub-request runs, so one that streams more frames than the channel holds
+            // cannot block on a full channel.
+            let (reply_tx, mut reply_rx) = mpsc::channel::<String>(256);
+            let mut replies = vec![];
+            tokio::join!(process_request(WorkItem{ frame: sub, reply_tx, slot: None, conn: item.conn }), async {
+                while let Some(line) = reply_rx.recv().await { if let Ok(v) = serde_json::from_str::<serde_json::Value>(&line) { replies.push(v); } }
+            });
+            match (sub_final(&replies), replies.iter().find(|r| r.get("error").is_some() || r.get("control.status").is_some())) {
+                (Some(fin), _) => json!({"id": id, "index": i, "final": fin}),
+                (None, Some(err)) => json!({"id": id, "index": i, "error": err}),
+                (None, None) => json!({"id": id, "index": i, "error": {"error":"no_result"}}),
+            }
+        }
+    });
+    let results: Vec<serde_json::Value> = futures_util::stream::iter(subs).buffered(concurrency).collect().await;
+    drop(extra);
+    let reply = control_frame(&frame, frame.msg_seq + 2, "FIN", "agent.result.batch", json!({"results": results}));
+    counter!("frames_tx_total", 1, "kind"=>"batch");
+    let _ = item.reply_tx.send(reply.to_string()).await;
+}
+
+/// Content of the router's own final among a sub-request's replies, reassembled if the outbox fragmented it.
+fn sub_final(replies: &[serde_json::Value]) -> Option<serde_json::Value> {
+    let frames: Vec<&serde_json::Value> = replies.iter().filter(|r| r["payload"]["type"] == "agent.result.final" && r.get("adapter").is_none()).collect();
+    match frames.as_slice() {
+        [] => None,
+        [one] => Some(one["payload"]["content"].clone()),
+        many => {
+            let frags: Vec<Frame> = many.iter().filter_map(|f| serde_json::from_value((*f).clone()).ok()).collect();
+            reassemble_counted(&frags).and_then(|t| serde_json::from_str(&t).ok())
+        }
+    }
+}
+
+fn count_reassembly_failure(e: &atp_schema::ReassemblyError, fragments: usize) {
+    tracing::warn!(reason = e.reason(), error = %e, fragments, "reassembly failed");
+    counter!("router_reassembly_failures_total", 1, "reason" => e.reason());
+}
+/// [`atp_schema::reassemble_text`], counting failures in `router_reassembly_failures_total{reason}`.
+fn reassemble_counted(frags: &[Frame]) -> Option<String> {
+    atp_schema::reassemble_text(frags).map_err(|e| count_reassembly_failure(&e, frags.len())).ok()
+}
+
+/// Inbound fragments buffered per `session:stream` until the fragment without MORE completes the message.
+/// One message may be in flight per stream; idle buffers are evicted like [`SeqTracker`] entries. Each connection
+/// owns its reassembler (see [`ConnState`]), so a disconnect frees its partial messages and no other socket can
+/// add to them.
+struct Partial { msg_seq: u64, frags: Vec<Frame>, bytes: usize, touched: Instant }
+struct StreamReassembler { inner: std::sync::Mutex<HashMap<SessionKey, Partial>>, idle: Duration, max_bytes: usize }
+impl StreamReassembler {
+    fn new(idle: Duration, max_bytes: usize) -> Self { StreamReassembler { inner: std::sync::Mutex::new(HashMap::new()), idle, max_bytes } }
+    fn from_env() -> Self {
+        Self::new(Duration::from_secs(std::env::var("ATP_FRAGMENT_IDLE_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(60)), atp_schema::DEFAULT_MAX_MESSAGE_BYTES)
+    }
+    /// Passes unfragmented frames through; buffers fragments and returns the reassembled request once the last
+    /// arrives. A fragment out of `frag_seq` order, or from another `msg_seq` mid-message, discards the buffer,
+    /// as does going over [`atp_schema::DEFAULT_MAX_FRAGMENTS`] fragments or the byte cap.
+    fn accept(&self, frame: Frame) -> Result<Option<Frame>, atp_schema::ReassemblyError> {
+        let more = frame.has_flag(Flag::More);
+        let key = format!("{}:{}", frame.session_id, frame.stream_id);
+        let mut map = self.inner.lock().unwrap();
+        let idle = self.idle;
+        map.retain(|key, p| {
+            let live = p.touched.elapsed() < idle;
+            if !live {
+                tracing::warn!(stream = %key, fragments = p.frags.len(), bytes = p.bytes, "evicting idle partial message");
+                counter!("router_reassembly_evicted_total", 1);
+            }
+            live
+        });
+        if !more && frame.frag_seq == 0 && !map.contains_key(&key) { return Ok(Some(frame)); }
+        let p = map.entry(key.clone()).or_insert_with(|| Partial { msg_seq: frame.msg_seq, frags: vec![], bytes: 0, touched: Instant::now() });
+        let expected = p.frags.len() as u32;
+        if frame.frag_seq != expected || p.msg_seq != frame.msg_seq {
+            let got = frame.frag_seq;
+            map.remove(&key);
+            return Err(atp_schema::ReassemblyError::OutOfOrder { expected, got });
+        }
+        if p.frags.len() >= atp_schema::DEFAULT_MAX_FRAGMENTS as usize {
+            map.remove(&key);
+            return Err(atp_schema::ReassemblyError::TooManyFragments { max: atp_schema::DEFAULT_MAX_FRAGMENTS });
+        }
+        let bytes = p.bytes + serde_json::to_string(&frame.payload.content).map_or(0, |c| c.len());
+        if bytes > self.max_bytes {
+            map.remove(&key);
+            return Err(atp_schema::ReassemblyError::TooLarge { max_bytes: self.max_bytes });
+        }
+        p.bytes = bytes;
+        p.touched = Instant::now();
+        p.frags.push(frame);
+        if more { return Ok(None); }
+        let frags = map.remove(&key).expect("buffered fragments").frags;
+        let text = atp_schema::reassemble_text(&frags)?;
+        let mut whole = frags.into_iter().next().expect("first fragment");
+        whole.flags.retain(|f| f != "MORE");
+        whole.payload.content = json!({"text": text});
+        whole.payload.checksum = None; whole.payload.message_digest = None; whole.checksum = None;
+        Ok(Some(whole))
+    }
+}
+
+/// Scheduler entry point: batches fan out into sub-requests, everything else is one request.
+async fn dispatch(item: WorkItem) {
+    if item.frame.payload.r#type == "batch" { process_batch(item, Lazy::get(&SCHED).map(|s| s.permits.clone())).await } else { process_request(item).await }
+}
+
+async fn process_request(item: WorkItem) {
+    let started = Instant::now();
+    let span = tracing::info_span!(
+        "process_request",
+        stream_id = %item.frame.stream_id,
+        session_id = %item.frame.session_id,
+        msg_seq = item.frame.msg_seq,
+        frag_seq = item.frame.frag_seq,
+        qos = %item.frame.qos
+    );
+    let _e = span.enter();
+    let frame = item.frame;
+    let inflight = INFLIGHT.register(&inflight_key(&frame), item.conn);
+    let key = window_key(&frame, per_lane_windows());
+    if !opa_allow(&frame.meta) { let _ = item.reply_tx.send(json!({"error":"policy_denied"}).to_string()).await; record_request_duration(started, &frame.qos, "rejected"); return; }
+    let endpoints = match request_endpoints(&frame.meta).and_then(undrained_endpoints).and_then(|eps| capable_endpoints(eps, &frame)) {
+        Ok(eps) => eps,
+        Err(e) => { let _ = item.reply_tx.send(e.to_string()).await; record_request_duration(started, &frame.qos, "rejected"); return; }
+    };
+    let prompt_json = frame.payload.content.to_string();
+    let mut per_ep_pred = estimate_costs(&endpoints, &prompt_json).await;
+    // Sampled after estimating so cost weights are this request's, and only the chosen adapters are reserved for.
+    let endpoints = fanout::select(fanout::strategy(), endpoints, &per_ep_pred, &adapters::cached_p95_ms());
+    per_ep_pred.retain(|ep, _| endpoints.contains(ep));
+    let estimated = total_cost(&endpoints, &per_ep_pred);
+    if estimated.is_none() {
+        tracing::warn!(target: ADMISSION, session_id = %frame.session_id, stream_id = %frame.stream_id, adapters = endpoints.len(), "no adapter estimate available, using fallback");
+        counter!("router_estimate_fallback_total", 1);
+    }
+    let (need_tokens, need_usd) = estimated.unwrap_or_else(fallback_estimate);
+    let mut phases = PhaseTimer::new(started);
+    phases.mark("estimate");
+    histogram!("router_estimate_tokens", need_tokens as f64);
+    histogram!("router_estimate_usd_micros", need_usd as f64);
+
+    if frame.has_flag(Flag::EstimateOnly) {
+        let adapters: serde_json::Map<String, serde_json::Value> = per_ep_pred.iter().map(|(ep, (t, u))| (ep.clone(), json!({"tokens": t, "usd_micros": u}))).collect();
+        let estimate = json!({
+            "v": frame.v, "session_id": frame.session_id, "stream_id": frame.stream_id,
+            "msg_seq": frame.msg_seq, "frag_seq": frame.frag_seq, "flags":["FIN"], "qos": frame.qos,
+            "payload": {"type":"agent.estimate","content":{"tokens": need_tokens, "usd_micros": need_usd, "adapters": adapters, "fallback": estimated.is_none()}},
+        });
+        counter!("frames_tx_total", 1, "kind"=>"estimate", "qos"=>frame.qos.clone());
+        let _ = item.reply_tx.send(estimate.to_string()).await;
+        record_request_duration(started, &frame.qos, "estimated");
+        return;
+    }
+    if inflight.token.is_cancelled() { record_request_duration(started, &frame.qos, "aborted"); return; }
+    if need_tokens == 0 && need_usd == 0 && !endpoints.is_empty() {
+        // A zero estimate passes any window, so cost limits are effectively off for this request.
+        tracing::warn!(target: ADMISSION, session_id = %frame.session_id, stream_id = %frame.stream_id, qos = %frame.qos, adapters = endpoints.len(), fallback = estimated.is_none(), "admitting with a zero cost estimate");
+        counter!("router_zero_estimate_total", 1, "qos" => frame.qos.clone());
+    }
+    if let Err(util) = GLOBAL_WINDOWS.admit(&key, &frame.window, need_tokens, need_usd).await {
+        tracing::warn!(target: ADMISSION, session_id = %frame.session_id, stream_id = %frame.stream_id, qos = %frame.qos, decision = "reject", limiting = util.saturated,
+            inflight = util.inflight, max_parallel = util.max_parallel, tokens_used = util.tokens_used, max_tokens = util.max_tokens, usd_used = util.usd_used, max_usd = util.max_usd,
+            need_tokens, need_usd, "admission rejected");
+        let _ = item.reply_tx.send(busy_payload(&util).to_string()).await;
+        GLOBAL_WINDOWS.mark_backpressure(&key).await;
+        counter!("router_windows_reject_total", 1);
+        record_request_duration(started, &frame.qos, "rejected");
+        return;
+    }
+    let tenant = match (tenant_id(&frame.meta), tenant_window()) {
+        (Some(t), Some(tw)) => {
+            if let Err(util) = TENANT_WINDOWS.admit(&t, &tw, need_tokens, need_usd).await {
+                tracing::warn!(target: ADMISSION, session_id = %frame.session_id, stream_id = %frame.stream_id, qos = %frame.qos, tenant = %t, decision = "reject", limiting = util.saturated,
+                    scope = "tenant", inflight = util.inflight, max_parallel = util.max_parallel, "tenant admission rejected");
+                GLOBAL_WINDOWS.ack(&key, need_tokens, need_usd).await;
+                let mut busy = busy_payload(&util);
+                busy["scope"] = json!("tenant");
+                let _ = item.reply_tx.send(busy.to_string()).await;
+                counter!("router_tenant_reject_total", 1);
+                record_request_duration(started, &frame.qos, "rejected");
+                return;
+            }
+            Some(t)
+        }
+        _ => None,
+    };
+    let mut reservation = Reservation::new(&key, tenant, need_tokens, need_usd);
+    let pressure = GLOBAL_WINDOWS.pressure(&key).await;
+    if pressure > 0 {
+        let lane = lane_from_qos(&frame.qos).unwrap_or(Lane::Bronze);
+        let action = match pressure_action(&lane) { PressureAction::Ecn if pressure >= ecn_severe_marks() => PressureAction::Drop, a => a };
+        match action {
+            PressureAction::Proceed => {}
+            PressureAction::Ecn => {
+                tracing::info!(target: ADMISSION, session_id = %frame.session_id, stream_id = %frame.stream_id, qos = %frame.qos, decision = "mark", limiting = "pressure", lane = lane.as_str(), marks = pressure, "admission marked under mild pressure");
+                counter!("router_qos_marks_total", 1, "lane" => lane.as_str());
+                let _ = item.reply_tx.send(json!({"control.status":"ECN","action":"mark","reason":"pressure"}).to_string()).await;
+            }
+            PressureAction::Drop => {
+                tracing::warn!(target: ADMISSION, session_id = %frame.session_id, stream_id = %frame.stream_id, qos = %frame.qos, decision = "drop", limiting = "pressure", lane = lane.as_str(), marks = pressure, "admission dropped under pressure");
+                counter!("router_qos_drops_total", 1, "lane" => lane.as_str());
+                if matches!(lane, Lane::Bronze) { counter!("router_qos_drops_bronze_total", 1); }
+                let _ = item.reply_tx.send(json!({"control.status":"ECN","action":"drop","reason":"pressure"}).to_string()).await;
+                reservation.release().await;
+                record_request_duration(started, &frame.qos, "rejected");
+                return;
+            }
+            PressureAction::Delay(ms) => {
+                tracing::info!(target: ADMISSION, session_id = %frame.session_id, stream_id = %frame.stream_id, qos = %frame.qos, decision = "delay", limiting = "pressure", lane = lane.as_str(), delay_ms = ms, "admission delayed under pressure");
+                counter!("router_qos_delays_total", 1, "lane" => lane.as_str());
+                let _ = item.reply_tx.send(json!({"control.status":"ECN","action":"delay","reason":"pressure","suggested_wait_ms":ms}).to_string()).await;
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_millis(ms)) => {}
+                    _ = inflight.token.cancelled() => {
+                        reservation.release().await;
+                        record_request_duration(started, &frame.qos, "aborted");
+                        return;
+                    }
+                }
+            }
+        }
+    }
+    tracing::info!(target: ADMISSION, session_id = %frame.session_id, stream_id = %frame.stream_id, qos = %frame.qos, decision = "admit",
+        need_tokens, need_usd, max_parallel = frame.window.max_parallel, max_tokens = frame.window.max_tokens, max_usd = frame.window.max_usd_micros, "admission granted");
+    counter!("router_windows_admit_total", 1);
+    phases.mark("admission");
+    let outbox = Outbox::open(item.reply_tx.clone(), frame.msg_seq + 1, frame.has_flag(Flag::Compressed), inflight.token.clone());
+    let mut ack = json!({
+        "v": frame.v, "session_id": frame.session_id, "stream_id": frame.stream_id,
+        "msg_seq": frame.msg_seq, "frag_seq": frame.frag_seq, "flags":["ACK"], "qos": frame.qos,
+        "ttl": frame.ttl-1, "window": frame.window, "meta": frame.meta,
+        "payload": {"type":"agent.result.partial","content":{"router":"ack","resume_token":outbox.token}},
+    });
+    mark_last_hop(&mut ack);
+    let ack_json = ack.to_string();
+    counter!("frames_tx_total", 1, "kind"=>"ack", "qos"=>frame.qos.clone());
+    OBSERVERS.publish(&frame.session_id, &ack_json);
+    // Gone before the ack, the client has no resume token either, so there is nothing to wait for.
+    if item.reply_tx.send(ack_json).await.is_err() { counter!("router_client_gone_total", 1); inflight.token.cancel(); }
+
+    use atp_adapter_proto::atp::adapter::v1::StreamRequest;
+    let (tx, mut rx) = mpsc::channel::<serde_json::Value>(64);
+    let mut join_handles = vec![];
+    let req_span = tracing::info_span!("fanout", adapters = endpoints.len());
+    let _s = req_span.enter();
+    let deadline_at = request_deadline(&frame.meta).map(|d| started + d);
+
+    for ep in endpoints.clone() {
+        let txc = tx.clone();
+        let prompt = prompt_json.clone();
+        let v = frame.v; let sid = frame.session_id.clone(); let st = frame.stream_id.clone();
+        let msg_seq = frame.msg_seq; let frag_seq = frame.frag_seq; let qos = frame.qos.clone();
+        let ttl = frame.ttl-1; let w = frame.window.clone(); let m = frame.meta.clone();
+        join_handles.push(tokio::spawn(async move {
+            let span = tracing::info_span!("adapter_stream", adapter = %ep);
+            let _e = span.enter();
+            let _open = adapters::open_stream(&ep);
+            let mut observed_tokens: u64 = 0; let mut observed_usd: u64 = 0; let mut streamed = false;
+            let stream = if fake::enabled() { Ok(fake::stream(&ep)) } else {
+                let mut cli = match adapters::connect_retrying(&ep).await {
+                    Ok(c) => c,
+                    Err(e) => {
+                        record_adapter_outcome(&ep, if adapters::is_timeout(&e) { "timeout" } else { "connect_error" });
+                        let _ = txc.send(json!({"error":"connect","adapter":ep,"reason":e.to_string(),"timeout":adapters::is_timeout(&e)})).await;
+                        return;
+                    }
+                };
+                let req = || {
+                    let mut req = adapters::request(&ep, StreamRequest{ stream_id: "s".into(), prompt_json: prompt.clone() });
+                    // Sent as `grpc-timeout`, so adapters that honor deadlines stop work once the router stops waiting.
+                    if let Some(t) = deadline_at { req.set_timeout(t.saturating_duration_since(Instant::now())); }
+                    req
+                };
+                // Adapters that advertise `unary_only`, or answer `Stream` with Unimplemented, are asked through `Complete`.
+                let unary_only = adapters::cached_capabilities(&ep).is_some_and(|c| c.unary_only);
+                let opened = if unary_only { Err(tonic::Status::unimplemented("unary_only")) } else { cli.stream(req()).await };
+                match opened {
+                    Ok(r) => Ok(Box::pin(r.into_inner()) as adapters::ChunkStream),
+                    Err(status) if status.code() == tonic::Code::Unimplemented => {
+                        counter!("router_unary_fallback_total", 1, "adapter"=>ep.clone());
+                        cli.complete(req()).await.map(|r| {
+                            let mut chunk = r.into_inner();
+                            if !chunk.r#type.ends_with("final") { chunk.r#type = "agent.result.final".into(); }
+                            Box::pin(futures_util::stream::iter([Ok(chunk)])) as adapters::ChunkStream
+                        })
+                    }
+                    Err(status) => Err(status),
+                }
+            };
+            let adapter_frame = |ty: &str, content: &str, confidence: f64| json!({
+                "v": v, "session_id": sid, "stream_id": st,
+                "msg_seq": msg_seq+1, "frag_seq": frag_seq, "flags":["MORE"],
+                "qos": qos, "ttl": ttl, "window": w, "meta": m,
+                "payload": {"type": ty, "content": content, "confidence": confidence},
+                "adapter": ep,
+            });
+            match stream {
+                Ok(mut stream) => {
+                    let mut saw_final = false;
+                    let mut last_partial: Option<(String, f64)> = None;
+                    // Running document for adapters streaming DELTA merge patches.
+                    let mut merged: Option<serde_json::Value> = None;
+                    let mut outcome = "ok";
+                    loop {
+                        let mut res = match stream.next().await {
+                            Some(Ok(res)) => res,
+                            None => break,
+                            Some(Err(status)) => { outcome = status_outcome(&status); break; }
+                        };
+                        // Handle the stream chunk directly
+                        streamed = true;
+                        observed_tokens += (res.partial_in_tokens + res.partial_out_tokens) as u64;
+                        observed_usd += res.partial_usd_micros as u64;
+                        if res.flags.iter().any(|f| f == Flag::Delta.as_str()) {
+                            match serde_json::from_str::<serde_json::Value>(&res.content_json) {
+                                Ok(patch) => { let doc = merged.get_or_insert(serde_json::Value::Null); merge_patch(doc, &patch); res.content_json = doc.to_string(); }
+                                Err(_) => counter!("router_invalid_deltas_total", 1, "adapter"=>ep.clone()),
+                            }
+                        }
+                        if res.r#type.ends_with("final") { saw_final = true; } else { last_partial = Some((res.content_json.clone(), res.confidence)); }
+                        let out = adapter_frame(&res.r#type, &res.content_json, res.confidence);
+                        counter!("frames_tx_total", 1, "kind"=>"partial", "adapter"=>ep.clone());
+                        let _ = txc.send(out).await;
+                    }
+                    // Adapters that never emit a `*final` still contribute their last partial to consensus.
+                    if let (false, Some((content, confidence))) = (saw_final, last_partial) {
+                        let mut out = adapter_frame("agent.result.final", &content, confidence);
+                        out["payload"]["synthesized"] = json!(true);
+                        counter!("router_synthesized_finals_total", 1, "adapter"=>ep.clone());
+                        let _ = txc.send(out).await;
+                    }
+                    record_adapter_outcome(&ep, outcome);
+                    if outcome == "timeout" { let _ = txc.send(json!({"type":"timeout","adapter":ep})).await; }
+                }
+                Err(e) => {
+                    record_adapter_outcome(&ep, status_outcome(&e));
+                    let _ = txc.send(json!({"error":"rpc","adapter":ep,"reason":e.to_string(),"timeout":status_outcome(&e) == "timeout"})).await;
+                }
+            }
+            let _ = txc.send(json!({ "type":"stats","adapter":ep, "observed_tokens": observed_tokens, "observed_usd": observed_usd, "streamed": streamed })).await;
+        }));
+    }
+    drop(tx);
+
+    let mut finals: Vec<String> = vec![];
+    // Producing adapter and its confidence per final, for consensus tie-breaks.
+    let mut final_sources: Vec<(Option<String>, Option<f32>)> = vec![];
+    let mut observed_usd: HashMap<String, u64> = HashMap::new();
+    // Per-request cost receipt, over adapters that streamed at least one chunk: (estimated, observed) tokens/usd.
+    let mut cost = ((0u64, 0u64), (0u64, 0u64), 0usize);
+    let max_finals = max_finals();
+    let mut findings: Vec<Vec<Finding>> = vec![];
+    let mut provisional_sent = false;
+    let mut provisional_conf: f32 = 0.0;
+    let mut provisional_result: Option<consensus::ConsensusResult> = None;
+    let mut adapter_errors = 0usize;
+    let start_t = Instant::now();
+    // NO_CONSENSUS: the client aggregates itself, so finals are passed through ungrouped.
+    let passthrough = frame.has_flag(Flag::NoConsensus);
+    let consensus_cfg = request_consensus_cfg(&frame.meta, &CONSENSUS_CFG);
+    // CANDIDATES: each group representative at or above the floor is streamed as it emerges, keyed by its text with
+    // the (score, group size) last sent, so an unchanged representative is not sent twice.
+    let candidates = frame.has_flag(Flag::Candidates) && !passthrough;
+    let mut candidates_sent: HashMap<String, (f32, usize)> = HashMap::new();
+    let mut deadline_hit = false;
+    // Adapters whose connect or stream ended in a timeout; the request then finalizes as `adapter_timeout`.
+    let mut adapter_timeouts = 0usize;
+
+    loop {
+        let msgv = tokio::select! {
+            m = rx.recv() => match m { Some(m) => m, None => break },
+            _ = async { match deadline_at { Some(t) => tokio::time::sleep_until(t).await, None => std::future::pending().await } } => {
+                for j in &join_handles { j.abort(); }
+                counter!("router_deadline_finalized_total", 1);
+                deadline_hit = true;
+                break;
+            }
+            _ = inflight.token.cancelled() => {
+                for j in &join_handles { j.abort(); }
+                reservation.release().await;
+                counter!("router_requests_aborted_total", 1);
+                counter!("router_finalize_total", 1, "reason" => "aborted");
+                record_request_duration(started, &frame.qos, "aborted");
+                return;
+            }
+        };
+        if msgv.get("type").and_then(|x| x.as_str()) == Some("timeout") { adapter_timeouts += 1; continue; }
+        if let Some(_err) = msgv.get("error") {
+            adapter_errors += 1;
+            if msgv.get("timeout").and_then(|t| t.as_bool()) == Some(true) { adapter_timeouts += 1; }
+            outbox.send(&json!({"payload":{"type":"agent.result.partial","content":{"adapter_error":msgv}}})).await;
+            continue;
+        }
+        if msgv.get("type").and_then(|x| x.as_str()) == Some("stats") {
+            if let (Some(adapter), Some(obs_t), Some(obs_u)) = (
+                msgv.get("adapter").and_then(|x| x.as_str()),
+                msgv.get("observed_tokens").and_then(|x| x.as_u64()),
+                msgv.get("observed_usd").and_then(|x| x.as_u64()),
+            ) {
+                let reserved = per_ep_pred.get(adapter).cloned().unwrap_or((0, 0));
+                if msgv.get("streamed").and_then(|x| x.as_bool()) == Some(true) {
+                    cost = ((cost.0.0 + reserved.0, cost.0.1 + reserved.1), (cost.1.0 + obs_t, cost.1.1 + obs_u), cost.2 + 1);
+                }
+                observed_usd.insert(adapter.to_string(), obs_u);
+                GLOBAL_WINDOWS.true_up(&key, reserved, (obs_t, obs_u)).await;
+                if let Some(t) = &reservation.tenant { TENANT_WINDOWS.true_up(t, reserved, (obs_t, obs_u)).await; }
+                // Budget still held in the window, trued up per adapter as observed costs arrive.
+                let held = &mut reservation.held;
+                *held = (held.0.saturating_sub(reserved.0) + obs_t, held.1.saturating_sub(reserved.1) + obs_u);
+                if obs_t > reserved.0 || obs_u > reserved.1 { GLOBAL_WINDOWS.mark_backpressure(&key).await; }
+                if let Some((pred_t, pred_u)) = per_ep_pred.get(adapter).cloned() {
+                    let mape_t = if pred_t>0 { (obs_t as f64 - pred_t as f64).abs() / pred_t as f64 } else { 0.0 };
+                    let mape_u = if pred_u>0 { (obs_u as f64 - pred_u as f64).abs() / pred_u as f64 } else { 0.0 };
+                    histogram!("router_estimate_mape_tokens", mape_t);
+                    histogram!("router_estimate_mape_usd", mape_u);
+                    if obs_t > pred_t { counter!("router_estimate_under_rate_tokens_total", 1); }
+                    if obs_u > pred_u { counter!("router_estimate_under_rate_usd_total", 1); }
+                    histogram!("adapter_estimate_mape_tokens", mape_t, "adapter" => adapter.to_string());
+                    histogram!("adapter_estimate_mape_usd", mape_u, "adapter" => adapter.to_string());
+                }
+            }
+            continue;
+        }
+
+        outbox.send(&msgv).await;
+        match msgv["payload"]["type"].as_str() {
+            Some(t) if t.ends_with("final") => phases.mark("first_final"),
+            Some(_) if !phases.reached("first_final") => phases.mark("first_partial"),
+            _ => {}
+        }
+
+        if let Some(payload) = msgv.get("payload") {
+            if payload.get("type").and_then(|t| t.as_str()).unwrap_or("").ends_with("final") {
+                let mut kept = false;
+                if let Some(c) = payload.get("content") {
+                    let source = (msgv.get("adapter").and_then(|a| a.as_str()).map(str::to_string), payload.get("confidence").and_then(|c| c.as_f64()).map(|c| c as f32));
+                    kept = admit_final(&mut finals, &mut final_sources, max_finals, c.to_string(), source);
+                }
+                if !kept { continue; }
+                if let Some(fs) = payload.get("content").and_then(|c| c.as_str()).and_then(adapter_findings) { findings.push(fs); }
+                let mut snapshot = None;
+                if candidates {
+                    let cs = consensus::run(&finals, &[], &consensus_cfg);
+                    for c in candidate_frames(&frame, &cs, &mut candidates_sent, candidate_floor()) {
+                        counter!("frames_tx_total", 1, "kind"=>"candidate");
+                        outbox.send(&c).await;
+                    }
+                    snapshot = Some(cs);
+                }
+                if !passthrough && !provisional_sent && finals.len() >= 2 {
+                    let pcs = snapshot.take().unwrap_or_else(|| consensus::run(&finals, &[], &consensus_cfg));
+                    let top = pcs.scores.iter().cloned().fold(0.0, f32::max);
+                    if top >= 0.66 || start_t.elapsed() > Duration::from_millis(700) {
+                        let provisional = json!({
+                            "v": frame.v, "session_id": frame.session_id, "stream_id": frame.stream_id,
+                            "msg_seq": frame.msg_seq+1, "frag_seq": frame.frag_seq, "flags":["MORE"],
+                            "qos": frame.qos, "ttl": frame.ttl-1, "window": frame.window, "meta": frame.meta,
+                            "payload": {"type":"agent.result.provisional","content": {
+                                "finals": pcs.finals, "groups": pcs.groups, "scores": pcs.scores
+                            }, "expiry_ms": 1500}
+                        });
+                        counter!("frames_tx_total", 1, "kind"=>"provisional");
+                        outbox.send(&provisional).await;
+                        provisional_sent = true; provisional_conf = top;
+                        provisional_result = Some(pcs);
+                        record_confidence("provisional", top);
+                    }
+                }
+            }
+        }
+    }
+    for j in join_handles { let _ = j.await; }
+    phases.mark("streams");
+
+    let span = tracing::info_span!("consensus_final");
+    let _e2 = span.enter();
+    // Observed spend when the adapter reported it, otherwise its estimate.
+    let final_meta: Vec<consensus::FinalMeta> = final_sources.iter().map(|(adapter, confidence)| consensus::FinalMeta {
+        usd_micros: adapter.as_ref().and_then(|a| observed_usd.get(a).copied().filter(|u| *u > 0).or_else(|| per_ep_pred.get(a).map(|p| p.1))),
+        confidence: *confidence,
+    }).collect();
+    let content = if passthrough {
+        // Every kept final verbatim, labeled by adapter; no grouping, scoring or downgrade check.
+        let answers: Vec<serde_json::Value> = finals.iter().zip(&final_sources).zip(&final_meta)
+            .map(|((f, (adapter, confidence)), m)| json!({"adapter": adapter, "final": f, "confidence": confidence, "usd_micros": m.usd_micros}))
+            .collect();
+        json!({"finals": finals, "answers": answers, "consensus": false, "findings": merge_findings(&findings)})
+    } else {
+        let cs = consensus::run(&finals, &final_meta, &consensus_cfg);
+        if let Some(top) = cs.scores.iter().cloned().reduce(f32::max) {
+            record_confidence("final", top);
+            if provisional_sent && top + downgrade_margin() < provisional_conf {
+                let ctrl = control_frame(&frame, frame.msg_seq+2, "MORE", "control.status", json!({"provisional":"DOWNGRADED","from":provisional_conf,"to":top}));
+                counter!("frames_tx_total", 1, "kind"=>"control");
+                outbox.send(&ctrl).await;
+            }
+        }
+        // VERBOSE keeps every group's representative; otherwise groups under the minimum score are left out.
+        let representatives = if frame.has_flag(Flag::Verbose) { cs.representatives.clone() } else { cs.representatives_at_least(min_group_score()) };
+        let mut content = json!({
+            "finals": cs.finals, "representatives": representatives, "groups": cs.groups, "scores": cs.scores,
+            "ranked": cs.ranked, "findings": merge_findings(&findings), "embed_version": cs.embed_version
+        });
+        // Audit mode reorders finals, so provisional and final indices no longer identify the same answer.
+        if let Some(pcs) = provisional_result.as_ref().filter(|_| !consensus_cfg.audit) { content["stability"] = json!(consensus::stability(pcs, &cs)); }
+        content
+    };
+    let mut final_msg = json!({
+        "v": frame.v, "session_id": frame.session_id, "stream_id": frame.stream_id,
+        "msg_seq": frame.msg_seq+2, "frag_seq": frame.frag_seq, "flags":["FIN"],
+        "qos": frame.qos, "ttl": frame.ttl-1, "window": frame.window, "meta": frame.meta,
+        "payload": {"type":"agent.result.final","content": content}
+    });
+    let ((est_t, est_u), (obs_t, obs_u), billed) = cost;
+    final_msg["payload"]["content"]["cost"] = json!({"estimated": {"tokens": est_t, "usd_micros": est_u}, "observed": {"tokens": obs_t, "usd_micros": obs_u}, "adapters": billed});
+    let responding = final_sources.iter().filter_map(|(a, _)| a.as_deref()).collect::<std::collections::HashSet<_>>().len();
+    let quorum = min_quorum(endpoints.len());
+    if deadline_hit { final_msg["payload"]["content"]["deadline_exceeded"] = json!(true); }
+    let finalize_reason = if deadline_hit { "deadline" } else if adapter_timeouts > 0 { "adapter_timeout" } else { "all_streams_ended" };
+    counter!("router_finalize_total", 1, "reason" => finalize_reason);
+    if responding < quorum {
+        counter!("router_degraded_finals_total", 1);
+        let content = &mut final_msg["payload"]["content"];
+        content["degraded"] = json!(true);
+        content["responding"] = json!(responding);
+        content["quorum"] = json!(quorum);
+    }
+    phases.mark("finalize");
+    if final_timing() { final_msg["payload"]["content"]["timing"] = phases.to_json(); }
+    counter!("frames_tx_total", 1, "kind"=>"final");
+    outbox.send(&final_msg).await;
+    record_request_duration(started, &frame.qos, if finals.is_empty() && adapter_errors > 0 { "error" } else { "completed" });
+    reservation.release().await;
+}
+
+async fn adapters_health() -> String {
+    let results = adapters::check_endpoints(adapter_endpoints()).await;
+    serde_json::to_string(&results).unwrap_or("[]".into())
+}
+
+/// Bearer token for `/admin/*` routes (`ATP_ADMIN_TOKEN`); while it is unset they answer 404.
+fn admin_authorized(headers: &axum::http::HeaderMap) -> Result<(), axum::http::StatusCode> {
+    let token = std::env::var("ATP_ADMIN_TOKEN").ok().filter(|t| !t.is_empty()).ok_or(axum::http::StatusCode::NOT_FOUND)?;
+    let given = headers.get(axum::http::header::AUTHORIZATION).and_then(|v| v.to_str().ok()).and_then(|v| v.strip_prefix("Bearer "));
+    if given == Some(token.as_str()) { Ok(()) } else { Err(axum::http::StatusCode::UNAUTHORIZED) }
+}
+
+#[derive(serde::Deserialize)]
+struct DrainRequest { endpoint: String, drain: Option<bool> }
+/// `POST /admin/adapters/drain` `{"endpoint": ..., "drain": true|false}`: stops (or resumes) fanout to a configured
+/// endpoint while its open streams finish; remove it once `/adapters/health` shows `open_streams` at 0.
+async fn drain_route(headers: axum::http::HeaderMap, body: Result<axum::Json<DrainRequest>, axum::extract::rejection::JsonRejection>) -> (axum::http::StatusCode, String) {
+    if let Err(status) = admin_authorized(&headers) { return (status, json!({"error":"unauthorized"}).to_string()); }
+    let bad = |reason: String| (axum::http::StatusCode::BAD_REQUEST, json!({"error":"invalid_request","reason":reason}).to_string());
+    let axum::Json(req) = match body { Ok(b) => b, Err(e) => return bad(e.body_text()) };
+    let ep = match adapters::normalize_endpoint(&req.endpoint) { Ok(ep) => ep, Err(reason) => return bad(reason) };
+    if !adapter_endpoints().contains(&ep) { return (axum::http::StatusCode::NOT_FOUND, json!({"error":"unknown_adapter","endpoint":ep}).to_string()); }
+    let drain = req.drain.unwrap_or(true);
+    adapters::set_draining(&ep, drain);
+    tracing::info!(endpoint = %ep, draining = drain, "adapter drain state changed");
+    (axum::http::StatusCode::OK, json!({"endpoint": ep, "draining": drain, "open_streams": adapters::open_streams(&ep)}).to_string())
+}
+
+async fn mem_put(Query(params): Query<HashMap<String, String>>) -> String {
+    let enabled = std::env::var("FEATURE_WIRE_MEMORY").ok().as_deref() == Some("true");
+    let ns = params.get("ns").cloned().unwrap_or_else(|| "tenant/acme".into());
+    let key = params.get("key").cloned().unwrap_or_else(|| "demo".into());
+    if enabled {
+        let url = std::env::var("MEMORY_GATEWAY_URL").unwrap_or_else(|_| "http://memory-gateway:8080".into());
+        let url = format!("{}/v1/memory/{}/{}", url.trim_end_matches('/'), ns, key);
+        let body = serde_json::json!({"object":{"type":"demo","note":"hello from router"}});
+        match reqwest::Client::new().put(url).json(&body).send().await {
+            Ok(resp) => return format!("ok: {}", resp.status()),
+            Err(e) => return format!("error: {}", e),
+        }
+    }
+    "memory wiring disabled".into()
+}
+
+/// How far final consensus may fall below a sent provisional before a DOWNGRADED status (`ATP_DOWNGRADE_MARGIN`).
+fn downgrade_margin() -> f32 { std::env::var("ATP_DOWNGRADE_MARGIN").ok().and_then(|v| v.parse().ok()).filter(|m: &f32| m.is_finite() && *m >= 0.0).unwrap_or(0.05) }
+/// Smallest group score whose representative appears in the final frame (`CONSENSUS_MIN_GROUP_SCORE`, default 0).
+fn min_group_score() -> f32 { std::env::var("CONSENSUS_MIN_GROUP_SCORE").ok().and_then(|v| v.parse().ok()).filter(|m: &f32| m.is_finite()).unwrap_or(0.0) }
+
+/// Lowest group score (share of finals) at which a representative is streamed as a candidate (`ATP_CANDIDATE_FLOOR`, default 0.5).
+fn candidate_floor() -> f32 { std::env::var("ATP_CANDIDATE_FLOOR").ok().and_then(|v| v.parse().ok()).filter(|m: &f32| m.is_finite()).unwrap_or(0.5) }
+
+/// `agent.result.candidate` frames for representatives that reached `floor` or changed since last sent; a candidate
+/// already sent is updated even once its group falls below the floor, so clients can demote it.
+fn candidate_frames(req: &Frame, cs: &consensus::ConsensusResult, sent: &mut HashMap<String, (f32, usize)>, floor: f32) -> Vec<serde_json::Value> {
+    let mut out = vec![];
+    for (rank, rep) in cs.ranked.iter().enumerate() {
+        let state = (rep.score, rep.group_size);
+        match sent.get(&rep.text) {
+            Some(prev) if *prev == state => continue,
+            None if rep.score < floor => continue,
+            _ => {}
+        }
+        sent.insert(rep.text.clone(), state);
+        out.push(json!({
+            "v": req.v, "session_id": req.session_id, "stream_id": req.stream_id,
+            "msg_seq": req.msg_seq+1, "frag_seq": req.frag_seq, "flags":["MORE"],
+            "qos": req.qos, "ttl": req.ttl.saturating_sub(1), "window": req.window, "meta": req.meta,
+            "payload": {"type":"agent.result.candidate","content": {
+                "text": rep.text, "index": rep.index, "score": rep.score, "group_size": rep.group_size, "rank": rank, "finals": cs.finals.len()
+            }}
+        }));
+    }
+    out
+}
+
+/// A schema-conformant control frame on the request's stream.
+fn control_frame(req: &Frame, msg_seq: u64, flag: &str, ty: &str, content: serde_json::Value) -> serde_json::Value {
+    let mut ctrl = json!({
+        "v": req.v, "session_id": req.session_id, "stream_id": req.stream_id,
+        "msg_seq": msg_seq, "frag_seq": 0, "flags": [flag],
+        "qos": req.qos, "ttl": req.ttl.saturating_sub(1), "window": req.window, "meta": req.meta,
+        "payload": {"type": ty, "content": content},
+    });
+    mark_last_hop(&mut ctrl);
+    ctrl
+}
+
+/// Cancels the in-flight requests `conn` submitted on the frame's tenant/session/stream and builds the
+/// `control.aborted` reply.
+fn abort_stream(frame: &Frame, conn: Option<u64>) -> serde_json::Value {
+    let cancelled = INFLIGHT.cancel(&inflight_key(frame), conn);
+    counter!("frames_tx_total", 1, "kind"=>"control");
+    control_frame(frame, frame.msg_seq, "FIN", "control.aborted", json!({"cancelled": cancelled}))
+}
+
+/// Per-connection cap on requests in flight (`ATP_WS_MAX_STREAMS`; unset or 0 means unlimited). An admitted request
+/// carries its slot in the work item, so the slot frees when the request finishes, however it ends.
+struct ConnStreams { max: usize, slots: std::sync::Arc<tokio::sync::Semaphore> }
+impl ConnStreams {
+    fn new(max: usize) -> Self { ConnStreams { max, slots: std::sync::Arc::new(tokio::sync::Semaphore::new(max)) } }
+    fn from_env() -> Option<Self> { std::env::var("ATP_WS_MAX_STREAMS").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).map(Self::new) }
+    /// Claims a slot for `item`, or returns the reply refusing it while the connection is at its cap.
+    fn admit(&self, item: &mut WorkItem) -> Result<(), serde_json::Value> {
+        match self.slots.clone().try_acquire_owned() {
+            Ok(permit) => { item.slot = Some(std::sync::Arc::new(permit)); Ok(()) }
+            Err(_) => {
+                counter!("router_conn_stream_limit_total", 1);
+                Err(json!({"control.status":"CONN_STREAM_LIMIT","stream_id":item.frame.stream_id,"max_streams":self.max}))
+            }
+        }
+    }
+}
+
+/// What one `/ws` connection (or one replay) owns: an id scoping its aborts, its stream cap, its partially
+/// reassembled inbound messages, and the validated default window merged into its frames (unbounded unless set
+/// with [`ConnState::with_default_window`]). Dropped with the connection.
+struct ConnState { id: u64, streams: Option<ConnStreams>, fragments: StreamReassembler, default_window: Window }
+static NEXT_CONN_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+impl ConnState {
+    fn from_env() -> Self { ConnState { streams: ConnStreams::from_env(), ..Self::uncapped() } }
+    /// No stream cap, as for replays.
+    fn uncapped() -> Self {
+        ConnState { id: NEXT_CONN_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed), streams: None, fragments: StreamReassembler::from_env(), default_window: Window::default() }
+    }
+    fn with_default_window(self, default_window: Window) -> Self { ConnState { default_window, ..self } }
+}
+
+/// Handles one inbound text frame exactly as received on a socket: validate, claim a stream slot, then enqueue on its lane.
+async fn ingest_text(txt: &str, out_tx: &mpsc::Sender<String>, conn: &ConnState) {
+    let Some((mut item, lane)) = route_inbound(txt, out_tx, conn).await else { return; };
+    if let Some(Err(e)) = conn.streams.as_ref().map(|s| s.admit(&mut item)) { let _ = out_tx.send(e.to_string()).await; return; }
+    match lane {
+        Lane::Gold => { let _ = SCHED.gold.send(item).await; }
+        Lane::Silver => { let _ = SCHED.silver.send(item).await; }
+        Lane::Bronze => { let _ = SCHED.bronze.send(item).await; }
+    }
+}
+
+/// Everything `ingest_text` does short of enqueueing: replies to control frames and invalid input itself,
+/// and returns the work item and its lane when the frame should be scheduled.
+async fn route_inbound(txt: &str, out_tx: &mpsc::Sender<String>, conn: &ConnState) -> Option<(WorkItem, Lane)> {
+    let parse: Result<Frame, _> = serde_json::from_str(txt);
+    if parse.is_err() { let _ = out_tx.send(json!({"error":"invalid_frame"}).to_string()).await; return None; }
+    let mut parsed = parse.unwrap();
+    if let Err(e) = normalize_flags(&mut parsed, strict_flags()) { let _ = out_tx.send(e.to_string()).await; return None; }
+    // Fragmented requests are rebuilt first, so transforms and scheduling see one whole frame.
+    let mut whole = match conn.fragments.accept(parsed) {
+        Ok(Some(f)) => f,
+        Ok(None) => return None,
+        Err(e) => {
+            count_reassembly_failure(&e, 1);
+            let _ = out_tx.send(json!({"error":"reassembly_failed","reason":e.reason(),"detail":e.to_string()}).to_string()).await;
+            return None;
+        }
+    };
+    // The COMPRESSED flag stays on the request so its replies are compressed too.
+    if whole.has_flag(Flag::Compressed) {
+        match compression::CODEC.decompress(&whole.payload.content) {
+            Ok(content) => whole.payload.content = content,
+            Err(e) => {
+                counter!("router_decompress_failures_total", 1);
+                let _ = out_tx.send(json!({"error":"decompress_failed","detail":e.to_string()}).to_string()).await;
+                return None;
+            }
+        }
+    }
+    let frame = match transform::apply(whole) {
+        Ok(f) => f,
+        Err(transform::RejectReason(reason)) => {
+            counter!("router_frames_rejected_total", 1);
+            let _ = out_tx.send(json!({"error":"frame_rejected","reason":reason}).to_string()).await;
+            return None;
+        }
+    };
+    counter!("frames_rx_total", 1, "qos"=>frame.qos.clone());
+    tracing::debug!(
+        session_id=%frame.session_id,
+        stream_id=%frame.stream_id,
+        msg_seq=frame.msg_seq,
+        frag_seq=frame.frag_seq,
+        qos=%frame.qos,
+        ?frame.flags,
+        "frame_rx"
+    );
+    if frame.ttl == 0 { let _ = out_tx.send(json!({"error":"ttl_expired"}).to_string()).await; return None; }
+    if frame.payload.r#type == "control.abort" { let _ = out_tx.send(abort_stream(&frame, Some(conn.id)).to_string()).await; return None; }
+    if frame.payload.r#type == "control.resume" { if let Some(e) = resume_stream(&frame, out_tx.clone()) { let _ = out_tx.send(e.to_string()).await; } return None; }
+    if let Err(violations) = content_schema::validate(frame.meta.task_type.as_deref(), &frame.payload.content) {
+        let task_type = frame.meta.task_type.clone().unwrap_or_default();
+        counter!("router_content_schema_rejects_total", 1, "task_type"=>task_type.clone());
+        let _ = out_tx.send(json!({"error":"content_schema_mismatch","task_type":task_type,"violations":violations}).to_string()).await;
+        return None;
+    }
+    if let Err(e) = check_msg_seq(&MSG_SEQS, &frame, strict_msg_seq()) { let _ = out_tx.send(e.to_string()).await; return None; }
+    let mut frame = frame;
+    let clamped = frame.window.intersect(&org_window()).intersect(&conn.default_window);
+    if clamped != frame.window {
+        tracing::warn!(session_id = %frame.session_id, stream_id = %frame.stream_id, requested = ?frame.window, effective = ?clamped, "window clamped to server limits");
+        counter!("router_window_clamped_total", 1);
+        frame.window = clamped;
+    }
+    let lane = match resolve_lane(&frame.qos, strict_qos()) {
+        Ok(l) => l,
+        Err(e) => { let _ = out_tx.send(e.to_string()).await; return None; }
+    };
+    Some((WorkItem{ frame, reply_tx: out_tx.clone(), slot: None, conn: Some(conn.id) }, lane))
+}
+
+static WS_CONNECTIONS: std::sync::atomic::AtomicI64 = std::sync::atomic::AtomicI64::new(0);
+/// Counts a live WebSocket in `router_ws_connections_active` until dropped, whichever way the handler exits.
+struct WsConnectionGuard;
+impl WsConnectionGuard {
+    fn new() -> Self { let n = WS_CONNECTIONS.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1; gauge!("router_ws_connections_active", n as f64); WsConnectionGuard }
+}
+impl Drop for WsConnectionGuard {
+    fn drop(&mut self) { let n = WS_CONNECTIONS.fetch_sub(1, std::sync::atomic::Ordering::SeqCst) - 1; gauge!("router_ws_connections_active", n as f64); }
+}
+
+/// How replies are packed into WebSocket text messages, negotiated per connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Framing { Message, Lines }
+impl Framing {
+    fn parse(s: &str) -> Option<Self> { match s { "message" => Some(Framing::Message), "lines" | "jsonl" => Some(Framing::Lines), _ => None } }
+}
+
+/// Whether a connection's first frame asks for JSON Lines framing via the `JSONL` flag.
+fn requests_lines(txt: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(txt).ok().and_then(|v| v["flags"].as_array().map(|f| f.iter().any(|x| x == "JSONL"))).unwrap_or(false)
+}
+
+/// Writes replies to `sink`: one message per reply, or in lines mode every reply already queued as newline-terminated JSON Lines in one message.
+/// Control messages (pings, pongs, close) from `ctl` go out ahead of queued replies; a close ends the pump.
+async fn pump_replies<S: futures_util::Sink<Message> + Unpin>(mut rx: mpsc::Receiver<String>, mut ctl: mpsc::Receiver<Message>, lines: std::sync::Arc<std::sync::atomic::AtomicBool>, mut sink: S) {
+    let mut ctl_open = true;
+    loop {
+        let line = tokio::select! {
+            biased;
+            c = ctl.recv(), if ctl_open => {
+                match c {
+                    Some(m) => { let close = matches!(m, Message::Close(_)); if sink.send(m).await.is_err() || close { break; } }
+                    None => ctl_open = false,
+                }
+                continue;
+            }
+            line = rx.recv() => match line { Some(l) => l, None => break },
+        };
+        let text = if lines.load(std::sync::atomic::Ordering::SeqCst) {
+            let mut batch = line + "\n";
+            while let Ok(next) = rx.try_recv() { batch.push_str(&next); batch.push('\n'); }
+            batch
+        } else { line };
+        if sink.send(Message::Text(text)).await.is_err() { break; }
+    }
+}
+
+/// Interval between server pings (`ATP_WS_PING_MS`, default 20s) and how long a connection may go without
+/// receiving anything, pongs included, before it is closed (`ATP_WS_IDLE_TIMEOUT_MS`, default 60s).
+fn ws_keepalive() -> (Duration, Duration) {
+    let ms = |k: &str, d: u64| Duration::from_millis(std::env::var(k).ok().and_then(|v| v.parse().ok()).filter(|v| *v > 0).unwrap_or(d));
+    (ms("ATP_WS_PING_MS", 20_000), ms("ATP_WS_IDLE_TIMEOUT_MS", 60_000))
+}
+
+async fn handle_socket(socket: WebSocket, lines: bool, default_window: Window) {
+    let (sender, receiver) = socket.split();
+    serve_socket(sender, receiver, lines, default_window).await
+}
+
+/// Serves one `/ws` connection, whichever WebSocket stack carries it.
+async fn serve_socket<S, R, E>(sender: S, mut receiver: R, lines: bool, default_window: Window)
+where S: futures_util::Sink<Message> + Unpin + Send + 'static, R: futures_util::Stream<Item = Result<Message, E>> + Unpin
+{
+    let _conn = WsConnectionGuard::new();
+    let span = tracing::info_span!("ws_session");
+    let _e = span.enter();
+    let (out_tx, out_rx) = mpsc::channel::<String>(128);
+    let (ctl_tx, ctl_rx) = mpsc::channel::<Message>(8);
+    let lines = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(lines));
+    tokio::spawn(pump_replies(out_rx, ctl_rx, lines.clone(), sender));
+    let (ping_every, idle_timeout) = ws_keepalive();
+    let mut ping = tokio::time::interval_at(Instant::now() + ping_every, ping_every);
+    let mut last_seen = Instant::now();
+    let mut first = true;
+    let conn = ConnState::from_env().with_default_window(default_window);
+    loop {
+        let msg = tokio::select! {
+            m = receiver.next() => match m { Some(m) => m, None => break },
+            _ = ping.tick() => { let _ = ctl_tx.send(Message::Ping(vec![])).await; continue; }
+            _ = tokio::time::sleep_until(last_seen + idle_timeout) => {
+                tracing::info!(idle_ms = idle_timeout.as_millis() as u64, "closing idle websocket");
+                counter!("router_ws_idle_closed_total", 1);
+                let _ = ctl_tx.send(Message::Close(Some(axum::extract::ws::CloseFrame{ code: axum::extract::ws::close_code::AWAY, reason: "idle timeout".into() }))).await;
+                break;
+            }
+        };
+        last_seen = Instant::now();
+        match msg {
+            Ok(Message::Text(txt)) => {
+                if std::mem::take(&mut first) && requests_lines(&txt) { lines.store(true, std::sync::atomic::Ordering::SeqCst); }
+                ingest_text(&txt, &out_tx, &conn).await
+            }
+            Ok(Message::Binary(_)) => { let _ = out_tx.send(r#"{"error":"binary_not_supported"}"#.into()).await; }
+            // tungstenite queues the pong for a ping itself; either way the peer counts as alive.
+            Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => {}
+            Ok(Message::Close(_)) | Err(_) => break,
+        }
+    }
+}
+
+/// `GET /ws/observe?session_id=...`: a read-only copy of the frames the router emits for that session. Since it
+/// exposes other clients' traffic, it is 404 unless `ATP_WS_OBSERVE` is set and needs the `ATP_ADMIN_TOKEN` bearer.
+async fn ws_observe_handler(ws: WebSocketUpgrade, headers: axum::http::HeaderMap, Query(params): Query<HashMap<String, String>>) -> Response {
+    if !matches!(std::env::var("ATP_WS_OBSERVE").ok().as_deref(), Some("1") | Some("true")) { return axum::http::StatusCode::NOT_FOUND.into_response(); }
+    if let Err(status) = admin_authorized(&headers) { return (status, json!({"error":"unauthorized"}).to_string()).into_response(); }
+    let Some(session_id) = params.get("session_id").filter(|s| !s.is_empty()).cloned() else {
+        return (axum::http::StatusCode::BAD_REQUEST, json!({"error":"session_id_required"}).to_string()).into_response();
+    };
+    ws.on_upgrade(move |socket| observe_socket(socket, session_id))
+}
+
+async fn observe_socket(mut socket: WebSocket, session_id: String) {
+    let _conn = WsConnectionGuard::new();
+    counter!("router_ws_observers_total", 1);
+    let mut frames = OBSERVERS.subscribe(&session_id);
+    loop {
+        tokio::select! {
+            f = frames.recv() => {
+                let text = match f {
+                    Ok(text) => text,
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => json!({"error":"observer_lagged","skipped":skipped}).to_string(),
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                };
+                if socket.send(Message::Text(text)).await.is_err() { break; }
+            }
+            m = socket.recv() => match m {
+                Some(Ok(Message::Text(_))) | Some(Ok(Message::Binary(_))) => { if socket.send(Message::Text(json!({"error":"read_only"}).to_string())).await.is_err() { break; } }
+                Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => {}
+                _ => break,
+            },
+        }
+    }
+}
+
+/// Assembles the router's HTTP and WebSocket routes. Hooks set on the builder take effect when [`RouterBuilder::build`]
+/// runs; like the registries they land in, they are process-wide rather than per router.
+#[derive(Default)]
+pub struct RouterBuilder {
+    transforms: Vec<std::sync::Arc<dyn transform::FrameTransform>>,
+    consensus: Option<std::sync::Arc<dyn consensus::ConsensusFn>>,
+    content_schemas: Option<content_schema::ContentSchemas>,
+}
+impl RouterBuilder {
+    pub fn new() -> Self { Self::default() }
+    /// Adds a pre-routing transform; see [`transform`]. At build the builder's transforms replace the chain.
+    pub fn frame_transform(mut self, t: impl transform::FrameTransform + 'static) -> Self { self.transforms.push(std::sync::Arc::new(t)); self }
+    /// Replaces the built-in consensus at build; see [`consensus::ConsensusFn`].
+    pub fn consensus(mut self, f: impl consensus::ConsensusFn + 'static) -> Self { self.consensus = Some(std::sync::Arc::new(f)); self }
+    /// Replaces the `ATP_CONTENT_SCHEMA_DIR` registry at build; see [`content_schema`].
+    pub fn content_schemas(mut self, s: content_schema::ContentSchemas) -> Self { self.content_schemas = Some(s); self }
+    /// Panics if an `ATP_DEFAULT_*` window variable is invalid; `main` checks them first with [`load_default_window`].
+    pub fn build(self) -> Router {
+        let default_window = default_window().unwrap_or_else(|e| panic!("{e}"));
+        if !self.transforms.is_empty() { transform::install(self.transforms); }
+        if let Some(f) = self.consensus { consensus::install_shared(f); }
+        if let Some(s) = self.content_schemas { content_schema::install(s); }
+        Router::new()
+            .route("/healthz",get(||async{"ok"}))
+            .route("/readyz",get(readyz_route))
+            .route("/version",get(version_route))
+            .route("/consensus",axum::routing::post(consensus_route))
+            .route("/consensus/confidence",get(confidence_route))
+            .route("/metrics",get(metrics_handler))
+            .route("/metrics/json",get(metrics_json_route))
+            .route("/ws",get(move |params, req| ws_handler(params, req, default_window.clone())))
+            .route("/ws/observe",get(ws_observe_handler))
+            .route("/agp/explain",get(explain_route))
+            .route("/debug/scheduler",get(debug_scheduler_route))
+            .route("/adapters/health", get(adapters_health))
+            .route("/admin/adapters/drain", axum::routing::post(drain_route))
+            .route("/mem/put", get(mem_put))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atp_adapter_proto::atp::adapter::v1::{adapter_service_server::{AdapterService, AdapterServiceServer}, EstimateRequest, EstimateResponse, StreamRequest, StreamChunk, HealthRequest, HealthResponse, CapabilitiesRequest, CapabilitiesResponse};
+    use std::pin::Pin;
+    use tonic::{Request, Response as GrpcResponse, Status};
+
+    /// Serializes tests that mutate process-wide env vars such as `ADAPTER_ENDPOINTS`.
+    static ENV_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    /// Scriptable in-process `AdapterService`: `chunks` are streamed as `(type, content_json)` pairs `chunk_delay` apart,
+    /// each `*_error` makes that RPC fail with `Status::internal`, `capabilities: None` answers `Unimplemented`
+    /// (like an adapter built before the RPC existed), `usage` is the `(in_tokens, out_tokens, usd_micros)` reported
+    /// on every chunk, `streams` counts stream calls, `grpc_timeouts` collects each stream call's `grpc-timeout` header
+    /// and `metadata` records the request metadata of every estimate, stream and health call. With `unary` set the
+    /// adapter cannot stream: `stream` answers `Unimplemented` and `complete` returns that `(type, content_json)`,
+    /// counted in `completes`.
+    #[derive(Default)]
+    struct MockAdapter { chunks: Vec<(&'static str, &'static str)>, chunk_delay: Duration, estimate: EstimateResponse, streams: std::sync::Arc<std::sync::atomic::AtomicUsize>, flags: Vec<&'static str>, stream_error: Option<&'static str>, estimate_error: Option<&'static str>, health: HealthResponse, health_error: Option<&'static str>, capabilities: Option<CapabilitiesResponse>, usage: (u64, u64, u64), grpc_timeouts: std::sync::Arc<std::sync::Mutex<Vec<String>>>, metadata: std::sync::Arc<std::sync::Mutex<Vec<(&'static str, tonic::metadata::MetadataMap)>>>, unary: Option<(&'static str, &'static str)>, completes: std::sync::Arc<std::sync::atomic::AtomicUsize> }
+    #[tonic::async_trait]
+    impl AdapterService for MockAdapter {
+        async fn estimate(&self, r: Request<EstimateRequest>) -> Result<GrpcResponse<EstimateResponse>, Status> {
+            self.metadata.lock().unwrap().push(("estimate", r.metadata().clone()));
+            if let Some(msg) = self.estimate_error { return Err(Status::internal(msg)); }
+            Ok(GrpcResponse::new(self.estimate.clone()))
+        }
+        type StreamStream = Pin<Box<dyn futures_util::Stream<Item = Result<StreamChunk, Status>> + Send>>;
+        async fn stream(&self, r: Request<StreamRequest>) -> Result<GrpcResponse<Self::StreamStream>, Status> {
+            self.streams.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.metadata.lock().unwrap().push(("stream", r.metadata().clone()));
+            if let Some(t) = r.metadata().get("grpc-timeout").and_then(|v| v.to_str().ok()) { self.grpc_timeouts.lock().unwrap().push(t.to_string()); }
+            if let Some(msg) = self.stream_error { return Err(Status::internal(msg)); }
+            if self.unary.is_some() { return Err(Status::unimplemented("stream")); }
+            let chunks: Vec<StreamChunk> = self.chunks.iter().map(|(ty, content)| StreamChunk{ r#type: ty.to_string(), content_json: content.to_string(), confidence: 0.5, more: true, flags: self.flags.iter().map(|f| f.to_string()).collect(), partial_in_tokens: self.usage.0, partial_out_tokens: self.usage.1, partial_usd_micros: self.usage.2 }).collect();
+            let delay = self.chunk_delay;
+            Ok(GrpcResponse::new(Box::pin(futures_util::stream::iter(chunks).then(move |c| async move { tokio::time::sleep(delay).await; Ok(c) }))))
+        }
+        async fn complete(&self, r: Request<StreamRequest>) -> Result<GrpcResponse<StreamChunk>, Status> {
+            self.completes.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.metadata.lock().unwrap().push(("complete", r.metadata().clone()));
+            let (ty, content) = self.unary.ok_or_else(|| Status::unimplemented("complete"))?;
+            Ok(GrpcResponse::new(StreamChunk{ r#type: ty.to_string(), content_json: content.to_string(), confidence: 0.5, partial_in_tokens: self.usage.0, partial_out_tokens: self.usage.1, partial_usd_micros: self.usage.2, ..Default::default() }))
+        }
+        async fn health(&self, r: Request<HealthRequest>) -> Result<GrpcResponse<HealthResponse>, Status> {
+            self.metadata.lock().unwrap().push(("health", r.metadata().clone()));
+            if let Some(msg) = self.health_error { return Err(Status::internal(msg)); }
+            Ok(GrpcResponse::new(self.health))
+        }
+        async fn capabilities(&self, _r: Request<CapabilitiesRequest>) -> Result<GrpcResponse<CapabilitiesResponse>, Status> {
+            self.capabilities.clone().map(GrpcResponse::new).ok_or_else(|| Status::unimplemented("capabilities"))
+        }
+    }
+
+    async fn spawn_mock(mock: MockAdapter) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = futures_util::stream::unfold(listener, |l| async move { Some((l.accept().await.map(|(s, _)| s), l)) });
+        tokio::spawn(tonic::transport::Server::builder().add_service(AdapterServiceServer::new(mock)).serve_with_incoming(incoming));
+        format!("http://{}", addr)
+    }
+
+    /// Spawns every mock and points `ADAPTER_ENDPOINTS` at them, in order; hold `ENV_LOCK` while using it.
+    async fn use_mocks(mocks: Vec<MockAdapter>) -> Vec<String> {
+        let mut eps = vec![];
+        for m in mocks { eps.push(spawn_mock(m).await); }
+        std::env::set_var("ADAPTER_ENDPOINTS", json!(eps).to_string());
+        eps
+    }
+
+    /// A metric observation captured by [`CaptureRecorder`].
+    #[derive(Clone, Debug)]
+    struct Sample { name: String, labels: Vec<(String, String)>, value: f64 }
+    static SAMPLES: std::sync::Mutex<Vec<Sample>> = std::sync::Mutex::new(Vec::new());
+
+    struct CaptureHandle(metrics::Key);
+    impl CaptureHandle {
+        fn push(&self, value: f64) {
+            let labels = self.0.labels().map(|l| (l.key().to_string(), l.value().to_string())).collect();
+            SAMPLES.lock().unwrap().push(Sample{ name: self.0.name().to_string(), labels, value });
+        }
+    }
+    impl metrics::CounterFn for CaptureHandle { fn increment(&self, v: u64) { self.push(v as f64) } fn absolute(&self, v: u64) { self.push(v as f64) } }
+    impl metrics::GaugeFn for CaptureHandle { fn increment(&self, v: f64) { self.push(v) } fn decrement(&self, v: f64) { self.push(-v) } fn set(&self, v: f64) { self.push(v) } }
+    impl metrics::HistogramFn for CaptureHandle { fn record(&self, v: f64) { self.push(v) } }
+
+    /// Process-wide test recorder; the router's own Prometheus recorder is only installed by `/metrics`.
+    struct CaptureRecorder;
+    impl metrics::Recorder for CaptureRecorder {
+        fn describe_counter(&self, _k: metrics::KeyName, _u: Option<metrics::Unit>, _d: metrics::SharedString) {}
+        fn describe_gauge(&self, _k: metrics::KeyName, _u: Option<metrics::Unit>, _d: metrics::SharedString) {}
+        fn describe_histogram(&self, _k: metrics::KeyName, _u: Option<metrics::Unit>, _d: metrics::SharedString) {}
+        fn register_counter(&self, key: &metrics::Key) -> metrics::Counter { metrics::Counter::from_arc(std::sync::Arc::new(CaptureHandle(key.clone()))) }
+        fn register_gauge(&self, key: &metrics::Key) -> metrics::Gauge { metrics::Gauge::from_arc(std::sync::Arc::new(CaptureHandle(key.clone()))) }
+        fn register_histogram(&self, key: &metrics::Key) -> metrics::Histogram { metrics::Histogram::from_arc(std::sync::Arc::new(CaptureHandle(key.clone()))) }
+    }
+    static CAPTURE: CaptureRecorder = CaptureRecorder;
+
+    /// Installs the capture recorder (idempotent) and returns samples for `name` carrying `label`.
+    fn samples(name: &str, label: (&str, &str)) -> Vec<Sample> {
+        all_samples(name).into_iter().filter(|s| s.labels.iter().any(|(k, v)| k == label.0 && v == label.1)).collect()
+    }
+    fn all_samples(name: &str) -> Vec<Sample> {
+        let _ = metrics::set_recorder(&CAPTURE);
+        SAMPLES.lock().unwrap().iter().filter(|s| s.name == name).cloned().collect()
+    }
+
+    fn test_frame(session_id: &str) -> Frame {
+        Frame { v:1, session_id: session_id.into(), stream_id:"streamA".into(), msg_seq:1, frag_seq:0, flags: vec![], qos:"gold".into(), ttl:5, window: Window{ max_parallel:4, max_tokens:10_000, max_usd_micros:2_000_000 }, meta: Meta{ task_type:Some("ask".into()), languages:None, risk:None, data_scope:None, trace:None, tool_permissions:None, environment_id:None, security_groups:None, tenant_id:None }, payload: atp_schema::Payload{ r#type:"text".into(), content: json!({"text":"hello"}), confidence:None, cost_est:None, checksum:None, expiry_ms:None, message_digest:None }, sig:None, checksum:None }
+    }
+
+    async fn run_request(frame: Frame) -> Vec<serde_json::Value> {
+        let (reply_tx, mut reply_rx) = mpsc::channel::<String>(128);
+        process_request(WorkItem{ frame, reply_tx, slot: None, conn: None }).await;
+        let mut out = vec![];
+        while let Ok(line) = reply_rx.try_recv() { out.push(serde_json::from_str(&line).unwrap()); }
+        out
+    }
+
+    #[tokio::test]
+    async fn partial_only_adapter_contributes_synthesized_final() {
+        let _g = ENV_LOCK.lock().await;
+        let ep = spawn_mock(MockAdapter{ chunks: vec![("agent.result.partial", "the answer is"), ("agent.result.partial", "the answer is 42")], ..Default::default() }).await;
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([ep]).to_string());
+        let out = run_request(test_frame("partial-only")).await;
+        let fin = out.iter().find(|m| m["flags"] == json!(["FIN"])).expect("final frame");
+        let finals = fin["payload"]["content"]["finals"].as_array().unwrap();
+        assert_eq!(finals.len(), 1);
+        assert_eq!(finals[0], json!("\"the answer is 42\""));
+        assert!(out.iter().any(|m| m["payload"]["synthesized"] == json!(true)));
+    }
+
+    #[tokio::test]
+    async fn request_duration_recorded_on_completion() {
+        let _g = ENV_LOCK.lock().await;
+        samples("router_request_duration_ms", ("outcome", "completed"));
+        std::env::set_var("ADAPTER_ENDPOINTS", "[]");
+        let mut frame = test_frame("duration");
+        frame.qos = "duration-probe".into();
+        let out = run_request(frame).await;
+        assert!(out.iter().any(|m| m["flags"] == json!(["FIN"])));
+        let recorded = samples("router_request_duration_ms", ("qos", "duration-probe"));
+        assert_eq!(recorded.len(), 1);
+        assert!(recorded[0].labels.contains(&("outcome".into(), "completed".into())));
+        assert!(recorded[0].value >= 0.0);
+    }
+
+    #[tokio::test]
+    async fn abort_stops_request_and_releases_window() {
+        let _g = ENV_LOCK.lock().await;
+        let ep = spawn_mock(MockAdapter{ chunks: vec![("agent.result.partial", "slow"); 50], chunk_delay: Duration::from_millis(100), ..Default::default() }).await;
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([ep]).to_string());
+        let frame = test_frame("abort");
+        let (reply_tx, mut reply_rx) = mpsc::channel::<String>(128);
+        let req = tokio::spawn(process_request(WorkItem{ frame: frame.clone(), reply_tx, slot: None, conn: None }));
+        let first_partial = reply_rx.recv().await.unwrap();
+        assert!(first_partial.contains("\"ACK\""));
+        assert_eq!(GLOBAL_WINDOWS.inner.read().await.get("abort:streamA").map(|w| w.inflight), Some(1));
+        let mut abort = frame.clone();
+        abort.payload.r#type = "control.abort".into();
+        let reply = abort_stream(&abort, None);
+        assert_eq!(reply["payload"]["type"], "control.aborted");
+        assert_eq!(reply["payload"]["content"]["cancelled"], 1);
+        tokio::time::timeout(Duration::from_secs(1), req).await.expect("request stopped").unwrap();
+        assert_eq!(GLOBAL_WINDOWS.inner.read().await.get("abort:streamA").map(|w| w.inflight), Some(0));
+        while let Ok(line) = reply_rx.try_recv() { assert!(!line.contains("\"FIN\""), "aborted request must not finalize"); }
+        assert_eq!(abort_stream(&abort, None)["payload"]["content"]["cancelled"], 0);
+    }
+
+    #[tokio::test]
+    async fn abort_only_cancels_the_submitting_connections_request() {
+        let _g = ENV_LOCK.lock().await;
+        let ep = spawn_mock(MockAdapter{ chunks: vec![("agent.result.partial", "slow"); 50], chunk_delay: Duration::from_millis(100), ..Default::default() }).await;
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([ep]).to_string());
+        let (first, second) = (ConnState::uncapped(), ConnState::uncapped());
+        let mut frame = test_frame("abort-scoped");
+        frame.meta.tenant_id = Some("acme".into());
+        let (reply_tx, mut reply_rx) = mpsc::channel::<String>(128);
+        let req = tokio::spawn(process_request(WorkItem{ frame: frame.clone(), reply_tx, slot: None, conn: Some(first.id) }));
+        assert!(reply_rx.recv().await.unwrap().contains("\"ACK\""));
+        let mut abort = frame.clone();
+        abort.payload.r#type = "control.abort".into();
+        let (out_tx, mut out_rx) = mpsc::channel::<String>(8);
+        let aborted = |out_rx: &mut mpsc::Receiver<String>| serde_json::from_str::<serde_json::Value>(&out_rx.try_recv().unwrap()).unwrap()["payload"]["content"]["cancelled"].clone();
+        assert!(route_inbound(&serde_json::to_string(&abort).unwrap(), &out_tx, &second).await.is_none());
+        assert_eq!(aborted(&mut out_rx), 0, "another connection cannot abort the stream");
+        let mut other_tenant = abort.clone();
+        other_tenant.meta.tenant_id = Some("globex".into());
+        assert!(route_inbound(&serde_json::to_string(&other_tenant).unwrap(), &out_tx, &first).await.is_none());
+        assert_eq!(aborted(&mut out_rx), 0, "nor can another tenant's frame");
+        assert!(!req.is_finished());
+        assert!(route_inbound(&serde_json::to_string(&abort).unwrap(), &out_tx, &first).await.is_none());
+        assert_eq!(aborted(&mut out_rx), 1);
+        tokio::time::timeout(Duration::from_secs(1), req).await.expect("request stopped").unwrap();
+    }
+
+    #[tokio::test]
+    async fn closed_reply_channel_cancels_request() {
+        let _g = ENV_LOCK.lock().await;
+        let ep = spawn_mock(MockAdapter{ chunks: vec![("agent.result.partial", "slow"); 50], chunk_delay: Duration::from_millis(100), ..Default::default() }).await;
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([ep]).to_string());
+        std::env::set_var("ATP_DISCONNECT_GRACE_MS", "0");
+        let (reply_tx, mut reply_rx) = mpsc::channel::<String>(128);
+        let gone_before = all_samples("router_client_gone_total").len();
+        let req = tokio::spawn(process_request(WorkItem{ frame: test_frame("gone"), reply_tx, slot: None, conn: None }));
+        assert!(reply_rx.recv().await.unwrap().contains("\"ACK\""));
+        drop(reply_rx);
+        let stopped = tokio::time::timeout(Duration::from_secs(1), req).await;
+        std::env::remove_var("ATP_DISCONNECT_GRACE_MS");
+        stopped.expect("request stopped soon after the client left").unwrap();
+        assert_eq!(all_samples("router_client_gone_total").len(), gone_before + 1);
+        assert_eq!(GLOBAL_WINDOWS.inner.read().await.get("gone:streamA").map(|w| w.inflight), Some(0));
+        for _ in 0..50 { if adapters::open_streams(&ep) == 0 { break; } tokio::time::sleep(Duration::from_millis(10)).await; }
+        assert_eq!(adapters::open_streams(&ep), 0, "adapter stream left running");
+    }
+
+    #[tokio::test]
+    async fn version_reports_crate_version() {
+        let v: serde_json::Value = serde_json::from_str(&version_route().await).unwrap();
+        assert_eq!(v["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(v["lane_weights"]["gold"], 5);
+        assert!(v["adapter_endpoints"].is_u64());
+    }
+
+    #[test]
+    fn strict_qos_rejects_unknown_lane() {
+        assert!(matches!(resolve_lane("GOLD", true), Ok(Lane::Gold)));
+        let err = resolve_lane("golld", true).unwrap_err();
+        assert_eq!(err, json!({"error":"unknown_qos","value":"golld"}));
+    }
+
+    #[test]
+    fn lenient_qos_falls_back_to_bronze_and_counts() {
+        let before = all_samples("router_qos_unknown_total").len();
+        assert!(matches!(resolve_lane("golld", false), Ok(Lane::Bronze)));
+        assert!(matches!(resolve_lane("Silver", false), Ok(Lane::Silver)));
+        assert_eq!(all_samples("router_qos_unknown_total").len(), before + 1);
+    }
+
+    #[tokio::test]
+    async fn meta_adapters_override_targets_one_request() {
+        let _g = ENV_LOCK.lock().await;
+        let global = spawn_mock(MockAdapter{ chunks: vec![("agent.result.final", "global")], ..Default::default() }).await;
+        let canary = spawn_mock(MockAdapter{ chunks: vec![("agent.result.final", "canary")], ..Default::default() }).await;
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([global]).to_string());
+        std::env::set_var("ADAPTER_ALLOWLIST", json!([global, canary]).to_string());
+        let finals_of = |out: Vec<serde_json::Value>| out.into_iter().find(|m| m["flags"] == json!(["FIN"])).unwrap()["payload"]["content"]["finals"].clone();
+        let mut frame = test_frame("override");
+        frame.meta.trace = Some(json!({"adapters": [canary]}));
+        assert_eq!(finals_of(run_request(frame).await), json!(["\"canary\""]));
+        assert_eq!(finals_of(run_request(test_frame("override-default")).await), json!(["\"global\""]));
+        let mut denied = test_frame("override-denied");
+        denied.meta.trace = Some(json!({"adapters": ["http://169.254.169.254:80"]}));
+        let out = run_request(denied).await;
+        assert_eq!(out, vec![json!({"error":"adapter_not_allowed","adapter":"http://169.254.169.254:80"})]);
+        let mut padded = test_frame("override-normalized");
+        padded.meta.trace = Some(json!({"adapters": [format!(" {canary}/"), canary]}));
+        let out = run_request(padded).await;
+        assert_eq!(out.iter().filter_map(|m| m["adapter"].as_str()).collect::<std::collections::HashSet<_>>(), std::collections::HashSet::from([canary.as_str()]), "tracked under the normalized endpoint");
+        assert_eq!(finals_of(out), json!(["\"canary\""]), "duplicates after normalization fan out once");
+        let mut invalid = test_frame("override-invalid");
+        invalid.meta.trace = Some(json!({"adapters": ["ftp://adapter:7070"]}));
+        assert_eq!(run_request(invalid).await, vec![json!({"error":"invalid_adapter","adapter":"ftp://adapter:7070","reason":"unsupported scheme \"ftp\""})]);
+        std::env::remove_var("ADAPTER_ALLOWLIST");
+    }
+
+    #[test]
+    fn allowlist_patterns_match_host_classes_only() {
+        assert!(endpoint_matches("http://*.internal:7070", "http://canary-1.internal:7070"));
+        assert!(endpoint_matches("http://127.0.0.1:7070", "http://127.0.0.1:7070"));
+        assert!(endpoint_matches("http://10.0.0.*:*", "http://10.0.0.12:7070"));
+        assert!(!endpoint_matches("http://10.0.0.*", "http://10.0.0.12:7070"));
+        assert!(!endpoint_matches("http://*.internal:7070", "http://canary.internal:8080"));
+        assert!(!endpoint_matches("http://*.internal:7070", "http://169.254.169.254:80"));
+        assert!(!endpoint_matches("http://*.internal:7070", "http://evil.com/x.internal:7070"));
+        assert!(!endpoint_matches("http://*.internal:7070", "http://evil.com@a.internal:7070"));
+        assert!(!endpoint_matches("http://127.0.0.1:7070", "http://127.0.0.1:70701"));
+        assert!(!endpoint_matches("http://127.0.0.*:7070", "http://127.0.0.1.evil.test:7070"));
+        assert!(!endpoint_matches("http://10.0.0.*:*", "http://10.0.0.1.attacker.example:7070"));
+        assert!(!endpoint_matches("http://canary-*.internal:7070", "http://canary-1.evil.test.internal:7070"));
+        assert!(endpoint_matches("http://*.internal:7070", "http://a.b.internal:7070"));
+    }
+
+    #[tokio::test]
+    async fn allowlist_pattern_admits_matching_override() {
+        let _g = ENV_LOCK.lock().await;
+        let eps = use_mocks(vec![MockAdapter{ chunks: vec![("agent.result.final", "global")], ..Default::default() }]).await;
+        let canary = spawn_mock(MockAdapter{ chunks: vec![("agent.result.final", "canary")], ..Default::default() }).await;
+        let port = canary.rsplit(':').next().unwrap().to_string();
+        std::env::set_var("ADAPTER_ALLOWLIST", json!([format!("http://127.0.0.*:{}", port)]).to_string());
+        let mut frame = test_frame("pattern-allowed");
+        frame.meta.trace = Some(json!({"adapters": [canary]}));
+        let out = run_request(frame).await;
+        let mut denied = test_frame("pattern-denied");
+        denied.meta.trace = Some(json!({"adapters": [eps[0]]}));
+        let denied = run_request(denied).await;
+        std::env::remove_var("ADAPTER_ALLOWLIST");
+        assert_eq!(out.iter().find(|m| m["flags"] == json!(["FIN"])).unwrap()["payload"]["content"]["finals"], json!(["\"canary\""]));
+        assert_eq!(denied, vec![json!({"error":"adapter_not_allowed","adapter":eps[0]})]);
+    }
+
+    #[tokio::test]
+    async fn busy_payload_reports_saturated_dimension() {
+        let table = WindowTable::default();
+        let w = Window{ max_parallel: 2, max_tokens: 100, max_usd_micros: 1_000 };
+        table.admit("k", &w, 60, 10).await.unwrap();
+        let util = table.admit("k", &w, 60, 10).await.unwrap_err();
+        let busy = busy_payload(&util);
+        assert_eq!(busy["control.status"], "BUSY");
+        assert_eq!(busy["saturated"], "tokens");
+        assert_eq!((busy["tokens_used"].as_u64(), busy["max_tokens"].as_u64()), (Some(60), Some(100)));
+        assert_eq!((busy["inflight"].as_u64(), busy["max_parallel"].as_u64()), (Some(1), Some(2)));
+        assert_eq!((busy["usd_used"].as_u64(), busy["max_usd"].as_u64()), (Some(10), Some(1_000)));
+        table.admit("k", &w, 10, 10).await.unwrap();
+        assert_eq!(table.admit("k", &w, 0, 0).await.unwrap_err().saturated, "parallel");
+    }
+
+    #[tokio::test]
+    async fn estimate_only_returns_costs_without_admission() {
+        let _g = ENV_LOCK.lock().await;
+        let a = spawn_mock(MockAdapter{ estimate: EstimateResponse{ in_tokens: 10, out_tokens: 30, usd_micros: 500, ..Default::default() }, ..Default::default() }).await;
+        let b = spawn_mock(MockAdapter{ estimate: EstimateResponse{ in_tokens: 5, out_tokens: 5, usd_micros: 100, ..Default::default() }, ..Default::default() }).await;
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([a, b]).to_string());
+        let mut frame = test_frame("estimate-only");
+        frame.flags = vec!["ESTIMATE_ONLY".into()];
+        let out = run_request(frame).await;
+        assert_eq!(out.len(), 1);
+        let content = &out[0]["payload"]["content"];
+        assert_eq!(out[0]["payload"]["type"], "agent.estimate");
+        assert_eq!((content["tokens"].as_u64(), content["usd_micros"].as_u64()), (Some(50), Some(600)));
+        assert_eq!(content["adapters"][a.as_str()], json!({"tokens": 40, "usd_micros": 500}));
+        assert_eq!(content["adapters"][b.as_str()], json!({"tokens": 10, "usd_micros": 100}));
+        assert!(GLOBAL_WINDOWS.inner.read().await.get("estimate-only:streamA").is_none());
+    }
+
+    #[tokio::test]
+    async fn failed_estimates_fall_back_to_conservative_cost() {
+        let _g = ENV_LOCK.lock().await;
+        std::env::set_var("ATP_FALLBACK_EST_TOKENS", "700");
+        std::env::set_var("ATP_FALLBACK_EST_USD_MICROS", "9000");
+        use_mocks(vec![MockAdapter{ estimate_error: Some("down"), ..Default::default() }, MockAdapter{ estimate_error: Some("down"), ..Default::default() }]).await;
+        let mut frame = test_frame("estimate-fallback");
+        frame.flags = vec!["ESTIMATE_ONLY".into()];
+        let out = run_request(frame).await;
+        let content = &out[0]["payload"]["content"];
+        assert_eq!((content["tokens"].as_u64(), content["usd_micros"].as_u64(), content["fallback"].as_bool()), (Some(700), Some(9000), Some(true)));
+        let mut frame = test_frame("estimate-fallback");
+        frame.window.max_tokens = 500;
+        let out = run_request(frame).await;
+        std::env::remove_var("ATP_FALLBACK_EST_TOKENS");
+        std::env::remove_var("ATP_FALLBACK_EST_USD_MICROS");
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0]["control.status"], "BUSY");
+        assert_eq!(total_cost(&["a".into()], &HashMap::from([("a".to_string(), (0, 0))])), Some((0, 0)));
+        assert_eq!(total_cost(&[], &HashMap::new()), Some((0, 0)));
+    }
+
+    #[tokio::test]
+    async fn per_lane_windows_isolate_bronze_from_gold() {
+        let table = WindowTable::default();
+        let w = Window{ max_parallel: 1, max_tokens: 100, max_usd_micros: 100 };
+        let mut bronze = test_frame("lanes");
+        bronze.qos = "bronze".into();
+        let gold = test_frame("lanes");
+        assert_eq!(window_key(&bronze, false), window_key(&gold, false));
+        table.admit(&window_key(&bronze, true), &w, 10, 10).await.unwrap();
+        assert!(table.admit(&window_key(&bronze, true), &w, 10, 10).await.is_err());
+        assert!(table.admit(&window_key(&gold, true), &w, 10, 10).await.is_ok());
+        assert_eq!(window_key(&gold, true), "lanes:streamA:gold");
+    }
+
+    #[tokio::test]
+    async fn true_up_frees_overestimated_budget() {
+        let table = WindowTable::default();
+        let w = Window{ max_parallel: 4, max_tokens: 100, max_usd_micros: 1_000 };
+        table.admit("trueup", &w, 80, 500).await.unwrap();
+        assert_eq!(table.admit("trueup", &w, 50, 100).await.unwrap_err().saturated, "tokens");
+        table.true_up("trueup", (80, 500), (20, 200)).await;
+        table.admit("trueup", &w, 50, 100).await.unwrap();
+        assert_eq!(table.inner.read().await["trueup"].tokens, 70);
+        table.true_up("trueup", (50, 100), (90, 100)).await;
+        assert_eq!(table.inner.read().await["trueup"].tokens, 110);
+        assert!(table.admit("trueup", &w, 1, 0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn replay_feeds_recorded_frames_through_scheduler() {
+        let _g = ENV_LOCK.lock().await;
+        let ep = spawn_mock(MockAdapter{ chunks: vec![("agent.result.final", "replayed")], ..Default::default() }).await;
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([ep]).to_string());
+        let mut first = serde_json::to_value(test_frame("replay")).unwrap();
+        first["ts_ms"] = json!(1000);
+        let mut second = serde_json::to_value(test_frame("replay-expired")).unwrap();
+        second["ttl"] = json!(0);
+        second["ts_ms"] = json!(1020);
+        let path = std::env::temp_dir().join(format!("atp-replay-{}.ndjson", std::process::id()));
+        std::fs::write(&path, format!("{}\n{}\n", first, second)).unwrap();
+        let (buf, n) = replay::run(&path, Vec::new(), true).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let replies: Vec<serde_json::Value> = String::from_utf8(buf).unwrap().lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(replies.len(), n);
+        assert!(replies.contains(&json!({"error":"ttl_expired"})));
+        let fin = replies.iter().find(|m| m["flags"] == json!(["FIN"])).expect("final frame");
+        assert_eq!(fin["session_id"], "replay");
+        assert_eq!(fin["payload"]["content"]["finals"], json!(["\"replayed\""]));
+    }
+
+    #[tokio::test]
+    async fn scheduler_concurrency_never_exceeds_max_inflight() {
+        use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+        let (running, peak, done) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let (r, p, d) = (running.clone(), peak.clone(), done.clone());
+        let sched = Scheduler::spawn(3, move |_item| {
+            let (r, p, d) = (r.clone(), p.clone(), d.clone());
+            async move {
+                let now = r.fetch_add(1, Ordering::SeqCst) + 1;
+                p.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                r.fetch_sub(1, Ordering::SeqCst);
+                d.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        let (reply_tx, _reply_rx) = mpsc::channel::<String>(1);
+        // Two full weighted rotations, so every lane the loop visits has work queued.
+        let mut total = 0;
+        for (lane, w) in LANE_WEIGHTS.iter() {
+            let tx = match lane { Lane::Gold => &sched.gold, Lane::Silver => &sched.silver, Lane::Bronze => &sched.bronze };
+            for i in 0..w * 2 { tx.send(WorkItem{ frame: test_frame(&format!("sem-{}-{i}", lane.as_str())), reply_tx: reply_tx.clone(), slot: None, conn: None }).await.unwrap(); total += 1; }
+        }
+        tokio::time::timeout(Duration::from_secs(5), async { while done.load(Ordering::SeqCst) < total { tokio::time::sleep(Duration::from_millis(5)).await; } }).await.expect("all items processed");
+        assert!(peak.load(Ordering::SeqCst) <= 3, "peak {}", peak.load(Ordering::SeqCst));
+        assert_eq!(peak.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn builder_serves_healthz_in_process() {
+        use tower::ServiceExt;
+        let app = RouterBuilder::new().build();
+        let resp = app.oneshot(axum::http::Request::builder().uri("/healthz").body(axum::body::Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(resp.status(), axum::http::StatusCode::OK);
+        let body = axum::body::to_bytes(resp.into_body(), 1024).await.unwrap();
+        assert_eq!(&body[..], b"ok");
+    }
+
+    #[tokio::test]
+    async fn metrics_use_openmetrics_only_when_accepted() {
+        use tower::ServiceExt;
+        let _tracer = otlp_tracer("http://127.0.0.1:4317").unwrap();
+        let scrape = |accept: Option<&'static str>| async move {
+            let mut req = axum::http::Request::builder().uri("/metrics");
+            if let Some(a) = accept { req = req.header(axum::http::header::ACCEPT, a); }
+            let resp = RouterBuilder::new().build().oneshot(req.body(axum::body::Body::empty()).unwrap()).await.unwrap();
+            let ct = resp.headers()[axum::http::header::CONTENT_TYPE].to_str().unwrap().to_string();
+            (ct, String::from_utf8(axum::body::to_bytes(resp.into_body(), 1 << 22).await.unwrap().to_vec()).unwrap())
+        };
+        let (ct, body) = scrape(None).await;
+        assert!(ct.starts_with("text/plain") && !body.contains("# EOF"), "{ct}");
+        let (ct, body) = scrape(Some("application/openmetrics-text;version=1.0.0")).await;
+        assert!(ct.starts_with("application/openmetrics-text") && body.ends_with("# EOF\n"), "{ct}");
+    }
+
+    #[tokio::test]
+    async fn consensus_route_groups_posted_answers() {
+        use tower::ServiceExt;
+        let post = |body: serde_json::Value| axum::http::Request::builder().method("POST").uri("/consensus").header("content-type", "application/json").body(axum::body::Body::from(body.to_string())).unwrap();
+        let resp = RouterBuilder::new().build().oneshot(post(json!({"finals": ["the answer is 42", "The answer is 42!", "paris is in france"], "threshold": 0.85}))).await.unwrap();
+        assert_eq!(resp.status(), axum::http::StatusCode::OK);
+        let v: serde_json::Value = serde_json::from_slice(&axum::body::to_bytes(resp.into_body(), 1 << 16).await.unwrap()).unwrap();
+        assert_eq!(v["groups"], json!([[0, 1], [2]]));
+        assert_eq!(v["ranked"][0]["group_size"], 2);
+        for bad in [json!({"finals": []}), json!({"finals": ["a"], "metric": "euclid"}), json!({"answers": ["a"]})] {
+            let resp = RouterBuilder::new().build().oneshot(post(bad)).await.unwrap();
+            assert_eq!(resp.status(), axum::http::StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn drained_adapter_finishes_open_stream_but_gets_no_new_ones() {
+        use tower::ServiceExt;
+        let _g = ENV_LOCK.lock().await;
+        let (slow_calls, fast_calls) = (std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0)), std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0)));
+        let eps = use_mocks(vec![
+            MockAdapter{ chunks: vec![("agent.result.partial", "thinking"), ("agent.result.final", "slow answer")], chunk_delay: Duration::from_millis(150), streams: slow_calls.clone(), ..Default::default() },
+            MockAdapter{ chunks: vec![("agent.result.final", "fast answer")], streams: fast_calls.clone(), ..Default::default() },
+        ]).await;
+        let in_flight = tokio::spawn(run_request(test_frame("drain-open")));
+        tokio::time::timeout(Duration::from_secs(5), async { while adapters::open_streams(&eps[0]) == 0 { tokio::time::sleep(Duration::from_millis(5)).await; } }).await.expect("stream opened");
+        std::env::set_var("ATP_ADMIN_TOKEN", "s3cret");
+        let drain = |token: &str| axum::http::Request::builder().method("POST").uri("/admin/adapters/drain").header("content-type", "application/json").header("authorization", format!("Bearer {token}")).body(axum::body::Body::from(json!({"endpoint": eps[0]}).to_string())).unwrap();
+        assert_eq!(RouterBuilder::new().build().oneshot(drain("wrong")).await.unwrap().status(), axum::http::StatusCode::UNAUTHORIZED);
+        let resp = RouterBuilder::new().build().oneshot(drain("s3cret")).await.unwrap();
+        std::env::remove_var("ATP_ADMIN_TOKEN");
+        assert_eq!(resp.status(), axum::http::StatusCode::OK);
+        let health: serde_json::Value = serde_json::from_str(&adapters_health().await).unwrap();
+        assert_eq!((health[0]["draining"].clone(), health[0]["open_streams"].clone(), health[1]["draining"].clone()), (json!(true), json!(1), json!(false)));
+        let out = run_request(test_frame("drain-new")).await;
+        assert_eq!(out.last().unwrap()["payload"]["content"]["finals"], json!(["\"fast answer\""]));
+        let open = in_flight.await.unwrap();
+        adapters::set_draining(&eps[0], false);
+        assert!(open.last().unwrap()["payload"]["content"]["finals"].as_array().unwrap().contains(&json!("\"slow answer\"")));
+        assert_eq!((slow_calls.load(std::sync::atomic::Ordering::SeqCst), fast_calls.load(std::sync::atomic::Ordering::SeqCst)), (1, 2));
+        assert_eq!(adapters::open_streams(&eps[0]), 0);
+    }
+
+    #[test]
+    fn msg_seq_regression_rejected_in_strict_mode() {
+        let tracker = SeqTracker::new(Duration::from_secs(600));
+        let mut frame = test_frame("seq");
+        frame.msg_seq = 5;
+        assert!(check_msg_seq(&tracker, &frame, true).is_ok());
+        frame.msg_seq = 7;
+        assert!(check_msg_seq(&tracker, &frame, true).is_ok());
+        assert!(check_msg_seq(&tracker, &frame, true).is_ok(), "fragments of one message share msg_seq");
+        frame.msg_seq = 3;
+        assert_eq!(check_msg_seq(&tracker, &frame, true).unwrap_err(), json!({"error":"msg_seq_regression","last":7}));
+        assert!(check_msg_seq(&tracker, &frame, false).is_ok());
+        frame.stream_id = "other".into();
+        assert!(check_msg_seq(&tracker, &frame, true).is_ok());
+    }
+
+    #[test]
+    fn unknown_flags_rejected_in_strict_mode_and_counted_otherwise() {
+        let mut frame = test_frame("flags");
+        frame.flags = vec!["VERBOSE".into(), "NO_CONSENSUS".into(), "VERBOSE".into()];
+        assert!(normalize_flags(&mut frame, true).is_ok());
+        assert_eq!(frame.flags, ["NO_CONSENSUS", "VERBOSE"]);
+        frame.flags.push("PRIORITY".into());
+        assert_eq!(normalize_flags(&mut frame, true).unwrap_err(), json!({"error":"unknown_flag","flag":"PRIORITY"}));
+        let before = all_samples("router_unknown_flags_total").len();
+        assert!(normalize_flags(&mut frame, false).is_ok());
+        assert_eq!(frame.flags, ["NO_CONSENSUS", "PRIORITY", "VERBOSE"]);
+        assert_eq!(all_samples("router_unknown_flags_total").len(), before + 1);
+    }
+
+    #[test]
+    fn msg_seq_tracker_evicts_idle_streams() {
+        let tracker = SeqTracker::new(Duration::ZERO);
+        assert!(tracker.observe("a:b", 9).is_ok());
+        assert!(tracker.observe("a:b", 1).is_ok());
+        assert_eq!(tracker.inner.lock().unwrap().0.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn final_frame_merges_findings_across_adapters() {
+        let _g = ENV_LOCK.lock().await;
+        let a = spawn_mock(MockAdapter{ chunks: vec![("agent.result.final", r#"{"findings":[{"id":"f1","claim":"sql injection","confidence":0.6,"provenance":["a"]},{"id":"f2","claim":"weak hash"}]}"#)], ..Default::default() }).await;
+        let b = spawn_mock(MockAdapter{ chunks: vec![("agent.result.final", r#"{"findings":[{"id":"f1","claim":"sql injection","confidence":0.8,"provenance":["b"]}]}"#)], ..Default::default() }).await;
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([a, b]).to_string());
+        let out = run_request(test_frame("findings")).await;
+        let fin = out.iter().find(|m| m["flags"] == json!(["FIN"])).expect("final frame");
+        let findings = fin["payload"]["content"]["findings"].as_array().unwrap();
+        assert_eq!(findings.len(), 2);
+        let f1 = findings.iter().find(|f| f["id"] == "f1").unwrap();
+        assert!((f1["confidence"].as_f64().unwrap() - 0.8).abs() < 1e-6);
+        let mut prov: Vec<&str> = f1["provenance"].as_array().unwrap().iter().map(|p| p.as_str().unwrap()).collect();
+        prov.sort();
+        assert_eq!(prov, ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn oversized_final_is_fragmented() {
+        let _g = ENV_LOCK.lock().await;
+        let big: &'static str = Box::leak("x".repeat(DEFAULT_MAX_FRAGMENT_BYTES + 100).into_boxed_str());
+        let ep = spawn_mock(MockAdapter{ chunks: vec![("agent.result.final", big)], ..Default::default() }).await;
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([ep]).to_string());
+        let out = run_request(test_frame("fragmented")).await;
+        let fin_idx = out.iter().position(|m| m["flags"] == json!(["FIN"])).expect("final frame");
+        let frames: Vec<Frame> = out[..=fin_idx].iter().rev().take_while(|m| m["msg_seq"] == 3).map(|m| serde_json::from_value(m.clone()).unwrap()).collect::<Vec<_>>().into_iter().rev().collect();
+        assert!(frames.len() > 1);
+        assert!(frames[..frames.len() - 1].iter().all(|f| f.flags == ["MORE"]));
+        let text = atp_schema::reassemble_text(&frames).expect("reassembled");
+        let content: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(content["finals"][0].as_str().unwrap().len(), big.len() + 2);
+        assert!(out.iter().filter(|m| m["adapter"] == json!(ep)).count() > 1, "adapter final is fragmented too");
+    }
+
+    #[tokio::test]
+    async fn resume_token_replays_missed_frames() {
+        let _g = ENV_LOCK.lock().await;
+        let streams = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let ep = spawn_mock(MockAdapter{ chunks: vec![("agent.result.partial", "draft"), ("agent.result.final", "resumed answer")], chunk_delay: Duration::from_millis(20), streams: streams.clone(), ..Default::default() }).await;
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([ep]).to_string());
+        let (reply_tx, mut reply_rx) = mpsc::channel::<String>(128);
+        let req = tokio::spawn(process_request(WorkItem{ frame: test_frame("resume"), reply_tx, slot: None, conn: None }));
+        let ack: serde_json::Value = serde_json::from_str(&reply_rx.recv().await.unwrap()).unwrap();
+        let token = ack["payload"]["content"]["resume_token"].as_str().expect("resume token").to_string();
+        drop(reply_rx);
+        req.await.unwrap();
+
+        let (out_tx, mut out_rx) = mpsc::channel::<String>(128);
+        let mut resume = test_frame("resume");
+        resume.msg_seq = 2;
+        resume.payload.r#type = "control.resume".into();
+        resume.payload.content = json!({"resume_token": token, "last_msg_seq": 1});
+        ingest_text(&serde_json::to_string(&resume).unwrap(), &out_tx, &ConnState::uncapped()).await;
+        let fin = loop {
+            let m: serde_json::Value = serde_json::from_str(&tokio::time::timeout(Duration::from_secs(2), out_rx.recv()).await.unwrap().unwrap()).unwrap();
+            assert!(m["msg_seq"].as_u64().unwrap() > 1);
+            if m["flags"] == json!(["FIN"]) { break m; }
+        };
+        assert_eq!(fin["payload"]["content"]["finals"], json!(["\"resumed answer\""]));
+        assert_eq!(streams.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        resume.payload.content = json!({"resume_token": "nope"});
+        ingest_text(&serde_json::to_string(&resume).unwrap(), &out_tx, &ConnState::uncapped()).await;
+        assert_eq!(out_rx.recv().await.unwrap(), json!({"error":"resume_unknown_token"}).to_string());
+    }
+
+    #[tokio::test]
+    async fn ws_connection_gauge_returns_to_zero() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let _g = ENV_LOCK.lock().await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, RouterBuilder::new().build()).await });
+        all_samples("router_ws_connections_active");
+        let active = || WS_CONNECTIONS.load(std::sync::atomic::Ordering::SeqCst);
+        let wait_for = |n: i64| async move { for _ in 0..200 { if active() == n { return; } tokio::time::sleep(Duration::from_millis(5)).await; } panic!("gauge stuck at {}, want {}", active(), n); };
+        let mut sock = tokio::net::TcpStream::connect(addr).await.unwrap();
+        sock.write_all(format!("GET /ws HTTP/1.1\r\nHost: {addr}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").as_bytes()).await.unwrap();
+        let mut buf = [0u8; 256];
+        let n = sock.read(&mut buf).await.unwrap();
+        assert!(String::from_utf8_lossy(&buf[..n]).starts_with("HTTP/1.1 101"));
+        wait_for(1).await;
+        assert_eq!(all_samples("router_ws_connections_active").last().map(|s| s.value), Some(1.0));
+        drop(sock);
+        wait_for(0).await;
+        assert_eq!(all_samples("router_ws_connections_active").last().map(|s| s.value), Some(0.0));
+        let panicked = tokio::spawn(async { let _g = WsConnectionGuard::new(); panic!("handler panic") }).await;
+        assert!(panicked.is_err());
+        assert_eq!(active(), 0);
+    }
+
+    #[tokio::test]
+    async fn ws_upgrade_declines_permessage_deflate_unless_enabled() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let _g = ENV_LOCK.lock().await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, RouterBuilder::new().build()).await });
+        let mut sock = tokio::net::TcpStream::connect(addr).await.unwrap();
+        sock.write_all(format!("GET /ws HTTP/1.1\r\nHost: {addr}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\r\n").as_bytes()).await.unwrap();
+        let mut buf = [0u8; 512];
+        let n = sock.read(&mut buf).await.unwrap();
+        let head = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+        assert!(head.starts_with("http/1.1 101"), "{}", head);
+        assert!(!head.contains("sec-websocket-extensions"), "{}", head);
+        // Uncompressed frames still flow: a masked binary frame (zero key) gets the usual text error back.
+        sock.write_all(&[0x82, 0x81, 0, 0, 0, 0, b'x']).await.unwrap();
+        let n = sock.read(&mut buf).await.unwrap();
+        assert_eq!((buf[0], &buf[2..n]), (0x81, br#"{"error":"binary_not_supported"}"#.as_slice()));
+    }
+
+    #[tokio::test]
+    async fn ws_deflate_is_negotiated_and_frames_round_trip() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+        let _g = ENV_LOCK.lock().await;
+        std::env::set_var("ATP_WS_DEFLATE", "1");
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, RouterBuilder::new().build()).await });
+        let mut sock = tokio::net::TcpStream::connect(addr).await.unwrap();
+        sock.write_all(format!("GET /ws HTTP/1.1\r\nHost: {addr}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\r\n").as_bytes()).await.unwrap();
+        let mut buf = [0u8; 512];
+        let n = sock.read(&mut buf).await.unwrap();
+        let head = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+        assert!(head.starts_with("http/1.1 101") && head.contains("sec-websocket-extensions: permessage-deflate"), "{head}");
+
+        let host = addr.to_string();
+        let mut client = soketto::handshake::Client::new(tokio::net::TcpStream::connect(addr).await.unwrap().compat(), &host, "/ws");
+        client.add_extension(Box::new(soketto::extension::deflate::Deflate::new(soketto::Mode::Client)));
+        assert!(matches!(client.handshake().await.unwrap(), soketto::handshake::ServerResponse::Accepted { .. }));
+        let extensions: Vec<_> = client.drain_extensions().collect();
+        assert!(extensions.iter().any(|e| e.name() == "permessage-deflate" && e.is_enabled()));
+        let mut builder = client.into_builder();
+        builder.add_extensions(extensions);
+        let (mut tx, mut rx) = builder.finish();
+        for _ in 0..2 {
+            tx.send_text("not a frame ".repeat(64)).await.unwrap();
+            tx.flush().await.unwrap();
+            let mut reply = vec![];
+            rx.receive_data(&mut reply).await.unwrap();
+            assert_eq!(serde_json::from_slice::<serde_json::Value>(&reply).unwrap(), json!({"error":"invalid_frame"}));
+        }
+        std::env::remove_var("ATP_WS_DEFLATE");
+    }
+
+    #[tokio::test]
+    async fn ws_answers_pings_and_closes_idle_connections() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let _g = ENV_LOCK.lock().await;
+        std::env::set_var("ATP_WS_PING_MS", "60000");
+        std::env::set_var("ATP_WS_IDLE_TIMEOUT_MS", "300");
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, RouterBuilder::new().build()).await });
+        let mut sock = tokio::net::TcpStream::connect(addr).await.unwrap();
+        sock.write_all(format!("GET /ws HTTP/1.1\r\nHost: {addr}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").as_bytes()).await.unwrap();
+        let mut buf = [0u8; 256];
+        let n = sock.read(&mut buf).await.unwrap();
+        assert!(String::from_utf8_lossy(&buf[..n]).starts_with("HTTP/1.1 101"));
+        let opened = Instant::now();
+        // Masked client ping with an all-zero key, payload "hi".
+        sock.write_all(&[0x89, 0x82, 0, 0, 0, 0, b'h', b'i']).await.unwrap();
+        let n = sock.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], &[0x8A, 0x02, b'h', b'i']);
+        let mut rest = vec![];
+        tokio::time::timeout(Duration::from_secs(2), sock.read_to_end(&mut rest)).await.expect("idle connection closed").unwrap();
+        std::env::remove_var("ATP_WS_PING_MS");
+        std::env::remove_var("ATP_WS_IDLE_TIMEOUT_MS");
+        assert!(opened.elapsed() >= Duration::from_millis(300));
+        assert_eq!(rest[0], 0x88, "expected a close frame, got {:?}", rest);
+        assert_eq!(u16::from_be_bytes([rest[2], rest[3]]), 1001);
+    }
+
+    #[tokio::test]
+    async fn consensus_confidence_lands_in_histogram_per_phase() {
+        let _g = ENV_LOCK.lock().await;
+        use_mocks((0..2).map(|_| MockAdapter{ chunks: vec![("agent.result.final", "same answer")], ..Default::default() }).collect()).await;
+        let phase_samples = |phase| samples("router_consensus_confidence_hist", ("phase", phase)).len();
+        let count = |phase: &str| CONFIDENCE.summary()[phase]["count"].as_u64().unwrap();
+        let before = (phase_samples("provisional"), phase_samples("final"), count("provisional"), count("final"));
+        let out = run_request(test_frame("confidence")).await;
+        assert!(out.iter().any(|m| m["payload"]["type"] == "agent.result.provisional"));
+        assert_eq!((phase_samples("provisional"), phase_samples("final")), (before.0 + 1, before.1 + 1));
+        let summary: serde_json::Value = serde_json::from_slice(&axum::body::to_bytes(confidence_route().await.into_body(), 1 << 16).await.unwrap()).unwrap();
+        assert!(count("provisional") > before.2 && count("final") > before.3);
+        let last_final = samples("router_consensus_confidence_hist", ("phase", "final")).last().unwrap().value;
+        assert!(summary["final"]["min"].as_f64().unwrap() <= last_final && last_final <= summary["final"]["max"].as_f64().unwrap());
+        let log = ConfidenceLog { cap: 4, inner: Default::default() };
+        for c in [0.9, 0.1, 0.5, 0.7, 0.3] { log.push("final", c); }
+        assert_eq!(log.summary()["final"], json!({"count": 4, "mean": 0.4, "min": 0.1, "p10": 0.1, "p50": 0.3, "p90": 0.7, "max": 0.7}));
+        assert_eq!(log.summary()["provisional"], json!({"count": 0}));
+    }
+
+    #[tokio::test]
+    async fn compressed_requests_are_decoded_and_replied_to_compressed() {
+        let _g = ENV_LOCK.lock().await;
+        use_mocks(vec![MockAdapter{ chunks: vec![("agent.result.final", "squeezed")], ..Default::default() }]).await;
+        let (out_tx, mut out_rx) = mpsc::channel::<String>(16);
+        let conn = ConnState::uncapped();
+        let mut frame = test_frame("zstd");
+        frame.flags = vec!["COMPRESSED".into()];
+        frame.payload.content = compression::CODEC.compress(&json!({"text": "hello"})).unwrap();
+        let (item, _) = route_inbound(&serde_json::to_string(&frame).unwrap(), &out_tx, &conn).await.unwrap();
+        assert_eq!(item.frame.payload.content, json!({"text": "hello"}));
+        let out = run_request(item.frame).await;
+        let fin = out.iter().find(|m| m["flags"] == json!(["COMPRESSED", "FIN"])).expect("compressed final frame");
+        assert_eq!(compression::CODEC.decompress(&fin["payload"]["content"]).unwrap()["finals"], json!(["\"squeezed\""]));
+        frame.payload.content = json!({"zstd": "AAAA"});
+        assert!(route_inbound(&serde_json::to_string(&frame).unwrap(), &out_tx, &conn).await.is_none());
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&out_rx.recv().await.unwrap()).unwrap()["error"], "decompress_failed");
+    }
+
+    #[tokio::test]
+    async fn observer_receives_frames_emitted_to_primary_client() {
+        let _g = ENV_LOCK.lock().await;
+        use_mocks(vec![MockAdapter{ chunks: vec![("agent.result.partial", "draft"), ("agent.result.final", "observed")], ..Default::default() }]).await;
+        let mut observer = OBSERVERS.subscribe("observed-session");
+        let mut other = OBSERVERS.subscribe("someone-else");
+        let primary = run_request(test_frame("observed-session")).await;
+        let mut seen = vec![];
+        while let Ok(text) = observer.try_recv() { seen.push(serde_json::from_str::<serde_json::Value>(&text).unwrap()); }
+        assert!(seen.iter().any(|m| m["flags"] == json!(["FIN"])));
+        assert_eq!(seen, primary.into_iter().filter(|m| m.get("session_id").is_some()).collect::<Vec<_>>());
+        assert!(other.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn connection_stream_cap_refuses_streams_until_one_completes() {
+        let _g = ENV_LOCK.lock().await;
+        use_mocks(vec![MockAdapter{ chunks: vec![("agent.result.partial", "working"), ("agent.result.final", "done")], chunk_delay: Duration::from_millis(100), ..Default::default() }]).await;
+        let streams = ConnStreams::new(2);
+        let (out_tx, mut out_rx) = mpsc::channel::<String>(256);
+        let conn = ConnState::uncapped();
+        let open = |stream_id: String| { let (out_tx, streams, conn) = (out_tx.clone(), &streams, &conn); async move {
+            let mut frame = test_frame("conn-cap");
+            frame.stream_id = stream_id;
+            let (mut item, _) = route_inbound(&serde_json::to_string(&frame).unwrap(), &out_tx, conn).await.unwrap();
+            streams.admit(&mut item).map(|_| tokio::spawn(process_request(item)))
+        } };
+        let first = open("s0".into()).await.unwrap();
+        let second = open("s1".into()).await.unwrap();
+        let before = all_samples("router_conn_stream_limit_total").len();
+        for i in 2..4 { assert_eq!(open(format!("s{i}")).await.unwrap_err(), json!({"control.status":"CONN_STREAM_LIMIT","stream_id":format!("s{i}"),"max_streams":2})); }
+        assert_eq!(all_samples("router_conn_stream_limit_total").len(), before + 2);
+        first.await.unwrap();
+        let third = open("s4".into()).await.expect("slot freed by a completed stream");
+        second.await.unwrap();
+        third.await.unwrap();
+        let mut finished = std::collections::HashSet::new();
+        while let Ok(line) = out_rx.try_recv() { let m: serde_json::Value = serde_json::from_str(&line).unwrap(); if m["flags"] == json!(["FIN"]) { finished.insert(m["stream_id"].as_str().unwrap().to_string()); } }
+        assert_eq!(finished, std::collections::HashSet::from(["s0", "s1", "s4"].map(String::from)));
+        assert_eq!(streams.slots.available_permits(), 2);
+    }
+
+    #[tokio::test]
+    async fn observe_endpoint_is_gated_and_read_only() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let _g = ENV_LOCK.lock().await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, RouterBuilder::new().build()).await });
+        let upgrade = |path: &str, auth: &str| format!("GET {path} HTTP/1.1\r\nHost: {addr}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n{auth}\r\n");
+        let mut buf = [0u8; 256];
+        let status = |req: String| async move {
+            let mut sock = tokio::net::TcpStream::connect(addr).await.unwrap();
+            sock.write_all(req.as_bytes()).await.unwrap();
+            let mut buf = [0u8; 256];
+            let n = sock.read(&mut buf).await.unwrap();
+            (String::from_utf8_lossy(&buf[..n])[..12].to_string(), sock)
+        };
+        std::env::remove_var("ATP_WS_OBSERVE");
+        std::env::set_var("ATP_ADMIN_TOKEN", "observer-secret");
+        assert_eq!(status(upgrade("/ws/observe?session_id=s1", "Authorization: Bearer observer-secret\r\n")).await.0, "HTTP/1.1 404", "disabled");
+        std::env::set_var("ATP_WS_OBSERVE", "true");
+        assert_eq!(status(upgrade("/ws/observe?session_id=s1", "")).await.0, "HTTP/1.1 401", "no token");
+        assert_eq!(status(upgrade("/ws/observe?session_id=s1", "Authorization: Bearer guess\r\n")).await.0, "HTTP/1.1 401", "wrong token");
+        std::env::remove_var("ATP_ADMIN_TOKEN");
+        assert_eq!(status(upgrade("/ws/observe?session_id=s1", "Authorization: Bearer observer-secret\r\n")).await.0, "HTTP/1.1 404", "no admin token configured");
+        std::env::set_var("ATP_ADMIN_TOKEN", "observer-secret");
+        let (head, mut sock) = status(upgrade("/ws/observe?session_id=s1", "Authorization: Bearer observer-secret\r\n")).await;
+        std::env::remove_var("ATP_WS_OBSERVE");
+        std::env::remove_var("ATP_ADMIN_TOKEN");
+        assert_eq!(head, "HTTP/1.1 101");
+        // Masked text frame (zero key) "{}": observers may not send frames.
+        sock.write_all(&[0x81, 0x82, 0, 0, 0, 0, b'{', b'}']).await.unwrap();
+        let n = sock.read(&mut buf).await.unwrap();
+        assert_eq!((buf[0], &buf[2..n]), (0x81, br#"{"error":"read_only"}"#.as_slice()));
+        OBSERVERS.publish("s1", r#"{"session_id":"s1"}"#);
+        let n = sock.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[2..n], br#"{"session_id":"s1"}"#.as_slice());
+    }
+
+    #[test]
+    fn merge_patch_follows_rfc7386() {
+        let mut doc = json!({"a": "b", "c": {"d": "e", "f": "g"}});
+        merge_patch(&mut doc, &json!({"a": "z", "c": {"f": null}}));
+        assert_eq!(doc, json!({"a": "z", "c": {"d": "e"}}));
+        merge_patch(&mut doc, &json!(["x"]));
+        assert_eq!(doc, json!(["x"]));
+    }
+
+    #[tokio::test]
+    async fn delta_chunks_are_forwarded_as_merged_snapshots() {
+        let _g = ENV_LOCK.lock().await;
+        let ep = spawn_mock(MockAdapter{ chunks: vec![("agent.result.partial", r#"{"title":"draft","body":{"intro":"hi","tags":["a"]}}"#), ("agent.result.final", r#"{"title":"done","body":{"tags":null,"outro":"bye"}}"#)], flags: vec!["DELTA"], ..Default::default() }).await;
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([ep]).to_string());
+        let out = run_request(test_frame("delta")).await;
+        let forwarded: Vec<serde_json::Value> = out.iter().filter(|m| m["adapter"] == json!(ep)).map(|m| serde_json::from_str(m["payload"]["content"].as_str().unwrap()).unwrap()).collect();
+        assert_eq!(forwarded, vec![json!({"title":"draft","body":{"intro":"hi","tags":["a"]}}), json!({"title":"done","body":{"intro":"hi","outro":"bye"}})]);
+        let fin = out.iter().find(|m| m["flags"] == json!(["FIN"])).expect("final frame");
+        let final_text: String = serde_json::from_str(fin["payload"]["content"]["finals"][0].as_str().unwrap()).unwrap();
+        let merged: serde_json::Value = serde_json::from_str(&final_text).unwrap();
+        assert_eq!(merged, json!({"title":"done","body":{"intro":"hi","outro":"bye"}}));
+    }
+
+    #[tokio::test]
+    async fn downgrade_past_margin_emits_control_frame() {
+        let _g = ENV_LOCK.lock().await;
+        let fast = || MockAdapter{ chunks: vec![("agent.result.final", "paris capital france")], ..Default::default() };
+        let (a, b) = (spawn_mock(fast()).await, spawn_mock(fast()).await);
+        let late = spawn_mock(MockAdapter{ chunks: vec![("agent.result.final", "answer forty two")], chunk_delay: Duration::from_millis(200), ..Default::default() }).await;
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([a, b, late]).to_string());
+        let downgrades = |out: &[serde_json::Value]| out.iter().filter(|m| m["payload"]["type"] == "control.status").cloned().collect::<Vec<_>>();
+        std::env::set_var("ATP_DOWNGRADE_MARGIN", "0.5");
+        assert!(downgrades(&run_request(test_frame("downgrade-tolerated")).await).is_empty());
+        std::env::set_var("ATP_DOWNGRADE_MARGIN", "0.2");
+        let out = run_request(test_frame("downgrade")).await;
+        std::env::remove_var("ATP_DOWNGRADE_MARGIN");
+        let ctrl = downgrades(&out);
+        assert_eq!(ctrl.len(), 1);
+        let f: Frame = serde_json::from_value(ctrl[0].clone()).expect("schema-conformant frame");
+        assert_eq!((f.session_id.as_str(), f.msg_seq), ("downgrade", 3));
+        assert_eq!(f.payload.content["provisional"], "DOWNGRADED");
+        assert_eq!(f.payload.content["from"].as_f64(), Some(1.0));
+        assert!((f.payload.content["to"].as_f64().unwrap() - 2.0 / 3.0).abs() < 1e-3);
+    }
+
+    #[tokio::test]
+    async fn pressure_policy_is_configurable() {
+        let _g = ENV_LOCK.lock().await;
+        let actions = || (pressure_action(&Lane::Gold), pressure_action(&Lane::Silver), pressure_action(&Lane::Bronze));
+        std::env::remove_var("ATP_PRESSURE_POLICY");
+        assert_eq!(actions(), (PressureAction::Proceed, PressureAction::Delay(500), PressureAction::Ecn));
+        std::env::set_var("ATP_PRESSURE_POLICY", "GOLD=delay:10,silver=sometimes,bronze=proceed");
+        assert_eq!(actions(), (PressureAction::Delay(10), PressureAction::Delay(500), PressureAction::Proceed));
+        std::env::set_var("ATP_PRESSURE_POLICY", "bronze=drop,gold=ECN");
+        assert_eq!((actions().0, actions().2), (PressureAction::Ecn, PressureAction::Drop));
+        std::env::remove_var("ATP_PRESSURE_POLICY");
+    }
+
+    #[tokio::test]
+    async fn pressure_drops_bronze_delays_silver_and_passes_gold() {
+        let _g = ENV_LOCK.lock().await;
+        let ep = spawn_mock(MockAdapter{ chunks: vec![("agent.result.final", "ok")], ..Default::default() }).await;
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([ep]).to_string());
+        std::env::set_var("ATP_PRESSURE_POLICY", "silver=delay:50, bronze = drop");
+        let under_pressure = |qos: &str| { let mut f = test_frame(&format!("pressure-{qos}")); f.qos = qos.into(); f };
+        let mut out = HashMap::new();
+        for qos in ["bronze", "silver", "gold"] {
+            let frame = under_pressure(qos);
+            let key = window_key(&frame, per_lane_windows());
+            GLOBAL_WINDOWS.admit(&key, &frame.window, 0, 0).await.unwrap();
+            GLOBAL_WINDOWS.ack(&key, 0, 0).await;
+            GLOBAL_WINDOWS.mark_backpressure(&key).await;
+            let started = Instant::now();
+            out.insert(qos, (run_request(frame).await, started.elapsed()));
+        }
+        std::env::remove_var("ATP_PRESSURE_POLICY");
+        let has_fin = |qos: &str| out[qos].0.iter().any(|m| m["flags"] == json!(["FIN"]));
+        assert_eq!(out["bronze"].0, vec![json!({"control.status":"ECN","action":"drop","reason":"pressure"})]);
+        assert_eq!(out["silver"].0[0], json!({"control.status":"ECN","action":"delay","reason":"pressure","suggested_wait_ms":50}));
+        assert!(has_fin("silver") && out["silver"].1 >= Duration::from_millis(50));
+        assert!(out["gold"].0.iter().all(|m| m.get("control.status").is_none()));
+        assert!(has_fin("gold"));
+    }
+
+    #[tokio::test]
+    async fn ecn_marks_bronze_under_mild_pressure_and_drops_under_severe() {
+        let _g = ENV_LOCK.lock().await;
+        use_mocks(vec![MockAdapter{ chunks: vec![("agent.result.final", "ok")], ..Default::default() }]).await;
+        std::env::remove_var("ATP_PRESSURE_POLICY");
+        let run_with_marks = |marks: usize| async move {
+            let mut frame = test_frame(&format!("ecn-{marks}"));
+            frame.qos = "bronze".into();
+            let key = window_key(&frame, per_lane_windows());
+            GLOBAL_WINDOWS.admit(&key, &frame.window, 0, 0).await.unwrap();
+            GLOBAL_WINDOWS.ack(&key, 0, 0).await;
+            for _ in 0..marks { GLOBAL_WINDOWS.mark_backpressure(&key).await; }
+            run_request(frame).await
+        };
+        let mild = run_with_marks(1).await;
+        assert_eq!(mild[0], json!({"control.status":"ECN","action":"mark","reason":"pressure"}));
+        assert!(mild.iter().any(|m| m["flags"] == json!(["FIN"])), "marked request is still served");
+        let severe = run_with_marks(ecn_severe_marks()).await;
+        assert_eq!(severe, vec![json!({"control.status":"ECN","action":"drop","reason":"pressure"})]);
+    }
+
+    #[test]
+    fn admit_final_keeps_most_confident_within_cap() {
+        let (mut finals, mut sources) = (vec![], vec![]);
+        for (text, conf) in [("a", 0.5), ("b", 0.2), ("c", 0.9), ("d", 0.1)] { admit_final(&mut finals, &mut sources, 2, text.into(), (None, Some(conf))); }
+        assert_eq!(finals, ["a", "c"]);
+        assert!(!admit_final(&mut finals, &mut sources, 2, "e".into(), (None, None)));
+    }
+
+    #[tokio::test]
+    async fn excess_finals_are_truncated() {
+        let _g = ENV_LOCK.lock().await;
+        let chunks = std::iter::repeat_n(("agent.result.final", "paris capital france"), 50).chain([("agent.result.final", "answer forty two")]).collect();
+        let ep = spawn_mock(MockAdapter{ chunks, ..Default::default() }).await;
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([ep]).to_string());
+        std::env::set_var("ATP_MAX_FINALS", "8");
+        let before = all_samples("finals_truncated_total").len();
+        let out = run_request(test_frame("flood")).await;
+        std::env::remove_var("ATP_MAX_FINALS");
+        assert_eq!(all_samples("finals_truncated_total").len() - before, 43);
+        let fin = out.iter().find(|m| m["flags"] == json!(["FIN"])).expect("final frame");
+        let content = &fin["payload"]["content"];
+        assert_eq!(content["finals"].as_array().unwrap().len(), 8);
+        assert_eq!(content["groups"], json!([[0, 1, 2, 3, 4, 5, 6, 7]]));
+        assert_eq!(content["ranked"][0]["score"], 1.0);
+    }
+
+    #[tokio::test]
+    async fn adapter_outcomes_are_counted() {
+        let _g = ENV_LOCK.lock().await;
+        let good = spawn_mock(MockAdapter{ chunks: vec![("agent.result.final", "fine")], ..Default::default() }).await;
+        let bad = spawn_mock(MockAdapter{ stream_error: Some("boom"), ..Default::default() }).await;
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([good, bad]).to_string());
+        all_samples("adapter_stream_total");
+        run_request(test_frame("outcomes")).await;
+        let outcomes = |ep: &str| all_samples("adapter_stream_total").into_iter().filter(|s| s.labels.contains(&("adapter".into(), ep.to_string()))).map(|s| s.labels.into_iter().find(|(k, _)| k == "outcome").unwrap().1).collect::<Vec<_>>();
+        assert_eq!(outcomes(&good), ["ok"]);
+        assert_eq!(outcomes(&bad), ["rpc_error"]);
+        assert_eq!(status_outcome(&Status::cancelled("Timeout expired")), "timeout");
+    }
+
+    #[tokio::test]
+    async fn final_below_quorum_is_degraded() {
+        let _g = ENV_LOCK.lock().await;
+        let good = spawn_mock(MockAdapter{ chunks: vec![("agent.result.final", "lonely answer")], ..Default::default() }).await;
+        let bad = || MockAdapter{ stream_error: Some("down"), ..Default::default() };
+        let (b1, b2) = (spawn_mock(bad()).await, spawn_mock(bad()).await);
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([good, b1, b2]).to_string());
+        let content_for = |out: Vec<serde_json::Value>| out.into_iter().find(|m| m["flags"] == json!(["FIN"])).unwrap()["payload"]["content"].clone();
+        std::env::set_var("ATP_MIN_QUORUM", "2");
+        let c = content_for(run_request(test_frame("quorum-count")).await);
+        assert_eq!((c["degraded"].clone(), c["responding"].clone(), c["quorum"].clone()), (json!(true), json!(1), json!(2)));
+        assert_eq!(c["scores"], json!([1.0]));
+        std::env::set_var("ATP_MIN_QUORUM", "0.3");
+        let c = content_for(run_request(test_frame("quorum-fraction")).await);
+        std::env::remove_var("ATP_MIN_QUORUM");
+        assert!(c.get("degraded").is_none());
+        assert_eq!((min_quorum(3), min_quorum(0)), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn deadline_finalizes_with_arrived_finals() {
+        let _g = ENV_LOCK.lock().await;
+        let fast = spawn_mock(MockAdapter{ chunks: vec![("agent.result.final", "quick answer")], ..Default::default() }).await;
+        let slow = spawn_mock(MockAdapter{ chunks: vec![("agent.result.final", "slow answer")], chunk_delay: Duration::from_secs(5), ..Default::default() }).await;
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([fast, slow]).to_string());
+        let mut frame = test_frame("deadline");
+        frame.meta.trace = Some(json!({"deadline_ms": 300}));
+        let key = window_key(&frame, per_lane_windows());
+        let deadline_finalized = samples("router_finalize_total", ("reason", "deadline")).len();
+        let started = Instant::now();
+        let out = run_request(frame).await;
+        assert_eq!(samples("router_finalize_total", ("reason", "deadline")).len(), deadline_finalized + 1);
+        assert!(started.elapsed() < Duration::from_secs(2), "took {:?}", started.elapsed());
+        let fin = out.iter().find(|m| m["flags"] == json!(["FIN"])).expect("final frame");
+        assert_eq!(fin["payload"]["content"]["finals"], json!(["\"quick answer\""]));
+        assert_eq!(fin["payload"]["content"]["deadline_exceeded"], true);
+        let w = Window{ max_parallel: 1, max_tokens: 1, max_usd_micros: 1 };
+        assert!(GLOBAL_WINDOWS.admit(&key, &w, 0, 0).await.is_ok(), "window slot released");
+    }
+
+    #[tokio::test]
+    async fn oldest_inflight_age_tracks_longest_request() {
+        let registry: &'static InflightRegistry = Box::leak(Box::default());
+        assert_eq!(registry.oldest_age(), Duration::ZERO);
+        let first = registry.register("s:a", None);
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let _second = registry.register("s:b", None);
+        assert!(registry.oldest_age() >= Duration::from_millis(30));
+        drop(first);
+        assert!(registry.oldest_age() < Duration::from_millis(30));
+    }
+
+    #[tokio::test]
+    async fn configured_adapter_headers_reach_every_rpc() {
+        let _g = ENV_LOCK.lock().await;
+        let metadata = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let eps = use_mocks(vec![MockAdapter{ chunks: vec![("agent.result.final", "authed")], metadata: metadata.clone(), ..Default::default() }]).await;
+        let port = eps[0].rsplit(':').next().unwrap().to_string();
+        let var = format!("ADAPTER_HEADERS_127_0_0_1_{port}");
+        std::env::set_var(&var, r#"{"x-api-key": "k-123", "x-tenant-id": "acme"}"#);
+        run_request(test_frame("adapter-headers")).await;
+        adapters::check_endpoints(eps).await;
+        std::env::remove_var(&var);
+        let seen = metadata.lock().unwrap().clone();
+        assert_eq!(seen.iter().map(|(rpc, _)| *rpc).collect::<std::collections::BTreeSet<_>>(), ["estimate", "health", "stream"].into());
+        for (rpc, md) in &seen {
+            assert_eq!(md.get("x-api-key").and_then(|v| v.to_str().ok()), Some("k-123"), "{rpc}");
+            assert_eq!(md.get("x-tenant-id").and_then(|v| v.to_str().ok()), Some("acme"), "{rpc}");
+        }
+    }
+
+    #[tokio::test]
+    async fn request_deadline_propagates_as_grpc_timeout() {
+        let _g = ENV_LOCK.lock().await;
+        let timeouts = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        use_mocks(vec![MockAdapter{ chunks: vec![("agent.result.final", "on time")], grpc_timeouts: timeouts.clone(), ..Default::default() }]).await;
+        run_request(test_frame("no-deadline")).await;
+        assert!(timeouts.lock().unwrap().is_empty());
+        let mut frame = test_frame("grpc-deadline");
+        frame.meta.trace = Some(json!({"deadline_ms": 5000}));
+        run_request(frame).await;
+        let sent = timeouts.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        let (value, unit) = sent[0].split_at(sent[0].len() - 1);
+        let per_ms = match unit { "H" => 3_600_000.0, "M" => 60_000.0, "S" => 1_000.0, "m" => 1.0, "u" => 1e-3, "n" => 1e-6, other => panic!("unit {other}") };
+        let ms = value.parse::<f64>().unwrap() * per_ms;
+        assert!(ms > 0.0 && ms <= 5000.0, "grpc-timeout {} = {ms}ms", sent[0]);
+    }
+
+    /// Collects `(target, level, fields)` of events emitted while installed as the thread's default subscriber.
+    type CapturedEvent = (String, tracing::Level, HashMap<String, String>);
+    #[derive(Clone, Default)]
+    struct CaptureLayer(std::sync::Arc<std::sync::Mutex<Vec<CapturedEvent>>>);
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for CaptureLayer {
+        fn on_event(&self, event: &tracing::Event<'_>, _ctx: tracing_subscriber::layer::Context<'_, S>) {
+            struct Fields(HashMap<String, String>);
+            impl tracing::field::Visit for Fields {
+                fn record_str(&mut self, f: &tracing::field::Field, v: &str) { self.0.insert(f.name().into(), v.into()); }
+                fn record_debug(&mut self, f: &tracing::field::Field, v: &dyn std::fmt::Debug) { self.0.insert(f.name().into(), format!("{:?}", v)); }
+            }
+            let mut fields = Fields(HashMap::new());
+            event.record(&mut fields);
+            self.0.lock().unwrap().push((event.metadata().target().to_string(), *event.metadata().level(), fields.0));
+        }
+    }
+
+    #[tokio::test]
+    async fn admission_reject_is_logged_with_fields() {
+        use tracing_subscriber::layer::SubscriberExt;
+        let _g = ENV_LOCK.lock().await;
+        let ep = spawn_mock(MockAdapter{ chunks: vec![("agent.result.final", "ok")], ..Default::default() }).await;
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([ep]).to_string());
+        let capture = CaptureLayer::default();
+        let _sub = tracing::subscriber::set_default(tracing_subscriber::registry().with(capture.clone()));
+        let mut frame = test_frame("admission-log");
+        frame.window.max_parallel = 0;
+        run_request(frame).await;
+        let events = capture.0.lock().unwrap();
+        let (_, level, fields) = events.iter().find(|(t, _, f)| t == ADMISSION && f.contains_key("decision")).expect("admission event");
+        assert_eq!(*level, tracing::Level::WARN);
+        assert_eq!(fields["decision"], "reject");
+        assert_eq!(fields["limiting"], "parallel");
+        assert_eq!(fields["session_id"], "admission-log");
+        assert_eq!(fields["stream_id"], "streamA");
+        assert_eq!((fields["inflight"].as_str(), fields["max_parallel"].as_str()), ("0", "0"));
+    }
+
+    #[tokio::test]
+    async fn zero_estimate_admission_is_counted_and_logged() {
+        use tracing_subscriber::layer::SubscriberExt;
+        let _g = ENV_LOCK.lock().await;
+        samples("router_zero_estimate_total", ("qos", "zero-estimate"));
+        use_mocks(vec![MockAdapter{ chunks: vec![("agent.result.final", "free")], ..Default::default() }]).await;
+        let capture = CaptureLayer::default();
+        let _sub = tracing::subscriber::set_default(tracing_subscriber::registry().with(capture.clone()));
+        let mut frame = test_frame("zero-estimate");
+        frame.qos = "zero-estimate".into();
+        run_request(frame).await;
+        assert_eq!(samples("router_zero_estimate_total", ("qos", "zero-estimate")).len(), 1);
+        let events = capture.0.lock().unwrap();
+        let (_, level, fields) = events.iter().find(|(t, _, f)| t == ADMISSION && f.get("message").is_some_and(|m| m.contains("zero cost estimate"))).expect("zero-estimate warning");
+        assert_eq!((*level, fields["session_id"].as_str(), fields["fallback"].as_str()), (tracing::Level::WARN, "zero-estimate", "false"));
+    }
+
+    #[tokio::test]
+    async fn message_framing_sends_one_reply_per_message() {
+        let (tx, rx) = mpsc::channel(8);
+        for r in ["{\"a\":1}", "{\"b\":2}"] { tx.send(r.to_string()).await.unwrap(); }
+        drop(tx);
+        let mut sent: Vec<Message> = Vec::new();
+        pump_replies(rx, mpsc::channel(1).1, std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false)), &mut sent).await;
+        let texts: Vec<String> = sent.into_iter().map(|m| match m { Message::Text(t) => t, other => panic!("unexpected {:?}", other) }).collect();
+        assert_eq!(texts, vec!["{\"a\":1}", "{\"b\":2}"]);
+        assert_eq!(Framing::parse("message"), Some(Framing::Message));
+        assert!(!requests_lines(&serde_json::to_string(&test_frame("framing")).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn lines_framing_batches_queued_replies_as_jsonl() {
+        let (tx, rx) = mpsc::channel(8);
+        for r in ["{\"a\":1}", "{\"b\":2}", "{\"c\":3}"] { tx.send(r.to_string()).await.unwrap(); }
+        drop(tx);
+        let mut sent: Vec<Message> = Vec::new();
+        pump_replies(rx, mpsc::channel(1).1, std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true)), &mut sent).await;
+        assert_eq!(sent.len(), 1);
+        let Message::Text(batch) = &sent[0] else { panic!("expected text") };
+        let parsed: Vec<serde_json::Value> = batch.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(parsed, vec![json!({"a":1}), json!({"b":2}), json!({"c":3})]);
+        assert!(batch.ends_with('\n'));
+        assert_eq!((Framing::parse("lines"), Framing::parse("jsonl"), Framing::parse("xml")), (Some(Framing::Lines), Some(Framing::Lines), None));
+        let mut frame = test_frame("framing");
+        frame.flags.push("JSONL".into());
+        assert!(requests_lines(&serde_json::to_string(&frame).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn debug_scheduler_route_is_gated_and_reports_lanes() {
+        use tower::ServiceExt;
+        let _g = ENV_LOCK.lock().await;
+        std::env::remove_var("ATP_DEBUG_SCHEDULER");
+        let resp = RouterBuilder::new().build().oneshot(axum::http::Request::builder().uri("/debug/scheduler").body(axum::body::Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(resp.status(), axum::http::StatusCode::NOT_FOUND);
+        // The global scheduler is pinned to whichever test runtime first touches it, so the snapshot is checked on a local one.
+        let sched = Scheduler::spawn(4, |_item| async {});
+        let (reply_tx, _reply_rx) = mpsc::channel(1);
+        for _ in 0..2 { sched.gold.send(WorkItem{ frame: test_frame("debug-sched"), reply_tx: reply_tx.clone(), slot: None, conn: None }).await.unwrap(); }
+        let mut v = sched.snapshot();
+        for _ in 0..200 { if v["lanes"]["gold"]["dispatched"] == 2 { break; } tokio::time::sleep(Duration::from_millis(5)).await; v = sched.snapshot(); }
+        for (lane, weight) in [("gold", 5), ("silver", 3), ("bronze", 1)] {
+            assert_eq!(v["lanes"][lane]["weight"], weight);
+            assert!(v["lanes"][lane]["depth"].is_u64() && v["lanes"][lane]["dispatched"].is_u64(), "{}: {}", lane, v);
+        }
+        assert_eq!((v["lanes"]["gold"]["dispatched"].clone(), v["lanes"]["gold"]["depth"].clone()), (json!(2), json!(0)));
+        assert_eq!(v["rotation"]["length"], 9);
+        assert!(v["rotation"]["position"].as_u64().unwrap() < 9 && v["turns"].as_u64().unwrap() >= 2);
+    }
+
+    #[tokio::test]
+    async fn readyz_flips_to_503_when_inflight_saturated() {
+        use tower::ServiceExt;
+        let resp = RouterBuilder::new().build().oneshot(axum::http::Request::builder().uri("/healthz").body(axum::body::Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(resp.status(), axum::http::StatusCode::OK);
+        let release = std::sync::Arc::new(tokio::sync::Notify::new());
+        let gate = release.clone();
+        let sched = Scheduler::spawn(2, move |_item| { let gate = gate.clone(); async move { gate.notified().await; } });
+        assert_eq!(readiness(&sched, 4).0, axum::http::StatusCode::OK);
+        let (reply_tx, _reply_rx) = mpsc::channel(1);
+        for i in 0..2 { sched.gold.send(WorkItem{ frame: test_frame(&format!("ready-{i}")), reply_tx: reply_tx.clone(), slot: None, conn: None }).await.unwrap(); }
+        for _ in 0..200 { if sched.permits.available_permits() == 0 { break; } tokio::time::sleep(Duration::from_millis(5)).await; }
+        let (status, body) = readiness(&sched, 4);
+        assert_eq!(status, axum::http::StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!((body["reason"].clone(), body["inflight"].clone()), (json!("inflight_saturated"), json!(2)));
+        // More work queues behind the taken permits; releasing them drains the lane and readiness recovers.
+        for i in 0..4 { sched.silver.send(WorkItem{ frame: test_frame(&format!("queued-{i}")), reply_tx: reply_tx.clone(), slot: None, conn: None }).await.unwrap(); }
+        release.notify_waiters();
+        for _ in 0..200 { if readiness(&sched, 4).0 == axum::http::StatusCode::OK { break; } release.notify_waiters(); tokio::time::sleep(Duration::from_millis(5)).await; }
+        assert_eq!(readiness(&sched, 4).0, axum::http::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn readyz_reports_queue_depth_over_threshold() {
+        let sched = Scheduler::spawn(1, |_item| std::future::pending());
+        let (reply_tx, _reply_rx) = mpsc::channel(1);
+        for i in 0..4 { sched.bronze.send(WorkItem{ frame: test_frame(&format!("deep-{i}")), reply_tx: reply_tx.clone(), slot: None, conn: None }).await.unwrap(); }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let body: serde_json::Value = serde_json::from_str(&readiness(&sched, 3).1).unwrap();
+        assert_eq!((body["reason"].clone(), body["deepest_lane"].clone(), body["depth"].clone()), (json!("queue_depth"), json!("bronze"), json!(3)));
+        let sched = Scheduler::spawn(8, |_item| std::future::pending());
+        let (status, body) = readiness(&sched, 3);
+        assert_eq!((status, serde_json::from_str::<serde_json::Value>(&body).unwrap()["ready"].clone()), (axum::http::StatusCode::OK, json!(true)));
+    }
+
+    /// Panics on a final reading `panic-after-admit`, so a request can be made to fail after admission; any other
+    /// finals get the built-in grouping.
+    struct PanicOnSentinel;
+    impl consensus::ConsensusFn for PanicOnSentinel {
+        fn compute(&self, finals: &[String], meta: &[consensus::FinalMeta], cfg: &consensus::ConsensusConfig) -> consensus::ConsensusResult {
+            if finals.iter().any(|f| f.contains("panic-after-admit")) { panic!("injected panic after admission"); }
+            consensus::compute_with(finals, meta, cfg)
+        }
+    }
+
+    #[tokio::test]
+    async fn panic_after_admission_releases_window_slot() {
+        let _g = ENV_LOCK.lock().await;
+        all_samples("router_request_panics_total");
+        use_mocks(vec![MockAdapter{ chunks: vec![("agent.result.final", "panic-after-admit")], ..Default::default() }]).await;
+        consensus::install(PanicOnSentinel);
+        let mut frame = test_frame("panic-after-admit");
+        frame.window.max_parallel = 1;
+        let key = window_key(&frame, per_lane_windows());
+        let (reply_tx, _reply_rx) = mpsc::channel::<String>(128);
+        let res = tokio::spawn(process_request(WorkItem{ frame: frame.clone(), reply_tx, slot: None, conn: None })).await;
+        consensus::install(consensus::BuiltIn);
+        assert!(res.unwrap_err().is_panic());
+        let mut released = false;
+        for _ in 0..200 {
+            if GLOBAL_WINDOWS.inner.read().await.get(&key).map(|w| w.inflight) == Some(0) { released = true; break; }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(released, "window slot still held after panic");
+        assert!(GLOBAL_WINDOWS.admit(&key, &frame.window, 0, 0).await.is_ok());
+        GLOBAL_WINDOWS.ack(&key, 0, 0).await;
+        assert_eq!(all_samples("router_request_panics_total").iter().map(|s| s.value).sum::<f64>(), 1.0);
+    }
+
+    /// Uppercases qos, but only on sessions named `transform-*` so other tests' frames are untouched.
+    struct UppercaseQos;
+    impl transform::FrameTransform for UppercaseQos {
+        fn transform(&self, mut frame: Frame) -> Result<Frame, transform::RejectReason> {
+            if !frame.session_id.starts_with("transform-") { return Ok(frame); }
+            if frame.session_id == "transform-reject" { return Err(transform::RejectReason("blocked".into())); }
+            frame.qos = frame.qos.to_uppercase();
+            Ok(frame)
+        }
+    }
+
+    #[tokio::test]
+    async fn frame_transforms_rewrite_before_routing() {
+        let _ = RouterBuilder::new().frame_transform(transform::Noop).frame_transform(UppercaseQos).build();
+        let (out_tx, mut out_rx) = mpsc::channel::<String>(8);
+        let conn = ConnState::uncapped();
+        let mut frame = test_frame("transform-qos");
+        frame.qos = "silver".into();
+        let (item, lane) = route_inbound(&serde_json::to_string(&frame).unwrap(), &out_tx, &conn).await.expect("scheduled");
+        assert_eq!((item.frame.qos.as_str(), lane.as_str()), ("SILVER", "silver"));
+        let (item, _) = route_inbound(&serde_json::to_string(&test_frame("untouched")).unwrap(), &out_tx, &conn).await.expect("scheduled");
+        assert_eq!(item.frame.qos, "gold");
+        assert!(route_inbound(&serde_json::to_string(&test_frame("transform-reject")).unwrap(), &out_tx, &conn).await.is_none());
+        assert_eq!(out_rx.recv().await.unwrap(), json!({"error":"frame_rejected","reason":"blocked"}).to_string());
+        transform::install(vec![]);
+    }
+
+    #[test]
+    fn reassembly_failures_are_counted_by_reason() {
+        let before = samples("router_reassembly_failures_total", ("reason", "out_of_order")).len();
+        let mut frags = fragment_text_frame(test_frame("reassembly-metrics"), &"x".repeat(30), 10);
+        frags.swap(0, 1);
+        assert!(reassemble_counted(&frags).is_none());
+        let after = samples("router_reassembly_failures_total", ("reason", "out_of_order"));
+        assert_eq!(after.len(), before + 1);
+        assert_eq!(after.last().unwrap().value, 1.0);
+    }
+
+    #[tokio::test]
+    async fn weighted_fanout_streams_to_k_adapters() {
+        let _g = ENV_LOCK.lock().await;
+        let calls: Vec<_> = (0..3).map(|_| std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0))).collect();
+        use_mocks(calls.iter().map(|c| MockAdapter{ chunks: vec![("agent.result.final", "sampled")], streams: c.clone(), ..Default::default() }).collect()).await;
+        std::env::set_var("ATP_FANOUT", "weighted:2:cost");
+        let out = run_request(test_frame("weighted-fanout")).await;
+        std::env::remove_var("ATP_FANOUT");
+        assert_eq!(out.iter().find(|m| m["flags"] == json!(["FIN"])).expect("final")["payload"]["content"]["finals"].as_array().unwrap().len(), 2);
+        assert_eq!(calls.iter().map(|c| c.load(std::sync::atomic::Ordering::SeqCst)).sum::<usize>(), 2);
+    }
+
+    #[tokio::test]
+    async fn inbound_fragments_are_reassembled_before_processing() {
+        let _g = ENV_LOCK.lock().await;
+        let streams = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        use_mocks(vec![MockAdapter{ chunks: vec![("agent.result.final", "whole")], streams: streams.clone(), ..Default::default() }]).await;
+        let (out_tx, mut out_rx) = mpsc::channel::<String>(8);
+        let conn = ConnState::uncapped();
+        let text = "a request long enough to span two fragments";
+        let frags = fragment_text_frame(test_frame("inbound-frags"), text, 25);
+        assert_eq!(frags.len(), 2);
+        assert!(route_inbound(&serde_json::to_string(&frags[0]).unwrap(), &out_tx, &conn).await.is_none());
+        assert!(out_rx.try_recv().is_err(), "no reply to a leading fragment");
+        let (item, _) = route_inbound(&serde_json::to_string(&frags[1]).unwrap(), &out_tx, &conn).await.expect("reassembled");
+        assert_eq!(item.frame.payload.content, json!({"text": text}));
+        assert!(!item.frame.flags.iter().any(|f| f == "MORE"));
+        let out = run_request(item.frame).await;
+        assert!(out.iter().any(|m| m["flags"] == json!(["FIN"])));
+        assert_eq!(streams.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        let frags = fragment_text_frame(test_frame("inbound-frags-gap"), &"y".repeat(30), 10);
+        assert!(route_inbound(&serde_json::to_string(&frags[0]).unwrap(), &out_tx, &conn).await.is_none());
+        assert!(route_inbound(&serde_json::to_string(&frags[2]).unwrap(), &out_tx, &conn).await.is_none());
+        let err: serde_json::Value = serde_json::from_str(&out_rx.recv().await.unwrap()).unwrap();
+        assert_eq!((err["error"].as_str(), err["reason"].as_str()), (Some("reassembly_failed"), Some("out_of_order")));
+        assert!(route_inbound(&serde_json::to_string(&test_frame("inbound-frags-gap")).unwrap(), &out_tx, &conn).await.is_some(), "buffer discarded");
+    }
+
+    #[tokio::test]
+    async fn inbound_fragments_are_scoped_to_their_connection_and_capped() {
+        let (out_tx, mut out_rx) = mpsc::channel::<String>(8);
+        let (first, second) = (ConnState::uncapped(), ConnState::uncapped());
+        let frags = fragment_text_frame(test_frame("frags-per-conn"), &"z".repeat(45), 15);
+        assert!(route_inbound(&serde_json::to_string(&frags[0]).unwrap(), &out_tx, &first).await.is_none());
+        assert!(route_inbound(&serde_json::to_string(&frags[1]).unwrap(), &out_tx, &second).await.is_none());
+        let err: serde_json::Value = serde_json::from_str(&out_rx.recv().await.unwrap()).unwrap();
+        assert_eq!(err["reason"], "out_of_order", "another connection cannot continue the message");
+        assert!(route_inbound(&serde_json::to_string(&frags[1]).unwrap(), &out_tx, &first).await.is_none());
+        assert!(route_inbound(&serde_json::to_string(&frags[2]).unwrap(), &out_tx, &first).await.is_some());
+
+        let capped = StreamReassembler::new(Duration::from_secs(60), 40);
+        assert!(matches!(capped.accept(frags[0].clone()), Ok(None)));
+        assert!(matches!(capped.accept(frags[1].clone()), Err(atp_schema::ReassemblyError::TooLarge { max_bytes: 40 })));
+        assert!(matches!(capped.accept(frags[0].clone()), Ok(None)), "the oversized buffer was dropped");
+
+        let before = all_samples("router_reassembly_evicted_total").len();
+        let idle = StreamReassembler::new(Duration::ZERO, atp_schema::DEFAULT_MAX_MESSAGE_BYTES);
+        assert!(matches!(idle.accept(frags[0].clone()), Ok(None)));
+        assert!(matches!(idle.accept(test_frame("frags-evicted")), Ok(Some(_))));
+        assert_eq!(all_samples("router_reassembly_evicted_total").len(), before + 1);
+    }
+
+    /// Puts every final in one group, but only when one of them mentions `one-group` so other tests keep the built-in.
+    struct OneGroup;
+    impl consensus::ConsensusFn for OneGroup {
+        fn compute(&self, finals: &[String], meta: &[consensus::FinalMeta], cfg: &consensus::ConsensusConfig) -> consensus::ConsensusResult {
+            if !finals.iter().any(|f| f.contains("one-group")) { return consensus::compute_with(finals, meta, cfg); }
+            let text = finals[0].clone();
+            consensus::ConsensusResult {
+                finals: finals.to_vec(), representatives: vec![(0, text.clone())], groups: vec![(0..finals.len()).collect()], scores: vec![1.0],
+                ranked: vec![consensus::Representative{ index: 0, text, score: 1.0, group_size: finals.len() }], embed_version: consensus::EMBED_VERSION,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_consensus_shapes_final_frame() {
+        let _g = ENV_LOCK.lock().await;
+        let _ = RouterBuilder::new().consensus(OneGroup).build();
+        use_mocks(vec![
+            MockAdapter{ chunks: vec![("agent.result.final", "one-group: forty two")], ..Default::default() },
+            MockAdapter{ chunks: vec![("agent.result.final", "paris is in france")], ..Default::default() },
+        ]).await;
+        let out = run_request(test_frame("custom-consensus")).await;
+        let content = &out.last().unwrap()["payload"]["content"];
+        assert_eq!((content["groups"].clone(), content["scores"].clone()), (json!([[0, 1]]), json!([1.0])));
+        assert_eq!(consensus::run(&["a b".into(), "c d".into()], &[], &consensus::ConsensusConfig::default()).groups.len(), 2);
+        consensus::install(consensus::BuiltIn);
+    }
+
+    /// Accepts and immediately drops connections, so an OPA query against it fails.
+    fn dead_opa() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || for conn in listener.incoming() { drop(conn); });
+        url
+    }
+
+    #[tokio::test]
+    async fn opa_failure_follows_fail_mode() {
+        let _g = ENV_LOCK.lock().await;
+        all_samples("router_opa_fail_open_total");
+        let total = |name: &'static str| all_samples(name).iter().map(|s| s.value).sum::<f64>();
+        let (open0, closed0) = (total("router_opa_fail_open_total"), total("router_opa_fail_closed_total"));
+        std::env::set_var("OPA_URL", dead_opa());
+        let high = Meta{ risk: Some("high".into()), ..Default::default() };
+        let low = Meta{ risk: Some("low".into()), ..Default::default() };
+        let decide = |mode: &str, meta: &Meta| { std::env::set_var("ATP_OPA_FAIL", mode); let meta = meta.clone(); std::thread::spawn(move || opa_allow(&meta)).join().unwrap() };
+        assert!(decide("open", &high));
+        assert!(!decide("closed", &low));
+        assert!(!decide("high-risk", &high));
+        assert!(decide("high-risk", &low));
+        std::env::remove_var("ATP_OPA_FAIL");
+        std::env::remove_var("OPA_URL");
+        assert_eq!(total("router_opa_fail_open_total") - open0, 2.0);
+        assert_eq!(total("router_opa_fail_closed_total") - closed0, 2.0);
+    }
+
+    #[tokio::test]
+    async fn batch_payload_returns_correlated_results() {
+        let _g = ENV_LOCK.lock().await;
+        let streams = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let ep = spawn_mock(MockAdapter{ chunks: vec![("agent.result.final", "batched")], streams: streams.clone(), ..Default::default() }).await;
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([ep]).to_string());
+        let mut frame = test_frame("batch");
+        frame.payload.r#type = "batch".into();
+        frame.payload.content = json!({"batch": [{"id": "a", "content": {"text": "one"}}, {"id": "b", "content": {"text": "two"}}, {"text": "three"}]});
+        let (reply_tx, mut reply_rx) = mpsc::channel::<String>(8);
+        dispatch(WorkItem{ frame: frame.clone(), reply_tx: reply_tx.clone(), slot: None, conn: None }).await;
+        let out: Vec<serde_json::Value> = std::iter::from_fn(|| reply_rx.try_recv().ok()).map(|l| serde_json::from_str(&l).unwrap()).collect();
+        assert_eq!(out.len(), 1);
+        assert_eq!((out[0]["flags"].clone(), out[0]["payload"]["type"].clone()), (json!(["FIN"]), json!("agent.result.batch")));
+        let results = out[0]["payload"]["content"]["results"].as_array().unwrap();
+        assert_eq!(results.iter().map(|r| r["id"].clone()).collect::<Vec<_>>(), vec![json!("a"), json!("b"), json!(2)]);
+        for (i, r) in results.iter().enumerate() {
+            assert_eq!(r["index"], i);
+            assert_eq!(r["final"]["finals"], json!(["\"batched\""]), "{}", r);
+        }
+        assert_eq!(streams.load(std::sync::atomic::Ordering::SeqCst), 3);
+        frame.payload.content = json!({"batch": []});
+        dispatch(WorkItem{ frame, reply_tx, slot: None, conn: None }).await;
+        assert_eq!(reply_rx.recv().await.unwrap(), json!({"error":"invalid_batch","max_items":MAX_BATCH_ITEMS}).to_string());
+    }
+
+    #[test]
+    fn batch_extra_permits_take_only_free_capacity() {
+        let inflight = std::sync::Arc::new(tokio::sync::Semaphore::new(3));
+        let _busy = inflight.clone().try_acquire_owned().unwrap();
+        let conn = std::sync::Arc::new(tokio::sync::Semaphore::new(3));
+        let slot = conn.clone().try_acquire_owned().unwrap();
+        assert_eq!(batch_extra_permits(Some(&inflight), Some(&slot), 5).len(), 2, "both free in-flight permits");
+        assert_eq!(inflight.available_permits(), 2, "released when the batch ends");
+        let _other = conn.clone().try_acquire_owned().unwrap();
+        let extra = batch_extra_permits(Some(&inflight), Some(&slot), 5);
+        assert_eq!((extra.len(), inflight.available_permits()), (1, 1), "the connection's one free slot bounds it");
+        assert_eq!(batch_extra_permits(None, None, 4).len(), 4);
+    }
+
+    #[tokio::test]
+    async fn batch_items_keep_their_type_and_run_without_spare_capacity() {
+        let _g = ENV_LOCK.lock().await;
+        let caps = CapabilitiesResponse{ payload_types: vec!["code".into()], ..Default::default() };
+        let ep = spawn_mock(MockAdapter{ chunks: vec![("agent.result.final", "typed")], capabilities: Some(caps), ..Default::default() }).await;
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([ep]).to_string());
+        adapters::refresh_capabilities(&[ep]).await;
+        let mut frame = test_frame("batch-typed");
+        frame.payload.r#type = "batch".into();
+        frame.payload.content = json!({"batch": [{"type": "code", "content": {"text": "fn main() {}"}}, {"content": {"text": "plain"}}, {"type": "code", "content": {"text": "x"}}]});
+        let (reply_tx, mut reply_rx) = mpsc::channel::<String>(8);
+        let full = std::sync::Arc::new(tokio::sync::Semaphore::new(0));
+        process_batch(WorkItem{ frame, reply_tx, slot: None, conn: None }, Some(full)).await;
+        let out: serde_json::Value = serde_json::from_str(&reply_rx.recv().await.unwrap()).unwrap();
+        let results = &out["payload"]["content"]["results"];
+        assert_eq!((results[0]["final"]["finals"].clone(), results[2]["final"]["finals"].clone()), (json!(["\"typed\""]), json!(["\"typed\""])), "{out}");
+        assert_eq!(results[1]["error"]["error"], "no_capable_adapter", "untyped items are text");
+    }
+
+    #[tokio::test]
+    async fn batch_item_streaming_past_the_reply_buffer_completes() {
+        let _g = ENV_LOCK.lock().await;
+        let mut chunks = vec![("agent.result.partial", "more"); 300];
+        chunks.push(("agent.result.final", "done"));
+        let ep = spawn_mock(MockAdapter{ chunks, ..Default::default() }).await;
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([ep]).to_string());
+        let mut frame = test_frame("batch-long");
+        frame.payload.r#type = "batch".into();
+        frame.payload.content = json!({"batch": [{"text": "one"}]});
+        let (reply_tx, mut reply_rx) = mpsc::channel::<String>(8);
+        tokio::time::timeout(Duration::from_secs(10), dispatch(WorkItem{ frame, reply_tx, slot: None, conn: None })).await.expect("batch finished");
+        let out: serde_json::Value = serde_json::from_str(&reply_rx.recv().await.unwrap()).unwrap();
+        assert_eq!(out["payload"]["content"]["results"][0]["final"]["finals"], json!(["\"done\""]), "{out}");
+    }
+
+    #[tokio::test]
+    async fn no_consensus_flag_passes_finals_through() {
+        let _g = ENV_LOCK.lock().await;
+        let a = spawn_mock(MockAdapter{ chunks: vec![("agent.result.final", "the answer is 42")], ..Default::default() }).await;
+        let b = spawn_mock(MockAdapter{ chunks: vec![("agent.result.final", "The answer is 42!")], ..Default::default() }).await;
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([a, b]).to_string());
+        let mut frame = test_frame("no-consensus");
+        frame.flags.push("NO_CONSENSUS".into());
+        let out = run_request(frame).await;
+        assert!(out.iter().all(|m| m["payload"]["type"] != "agent.result.provisional" && m["payload"]["content"]["provisional"].is_null()));
+        let fin = out.iter().find(|m| m["flags"] == json!(["FIN"])).expect("final frame");
+        let content = &fin["payload"]["content"];
+        assert_eq!(content["consensus"], false);
+        assert!(content.get("groups").is_none() && content.get("scores").is_none() && content.get("ranked").is_none());
+        let mut answers: Vec<(String, String)> = content["answers"].as_array().unwrap().iter().map(|x| (x["adapter"].as_str().unwrap().to_string(), x["final"].as_str().unwrap().to_string())).collect();
+        answers.sort();
+        let mut want = vec![(a.clone(), "\"the answer is 42\"".to_string()), (b.clone(), "\"The answer is 42!\"".to_string())];
+        want.sort();
+        assert_eq!(answers, want);
+    }
+
+    #[tokio::test]
+    async fn oversized_window_is_clamped_to_org_ceiling() {
+        let _g = ENV_LOCK.lock().await;
+        let ep = spawn_mock(MockAdapter{ chunks: vec![("agent.result.final", "ok")], ..Default::default() }).await;
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([ep]).to_string());
+        std::env::set_var("ATP_ORG_MAX_PARALLEL", "2");
+        std::env::set_var("ATP_ORG_MAX_USD_MICROS", "1000");
+        let mut frame = test_frame("org-ceiling");
+        frame.window = Window{ max_parallel: u32::MAX, max_tokens: 500, max_usd_micros: u64::MAX };
+        let (out_tx, _out_rx) = mpsc::channel::<String>(8);
+        let conn = ConnState::uncapped();
+        let routed = route_inbound(&serde_json::to_string(&frame).unwrap(), &out_tx, &conn).await;
+        std::env::remove_var("ATP_ORG_MAX_PARALLEL");
+        std::env::remove_var("ATP_ORG_MAX_USD_MICROS");
+        let (item, _) = routed.expect("scheduled");
+        let ceiling = Window{ max_parallel: 2, max_tokens: 500, max_usd_micros: 1000 };
+        assert_eq!(item.frame.window, ceiling);
+        let out = run_request(item.frame).await;
+        let ack = out.iter().find(|m| m["flags"] == json!(["ACK"])).expect("ack");
+        assert_eq!(ack["window"], json!(ceiling));
+        let (item, _) = route_inbound(&serde_json::to_string(&test_frame("org-unset")).unwrap(), &out_tx, &conn).await.expect("scheduled");
+        assert_eq!(item.frame.window, test_frame("org-unset").window);
+    }
+
+    #[tokio::test]
+    async fn default_window_merges_with_client_window() {
+        let _g = ENV_LOCK.lock().await;
+        std::env::set_var("ATP_DEFAULT_MAX_PARALLEL", "4");
+        std::env::set_var("ATP_DEFAULT_MAX_TOKENS", "8000");
+        let (out_tx, _out_rx) = mpsc::channel::<String>(8);
+        let conn = ConnState::uncapped().with_default_window(load_default_window().unwrap());
+        let mut over = test_frame("default-over");
+        over.window = Window{ max_parallel: 16, max_tokens: 100_000, max_usd_micros: 5000 };
+        let mut under = test_frame("default-under");
+        under.window = Window{ max_parallel: 1, max_tokens: 200, max_usd_micros: 5000 };
+        let mut omitted = serde_json::to_value(test_frame("default-omitted")).unwrap();
+        omitted.as_object_mut().unwrap().remove("window");
+        let over = route_inbound(&serde_json::to_string(&over).unwrap(), &out_tx, &conn).await.expect("scheduled").0.frame.window;
+        let under = route_inbound(&serde_json::to_string(&under).unwrap(), &out_tx, &conn).await.expect("scheduled").0.frame.window;
+        let omitted = route_inbound(&omitted.to_string(), &out_tx, &conn).await.expect("scheduled").0.frame.window;
+        std::env::set_var("ATP_DEFAULT_MAX_TOKENS", "lots");
+        let invalid = load_default_window();
+        let panicked = std::panic::catch_unwind(|| RouterBuilder::new().build()).map(|_| ()).unwrap_err();
+        std::env::remove_var("ATP_DEFAULT_MAX_PARALLEL");
+        std::env::remove_var("ATP_DEFAULT_MAX_TOKENS");
+        assert_eq!(over, Window{ max_parallel: 4, max_tokens: 8000, max_usd_micros: 5000 });
+        assert_eq!(under, Window{ max_parallel: 1, max_tokens: 200, max_usd_micros: 5000 });
+        assert_eq!(omitted, Window{ max_parallel: 4, max_tokens: 8000, max_usd_micros: u64::MAX });
+        assert!(invalid.unwrap_err().to_string().contains("ATP_DEFAULT_MAX_TOKENS"));
+        assert!(panicked.downcast_ref::<String>().is_some_and(|m| m.contains("ATP_DEFAULT_MAX_TOKENS")));
+        assert_eq!(load_default_window().unwrap(), Window::default());
+    }
+
+    #[tokio::test]
+    async fn final_reports_cost_of_streaming_adapters() {
+        let _g = ENV_LOCK.lock().await;
+        use_mocks(vec![
+            MockAdapter{ chunks: vec![("agent.result.partial", "draft"), ("agent.result.final", "a")], usage: (3, 7, 40), estimate: EstimateResponse{ in_tokens: 10, out_tokens: 10, usd_micros: 100, ..Default::default() }, ..Default::default() },
+            MockAdapter{ chunks: vec![("agent.result.final", "b")], usage: (5, 0, 9), estimate: EstimateResponse{ in_tokens: 4, out_tokens: 4, usd_micros: 10, ..Default::default() }, ..Default::default() },
+            MockAdapter{ stream_error: Some("down"), usage: (100, 100, 100), estimate: EstimateResponse{ in_tokens: 50, out_tokens: 50, usd_micros: 500, ..Default::default() }, ..Default::default() },
+        ]).await;
+        let out = run_request(test_frame("cost-receipt")).await;
+        let fin = out.iter().find(|m| m["flags"] == json!(["FIN"])).expect("fin");
+        assert_eq!(fin["payload"]["content"]["cost"], json!({
+            "estimated": {"tokens": 28, "usd_micros": 110},
+            "observed": {"tokens": 2 * 10 + 5, "usd_micros": 2 * 40 + 9},
+            "adapters": 2,
+        }));
+    }
+
+    #[tokio::test]
+    async fn mock_harness_frame_in_fin_out() {
+        let _g = ENV_LOCK.lock().await;
+        let eps = use_mocks(vec![
+            MockAdapter{ chunks: vec![("agent.result.partial", "draft"), ("agent.result.final", "paris")], estimate: EstimateResponse{ in_tokens: 10, out_tokens: 20, usd_micros: 7, ..Default::default() }, health: HealthResponse{ p95_ms: 12.0, error_rate: 0.0 }, ..Default::default() },
+            MockAdapter{ estimate_error: Some("no estimate"), stream_error: Some("down"), health_error: Some("sick"), ..Default::default() },
+        ]).await;
+        let out = run_request(test_frame("harness")).await;
+        assert_eq!(out.first().map(|m| m["flags"].clone()), Some(json!(["ACK"])));
+        let partial = out.iter().find(|m| m["adapter"] == json!(eps[0]) && m["payload"]["type"] == "agent.result.partial").expect("partial forwarded");
+        assert_eq!(partial["payload"]["content"], "draft");
+        assert!(out.iter().any(|m| m["payload"]["content"]["adapter_error"].is_object()));
+        let fin = out.last().unwrap();
+        assert_eq!(fin["flags"], json!(["FIN"]));
+        assert_eq!(fin["payload"]["content"]["finals"], json!(["\"paris\""]));
+        let health = adapters::check_endpoints(eps.clone()).await;
+        assert_eq!((health[0].ok, health[0].p95_ms, health[1].ok), (true, 12.0, false));
+    }
+
+    #[tokio::test]
+    async fn tenants_have_independent_window_accounting() {
+        let _g = ENV_LOCK.lock().await;
+        let mut acme = test_frame("shared-session");
+        acme.meta.tenant_id = Some("acme".into());
+        let mut globex = test_frame("shared-session");
+        globex.meta.data_scope = Some(vec!["pii".into(), "tenant:globex".into()]);
+        let (ka, kg) = (window_key(&acme, false), window_key(&globex, false));
+        assert_ne!(ka, kg);
+        assert_eq!(kg, "globex/shared-session:streamA");
+        let w = Window{ max_parallel: 1, max_tokens: 100, max_usd_micros: 100 };
+        GLOBAL_WINDOWS.admit(&ka, &w, 60, 0).await.unwrap();
+        assert!(GLOBAL_WINDOWS.admit(&ka, &w, 0, 0).await.is_err(), "acme is saturated");
+        GLOBAL_WINDOWS.admit(&kg, &w, 60, 0).await.expect("globex unaffected by acme");
+        GLOBAL_WINDOWS.ack(&ka, 60, 0).await;
+        GLOBAL_WINDOWS.ack(&kg, 60, 0).await;
+
+        // An aggregate cap of one request per tenant: acme's second stream is refused, globex still runs.
+        let ep = spawn_mock(MockAdapter{ chunks: vec![("agent.result.final", "ok")], chunk_delay: Duration::from_millis(300), ..Default::default() }).await;
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([ep]).to_string());
+        std::env::set_var("ATP_TENANT_MAX_PARALLEL", "1");
+        let first = tokio::spawn(run_request(acme.clone()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut acme2 = acme.clone();
+        acme2.stream_id = "streamB".into();
+        let refused = run_request(acme2).await;
+        let other = run_request(globex).await;
+        let first = first.await.unwrap();
+        std::env::remove_var("ATP_TENANT_MAX_PARALLEL");
+        assert_eq!((refused[0]["control.status"].clone(), refused[0]["scope"].clone(), refused[0]["saturated"].clone()), (json!("BUSY"), json!("tenant"), json!("parallel")));
+        assert!(other.iter().any(|m| m["flags"] == json!(["FIN"])));
+        assert!(first.iter().any(|m| m["flags"] == json!(["FIN"])));
+        assert_eq!(TENANT_WINDOWS.inner.read().await.get("acme").map(|w| w.inflight), Some(0));
+    }
+
+    #[tokio::test]
+    async fn capabilities_are_cached_and_steer_fanout() {
+        let _g = ENV_LOCK.lock().await;
+        let (ask_calls, code_calls, legacy_calls) = (std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0)), std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0)), std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0)));
+        let ask_caps = CapabilitiesResponse{ task_types: vec!["ask".into()], max_context_tokens: 8192, payload_types: vec!["text".into()], ..Default::default() };
+        let eps = use_mocks(vec![
+            MockAdapter{ chunks: vec![("agent.result.final", "asked")], streams: ask_calls.clone(), capabilities: Some(ask_caps), ..Default::default() },
+            MockAdapter{ chunks: vec![("agent.result.final", "coded")], streams: code_calls.clone(), capabilities: Some(CapabilitiesResponse{ task_types: vec!["code".into()], ..Default::default() }), ..Default::default() },
+            MockAdapter{ chunks: vec![("agent.result.final", "legacy")], streams: legacy_calls.clone(), ..Default::default() },
+        ]).await;
+        adapters::refresh_capabilities(&eps).await;
+        assert_eq!(adapters::cached_capabilities(&eps[0]), Some(adapters::Capabilities{ task_types: vec!["ask".into()], max_context_tokens: 8192, payload_types: vec!["text".into()], languages: vec![], ..Default::default() }));
+        assert!(adapters::cached_capabilities(&eps[2]).is_none(), "unimplemented RPC leaves the adapter unrestricted");
+        let out = run_request(test_frame("capabilities")).await;
+        let fin = out.iter().find(|m| m["flags"] == json!(["FIN"])).expect("final frame");
+        let mut finals: Vec<String> = fin["payload"]["content"]["finals"].as_array().unwrap().iter().map(|f| f.as_str().unwrap().to_string()).collect();
+        finals.sort();
+        assert_eq!(finals, ["\"asked\"", "\"legacy\""]);
+        let calls = |c: &std::sync::Arc<std::sync::atomic::AtomicUsize>| c.load(std::sync::atomic::Ordering::SeqCst);
+        assert_eq!((calls(&ask_calls), calls(&code_calls), calls(&legacy_calls)), (1, 0, 1));
+        let health = adapters::check_endpoints(eps.clone()).await;
+        assert_eq!(health[1].capabilities.as_ref().map(|c| c.task_types.clone()), Some(vec!["code".to_string()]));
+        let mut frame = test_frame("capabilities-none");
+        frame.meta.task_type = Some("translate".into());
+        std::env::set_var("ADAPTER_ENDPOINTS", json!([eps[0], eps[1]]).to_string());
+        let refused = run_request(frame).await;
+        adapters::forget_capabilities(&eps);
+        assert_eq!(refused[0]["error"], "no_capable_adapter");
+    }
+
+    #[tokio::test]
+    async fn languages_filter_fanout_by_capabilities() {
+        let _g = ENV_LOCK.lock().await;
+        let (en_calls, ja_calls) = (std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0)), std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0)));
+        let eps = use_mocks(vec![
+            MockAdapter{ chunks: vec![("agent.result.final", "hello")], streams: en_calls.clone(), capabilities: Some(CapabilitiesResponse{ languages: vec!["en".into()], ..Default::default() }), ..Default::default() },
+            MockAdapter{ chunks: vec![("agent.result.final", "konnichiwa")], streams: ja_calls.clone(), capabilities: Some(CapabilitiesResponse{ languages: vec!["ja".into(), "en".into()], ..Default::default() }), ..Default::default() },
+        ]).await;
+        adapters::refresh_capabilities(&eps).await;
+        let calls = |c: &std::sync::Arc<std::sync::atomic::AtomicUsize>| c.load(std::sync::atomic::Ordering::SeqCst);
+        let mut frame = test_frame("languages-ja");
+        frame.meta.languages = Some(vec!["ja-JP".into()]);
+        let out = run_request(frame).await;
+        assert_eq!(out.last().unwrap()["payload"]["content"]["finals"], json!(["\"konnichiwa\""]));
+        assert_eq!((calls(&en_calls), calls(&ja_calls)), (0, 1));
+        run_request(test_frame("languages-any")).await;
+        assert_eq!((calls(&en_calls), calls(&ja_calls)), (1, 2));
+        let mut frame = test_frame("languages-none");
+        frame.meta.languages = Some(vec!["fr".into()]);
+        let refused = run_request(frame).await;
+        adapters::forget_capabilities(&eps);
+        assert_eq!((refused[0]["error"].clone(), refused[0]["languages"].clone()), (json!("no_capable_adapter"), json!(["fr"])));
+    }
+
+    #[tokio::test]
+    async fn idle_scheduler_dispatches_without_polling_delay() {
+        let (dispatched_tx, mut dispatched_rx) = mpsc::unbounded_channel::<Instant>();
+        let sched = Scheduler::spawn(4, move |_item| { let tx = dispatched_tx.clone(); async move { let _ = tx.send(Instant::now()); } });
+        let (reply_tx, _reply_rx) = mpsc::channel::<String>(1);
+        // Let the loop go idle on empty lanes, then enqueue on the lowest-weight lane.
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let turns_idle = sched.stats.turns.load(std::sync::atomic::Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(sched.stats.turns.load(std::sync::atomic::Ordering::Relaxed), turns_idle, "idle loop kept spinning");
+        let mut worst = Duration::ZERO;
+        for i in 0..5 {
+            let enqueued = Instant::now();
+            sched.bronze.send(WorkItem{ frame: test_frame(&format!("idle-{i}")), reply_tx: reply_tx.clone(), slot: None, conn: None }).await.unwrap();
+            let at = tokio::time::timeout(Duration::from_secs(1), dispatched_rx.recv()).await.unwrap().unwrap();
+            worst = worst.max(at - enqueued);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(worst < Duration::from_millis(2), "dispatch took {:?}", worst);
+    }
+
+    #[tokio::test]
+    async fn busy_lane_is_not_blocked_by_empty_higher_lanes() {
+        let (dispatched_tx, mut dispatched_rx) = mpsc::unbounded_channel::<String>();
+        let sched = Scheduler::spawn(4, move |item| { let tx = dispatched_tx.clone(); async move { let _ = tx.send(item.frame.session_id); } });
+        let (reply_tx, _reply_rx) = mpsc::channel::<String>(1);
+        for i in 0..3 { sched.silver.send(WorkItem{ frame: test_frame(&format!("silver-{i}")), reply_tx: reply_tx.clone(), slot: None, conn: None }).await.unwrap(); }
+        for i in 0..3 { assert_eq!(tokio::time::timeout(Duration::from_secs(1), dispatched_rx.recv()).await.unwrap().unwrap(), format!("silver-{i}")); }
+    }
+
+    /// The golden wire corpus, shared with atp-schema's round-trip check; see its README for the update procedure.
+    const GOLDEN_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../atp-schema/testdata/wire");
+    /// Fields that differ run to run, replaced by `"<dynamic>"` before comparing.
+    const GOLDEN_DYNAMIC: &[&str] = &["/payload/content/resume_token", "/adapter"];
+
+    /// Compares one wire line byte for byte with `<name>.json`, or rewrites the golden under `ATP_UPDATE_GOLDEN=1`.
+    fn assert_golden(name: &str, line: &str) {
+        let mut v: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert_eq!(v.to_string(), line, "{name}: wire line is not in canonical serde_json form");
+        for p in GOLDEN_DYNAMIC { if let Some(x) = v.pointer_mut(p) { *x = json!("<dynamic>"); } }
+        let (got, path) = (v.to_string(), format!("{GOLDEN_DIR}/{name}.json"));
+        if std::env::var_os("ATP_UPDATE_GOLDEN").is_some() { std::fs::write(&path, format!("{got}\n")).unwrap(); return; }
+        let want = std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("{path}: {e}"));
+        assert_eq!(got, want.trim_end(), "{name} drifted from its golden; if the change is intended, regenerate with ATP_UPDATE_GOLDEN=1");
+    }
+
+    #[tokio::test]
+    async fn wire_output_matches_golden_corpus() {
+        let _g = ENV_LOCK.lock().await;
+        let chunks = vec![("agent.result.partial", "draft"), ("agent.result.final", "paris")];
+        use_mocks(vec![MockAdapter{ chunks: chunks.clone(), ..Default::default() }, MockAdapter{ chunks, ..Default::default() }]).await;
+        let (reply_tx, mut reply_rx) = mpsc::channel::<String>(128);
+        process_request(WorkItem{ frame: test_frame("golden"), reply_tx, slot: None, conn: None }).await;
+        let mut lines = vec![];
+        while let Ok(line) = reply_rx.try_recv() { lines.push(line); }
+        let first = |name: &str, pred: &dyn Fn(&serde_json::Value) -> bool| {
+            let line = lines.iter().find(|l| pred(&serde_json::from_str(l).unwrap())).unwrap_or_else(|| panic!("no {name} frame in {lines:?}"));
+            assert_golden(name, line);
+        };
+        first("ack", &|m| m["flags"] == json!(["ACK"]));
+        first("partial", &|m| m["payload"]["type"] == "agent.result.partial" && m["flags"] == json!(["MORE"]));
+        first("provisional", &|m| m["payload"]["type"] == "agent.result.provisional");
+        first("final", &|m| m["flags"] == json!(["FIN"]));
+
+        assert_golden("control-aborted", &abort_stream(&test_frame("golden"), None).to_string());
+        let (tx, mut rx) = mpsc::channel::<String>(4);
+        let conn = ConnState::uncapped();
+        let (mut item, _) = route_inbound(&serde_json::to_string(&test_frame("golden")).unwrap(), &tx, &conn).await.unwrap();
+        assert_golden("control-stream-limit", &ConnStreams::new(0).admit(&mut item).unwrap_err().to_string());
+        assert!(route_inbound("{not a frame", &tx, &conn).await.is_none());
+        assert_golden("error-invalid-frame", &rx.try_recv().unwrap());
+    }
+
+    #[tokio::test]
+    async fn candidates_stream_as_finals_accumulate() {
+        let _g = ENV_LOCK.lock().await;
+        let mock = |answer: &'static str, ms| MockAdapter{ chunks: vec![("agent.result.final", answer)], chunk_delay: Duration::from_millis(ms), ..Default::default() };
+        use_mocks(vec![mock("paris", 0), mock("london", 150), mock("paris", 300)]).await;
+        let mut frame = test_frame("candidates");
+        frame.flags = vec!["CANDIDATES".into()];
+        let out = run_request(frame).await;
+        let seen: Vec<(String, f64, u64)> = out.iter().filter(|m| m["payload"]["type"] == "agent.result.candidate")
+            .map(|m| { let c = &m["payload"]["content"]; (c["text"].as_str().unwrap().to_string(), (c["score"].as_f64().unwrap() * 100.0).round() / 100.0, c["group_size"].as_u64().unwrap()) })
+            .collect();
+        let (paris, london) = ("\"paris\"".to_string(), "\"london\"".to_string());
+        assert_eq!(seen, vec![(paris.clone(), 1.0, 1), (paris.clone(), 0.5, 1), (london.clone(), 0.5, 1), (paris.clone(), 0.67, 2), (london, 0.33, 1)]);
+        assert!(run_request(test_frame("no-candidates")).await.iter().all(|m| m["payload"]["type"] != "agent.result.candidate"), "off unless flagged");
+
+        let cs = consensus::compute(&[paris.clone(), paris], &CONSENSUS_CFG);
+        let mut sent = HashMap::new();
+        assert_eq!(candidate_frames(&test_frame("candidates"), &cs, &mut sent, 0.5).len(), 1);
+        assert!(candidate_frames(&test_frame("candidates"), &cs, &mut sent, 0.5).is_empty(), "an unchanged representative is not re-sent");
+        assert!(candidate_frames(&test_frame("candidates"), &consensus::compute(&["\"lyon\"".into()], &CONSENSUS_CFG), &mut HashMap::new(), 1.5).is_empty(), "below the floor");
+    }
+
+    #[tokio::test]
+    async fn content_schema_rejects_payload_missing_required_field() {
+        let dir = std::env::temp_dir().join(format!("atp-router-schemas-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("tool_call.json"), r#"{"type":"object","required":["name","args"],"properties":{"name":{"type":"string"},"args":{"type":"object"}}}"#).unwrap();
+        let schemas = content_schema::ContentSchemas::load(&dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = RouterBuilder::new().content_schemas(schemas).build();
+        let (out_tx, mut out_rx) = mpsc::channel::<String>(4);
+        let conn = ConnState::uncapped();
+        let tool_call = |session: &str, content: serde_json::Value| {
+            let mut f = test_frame(session);
+            f.meta.task_type = Some("tool_call".into());
+            f.payload.content = content;
+            serde_json::to_string(&f).unwrap()
+        };
+        let before = samples("router_content_schema_rejects_total", ("task_type", "tool_call")).len();
+        assert!(route_inbound(&tool_call("schema-missing", json!({"name": "search"})), &out_tx, &conn).await.is_none());
+        let reply: serde_json::Value = serde_json::from_str(&out_rx.try_recv().unwrap()).unwrap();
+        assert_eq!((&reply["error"], &reply["task_type"]), (&json!("content_schema_mismatch"), &json!("tool_call")));
+        assert_eq!((&reply["violations"][0]["path"], &reply["violations"][0]["schema_path"]), (&json!(""), &json!("/required")));
+        assert!(reply["violations"][0]["message"].as_str().unwrap().contains("args"), "{reply}");
+        assert!(route_inbound(&tool_call("schema-nested", json!({"name": "search", "args": []})), &out_tx, &conn).await.is_none());
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&out_rx.try_recv().unwrap()).unwrap()["violations"][0]["path"], "/args");
+        assert_eq!(samples("router_content_schema_rejects_total", ("task_type", "tool_call")).len(), before + 2);
+
+        assert!(route_inbound(&tool_call("schema-ok", json!({"name": "search", "args": {"q": "rust"}})), &out_tx, &conn).await.is_some());
+        assert!(route_inbound(&serde_json::to_string(&test_frame("schema-unregistered")).unwrap(), &out_tx, &conn).await.is_some(), "no schema for `ask`, so no validation");
+        content_schema::install(Default::default());
+    }
+
+    #[tokio::test]
+    async fn completed_request_records_every_phase() {
+        let _g = ENV_LOCK.lock().await;
+        use_mocks(vec![MockAdapter{ chunks: vec![("agent.result.partial", "draft"), ("agent.result.final", "done")], chunk_delay: Duration::from_millis(20), ..Default::default() }]).await;
+        const PHASES: [&str; 6] = ["estimate", "admission", "first_partial", "first_final", "streams", "finalize"];
+        let count = |phase| samples("router_phase_duration_ms", ("phase", phase)).len();
+        let before = PHASES.map(count);
+        std::env::set_var("ATP_FINAL_TIMING", "1");
+        let out = run_request(test_frame("phases")).await;
+        std::env::remove_var("ATP_FINAL_TIMING");
+        for (phase, n) in PHASES.iter().zip(before) { assert!(count(phase) > n, "no {phase} sample"); }
+        let timing = out.iter().find(|m| m["flags"] == json!(["FIN"])).unwrap()["payload"]["content"]["timing"].as_object().unwrap().clone();
+        let mut keys: Vec<String> = PHASES.map(|p| format!("{p}_ms")).into();
+        keys.sort();
+        assert_eq!(timing.keys().cloned().collect::<Vec<_>>(), keys);
+        assert!(timing["first_partial_ms"].as_f64().unwrap() >= 15.0 && timing["first_final_ms"].as_f64().unwrap() >= 15.0, "{timing:?}");
+        let untimed = run_request(test_frame("phases-off")).await;
+        assert!(untimed.iter().find(|m| m["flags"] == json!(["FIN"])).unwrap()["payload"]["content"].get("timing").is_none());
+    }
+
+    #[tokio::test]
+    async fn fake_adapters_complete_a_request_offline() {
+        let _g = ENV_LOCK.lock().await;
+        // A real adapter stays configured, to show fake mode never contacts it.
+        let streams = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        use_mocks(vec![MockAdapter{ chunks: vec![("agent.result.final", "real")], streams: streams.clone(), ..Default::default() }]).await;
+        std::env::set_var("ATP_FAKE_ADAPTERS", "true");
+        std::env::set_var("ATP_FAKE_ANSWERS", json!(["it is sunny", "it is sunny", "rain is expected"]).to_string());
+        std::env::set_var("ATP_FAKE_DELAY_MS", "5");
+        let (out_tx, mut out_rx) = mpsc::channel::<String>(128);
+        let conn = ConnState::uncapped();
+        // Lane workers live on the runtime of the test that first touches the scheduler, so the request is run directly.
+        let (item, _) = route_inbound(&serde_json::to_string(&test_frame("fake-mode")).unwrap(), &out_tx, &conn).await.expect("scheduled");
+        tokio::spawn(process_request(item));
+        let mut out = vec![];
+        while let Ok(Some(line)) = tokio::time::timeout(Duration::from_secs(5), out_rx.recv()).await {
+            let m: serde_json::Value = serde_json::from_str(&line).unwrap();
+            let fin = m["flags"] == json!(["FIN"]);
+            out.push(m);
+            if fin { break; }
+        }
+        for k in ["ATP_FAKE_ADAPTERS", "ATP_FAKE_ANSWERS", "ATP_FAKE_DELAY_MS"] { std::env::remove_var(k); }
+        let count = |ty: &str| out.iter().filter(|m| m["payload"]["type"] == ty && m.get("adapter").is_some()).count();
+        assert_eq!((out[0]["flags"].clone(), count("agent.result.partial"), count("agent.result.final")), (json!(["ACK"]), 3, 3), "{out:?}");
+        assert_eq!((&out[1]["adapter"], &out[1]["payload"]["content"]), (&json!("http://fake-adapter-0"), &json!("it is")));
+        assert!(out.iter().any(|m| m["payload"]["type"] == "agent.result.provisional"), "{out:?}");
+        let fin = &out.last().unwrap()["payload"]["content"];
+        assert_eq!((fin["groups"].as_array().unwrap().len(), &fin["representatives"][0][1]), (2, &json!("\"it is sunny\"")));
+        assert!(fin["cost"]["estimated"]["tokens"].as_u64().unwrap() > 0 && fin["cost"]["observed"]["tokens"] == 9, "{fin}");
+        assert_eq!(streams.load(std::sync::atomic::Ordering::SeqCst), 0, "no adapter was contacted");
+    }
+
+    #[tokio::test]
+    async fn ttl_one_request_replies_are_marked_last_hop() {
+        let _g = ENV_LOCK.lock().await;
+        use_mocks(vec![MockAdapter{ chunks: vec![("agent.result.partial", "draft"), ("agent.result.final", "done")], ..Default::default() }]).await;
+        let before = all_samples("router_ttl_last_hop_total").len();
+        let mut frame = test_frame("last-hop");
+        frame.ttl = 1;
+        let out = run_request(frame.clone()).await;
+        assert!(out.len() >= 4, "{out:?}");
+        for m in &out { assert_eq!((&m["ttl"], &m["control.status"]), (&json!(0), &json!("TTL_LAST_HOP")), "{m}"); }
+        assert_eq!(all_samples("router_ttl_last_hop_total").len(), before + out.len());
+        assert_eq!(abort_stream(&frame, None)["control.status"], "TTL_LAST_HOP");
+        assert!(run_request(test_frame("two-hops")).await.iter().all(|m| m.get("control.status").is_none()));
+    }
+
+    #[tokio::test]
+    async fn unary_adapters_answer_through_complete() {
+        let _g = ENV_LOCK.lock().await;
+        let counter = || std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let (fallback_streams, fallback_completes, advertised_streams, advertised_completes) = (counter(), counter(), counter(), counter());
+        let eps = use_mocks(vec![
+            MockAdapter{ chunks: vec![("agent.result.partial", "pa"), ("agent.result.final", "paris")], ..Default::default() },
+            MockAdapter{ unary: Some(("agent.result.final", "paris")), streams: fallback_streams.clone(), completes: fallback_completes.clone(), ..Default::default() },
+            MockAdapter{ unary: Some(("", "lyon")), streams: advertised_streams.clone(), completes: advertised_completes.clone(),
+                capabilities: Some(CapabilitiesResponse{ unary_only: true, ..Default::default() }), ..Default::default() },
+        ]).await;
+        adapters::refresh_capabilities(&eps).await;
+        let before = samples("router_unary_fallback_total", ("adapter", &eps[1])).len();
+        let out = run_request(test_frame("unary")).await;
+        adapters::forget_capabilities(&eps);
+        let fin = &out.iter().find(|m| m["flags"] == json!(["FIN"])).expect("final frame")["payload"]["content"];
+        let mut finals: Vec<&str> = fin["finals"].as_array().unwrap().iter().map(|f| f.as_str().unwrap()).collect();
+        finals.sort();
+        assert_eq!(finals, ["\"lyon\"", "\"paris\"", "\"paris\""], "{fin}");
+        let unary_final = out.iter().find(|m| m["adapter"] == json!(eps[2])).expect("unary answer forwarded");
+        assert_eq!(unary_final["payload"]["type"], "agent.result.final", "an untyped unary response is a final");
+        let calls = |c: &std::sync::Arc<std::sync::atomic::AtomicUsize>| c.load(std::sync::atomic::Ordering::SeqCst);
+        assert_eq!((calls(&fallback_streams), calls(&fallback_completes)), (1, 1), "Unimplemented stream falls back to complete");
+        assert_eq!((calls(&advertised_streams), calls(&advertised_completes)), (0, 1), "unary_only skips stream");
+        assert_eq!(samples("router_unary_fallback_total", ("adapter", &eps[1])).len(), before + 1);
+    }
+
+    #[tokio::test]
+    async fn consensus_threshold_from_meta_applies_to_one_request() {
+        let _g = ENV_LOCK.lock().await;
+        let groups = |threshold: Option<serde_json::Value>| async move {
+            use_mocks(vec![
+                MockAdapter{ chunks: vec![("agent.result.final", "the answer is 42")], ..Default::default() },
+                MockAdapter{ chunks: vec![("agent.result.final", "the answer is 42 for sure")], ..Default::default() },
+            ]).await;
+            let mut frame = test_frame("per-request-threshold");
+            frame.meta.trace = threshold.map(|t| json!({"consensus_threshold": t}));
+            let out = run_request(frame).await;
+            out.iter().find(|m| m["flags"] == json!(["FIN"])).expect("final frame")["payload"]["content"]["groups"].as_array().unwrap().len()
+        };
+        assert_eq!(groups(None).await, 2, "the default threshold keeps the answers apart");
+        assert_eq!(groups(Some(json!(0.1))).await, 1, "a looser threshold for this request merges them");
+        assert_eq!(groups(None).await, 2, "the next request is back on the default");
+        for ignored in [json!(1.5), json!(-0.1), json!("0.1")] { assert_eq!(groups(Some(ignored.clone())).await, 2, "{ignored} is ignored"); }
+    }
+
+    #[test]
+    fn consensus_threshold_from_meta_must_be_a_similarity() {
+        let base = consensus::ConsensusConfig::default();
+        let with = |t: serde_json::Value| { let mut m = test_frame("threshold").meta; m.trace = Some(json!({"consensus_threshold": t})); m };
+        assert_eq!(request_consensus_cfg(&with(json!(0.95)), &base).threshold(), 0.95);
+        for ignored in [json!(3.0), json!(-0.1), json!("high")] { assert_eq!(request_consensus_cfg(&with(ignored), &base).threshold(), base.threshold()); }
+    }
+}
@@ -0,0 +1,109 @@
+//! `GET /metrics/json`: the Prometheus exposition rendered by `/metrics`, restructured as JSON for pollers
+//! that cannot parse the text format.
+//!
+//! Series are grouped by the `# TYPE` line that precedes them; summaries and histograms fold their
+//! `_sum`, `_count`, quantile and bucket samples into one entry per label set.
+
+use serde_json::{json, Map, Value};
+
+type Labels = Vec<(String, String)>;
+
+/// Splits `name{k="v",...} value` into its parts; label values may contain escaped quotes and commas.
+fn parse_sample(line: &str) -> Option<(&str, Labels, f64)> {
+    let (series, value) = line.rsplit_once(' ')?;
+    let value: f64 = value.parse().ok()?;
+    let Some((name, rest)) = series.split_once('{') else { return Some((series, vec![], value)); };
+    let body = rest.strip_suffix('}')?;
+    let mut labels = vec![];
+    let mut chars = body.chars().peekable();
+    while chars.peek().is_some() {
+        let key: String = chars.by_ref().take_while(|c| *c != '=').collect();
+        if chars.next() != Some('"') { return None; }
+        let mut val = String::new();
+        loop {
+            match chars.next()? {
+                '\\' => match chars.next()? { 'n' => val.push('\n'), c => val.push(c) },
+                '"' => break,
+                c => val.push(c),
+            }
+        }
+        labels.push((key.trim_start_matches(',').to_string(), val));
+        if chars.peek() == Some(&',') { chars.next(); }
+    }
+    Some((name, labels, value))
+}
+
+fn label_map(labels: &[(String, String)]) -> Map<String, Value> {
+    labels.iter().map(|(k, v)| (k.clone(), json!(v))).collect()
+}
+
+/// One summary or histogram series being assembled from its component samples.
+struct Distribution { name: String, labels: Labels, count: Option<f64>, sum: Option<f64>, quantiles: Map<String, Value>, buckets: Map<String, Value> }
+
+/// Converts rendered Prometheus text into `{"counters": [...], "gauges": [...], "histograms": [...]}`.
+/// Histogram entries carry `count`, `sum` and, depending on how the exporter renders them, `quantiles` or `buckets`.
+pub fn render(prometheus: &str) -> Value {
+    let (mut counters, mut gauges) = (vec![], vec![]);
+    let mut dists: Vec<Distribution> = vec![];
+    let mut kind = ("", "");
+    for line in prometheus.lines() {
+        if let Some(rest) = line.strip_prefix("# TYPE ") {
+            if let Some(k) = rest.split_once(' ') { kind = k; }
+            continue;
+        }
+        if line.is_empty() || line.starts_with('#') { continue; }
+        let Some((name, mut labels, value)) = parse_sample(line) else { continue; };
+        match kind.1 {
+            "counter" => counters.push(json!({"name": name, "labels": label_map(&labels), "value": value})),
+            "gauge" => gauges.push(json!({"name": name, "labels": label_map(&labels), "value": value})),
+            "summary" | "histogram" => {
+                let base = kind.0;
+                let quantile = labels.iter().position(|(k, _)| k == "quantile").map(|i| labels.remove(i).1);
+                let le = labels.iter().position(|(k, _)| k == "le").map(|i| labels.remove(i).1);
+                let i = match dists.iter().position(|d| d.name == base && d.labels == labels) {
+                    Some(i) => i,
+                    None => { dists.push(Distribution { name: base.to_string(), labels, count: None, sum: None, quantiles: Map::new(), buckets: Map::new() }); dists.len() - 1 }
+                };
+                let d = &mut dists[i];
+                match name.strip_prefix(base) {
+                    Some("_count") => d.count = Some(value),
+                    Some("_sum") => d.sum = Some(value),
+                    Some("_bucket") => { if let Some(le) = le { d.buckets.insert(le, json!(value)); } }
+                    Some("") => { if let Some(q) = quantile { d.quantiles.insert(q, json!(value)); } }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    let histograms: Vec<Value> = dists.into_iter().map(|d| {
+        let mut h = json!({"name": d.name, "labels": label_map(&d.labels), "count": d.count, "sum": d.sum});
+        if !d.quantiles.is_empty() { h["quantiles"] = Value::Object(d.quantiles); }
+        if !d.buckets.is_empty() { h["buckets"] = Value::Object(d.buckets); }
+        h
+    }).collect();
+    json!({"counters": counters, "gauges": gauges, "histograms": histograms})
+}
+
+#[cfg(test)]
+mod tests { use super::*;
+    const RENDERED: &str = "# TYPE frames_tx_total counter\nframes_tx_total{kind=\"final\",qos=\"gold\"} 3\nframes_tx_total{kind=\"estimate\",qos=\"gold\"} 1\n\n# TYPE router_inflight gauge\nrouter_inflight 2\n\n# TYPE router_estimate_tokens summary\nrouter_estimate_tokens{quantile=\"0\"} 10\nrouter_estimate_tokens{quantile=\"0.5\"} 40\nrouter_estimate_tokens{quantile=\"0.99\"} 90\nrouter_estimate_tokens_sum 140\nrouter_estimate_tokens_count 3\n\n# TYPE router_request_duration_ms histogram\nrouter_request_duration_ms_bucket{qos=\"gold\",outcome=\"completed\",le=\"10\"} 0\nrouter_request_duration_ms_bucket{qos=\"gold\",outcome=\"completed\",le=\"+Inf\"} 1\nrouter_request_duration_ms_sum{qos=\"gold\",outcome=\"completed\"} 42\nrouter_request_duration_ms_count{qos=\"gold\",outcome=\"completed\"} 1\n";
+    #[test] fn counters_and_gauges_keep_labels() {
+        let v = render(RENDERED);
+        assert_eq!(v["counters"][0], json!({"name": "frames_tx_total", "labels": {"kind": "final", "qos": "gold"}, "value": 3.0}));
+        assert_eq!(v["counters"].as_array().unwrap().len(), 2);
+        assert_eq!(v["gauges"], json!([{"name": "router_inflight", "labels": {}, "value": 2.0}]));
+    }
+    #[test] fn summaries_and_histograms_fold_into_one_entry() {
+        let v = render(RENDERED);
+        assert_eq!(v["histograms"][0], json!({"name": "router_estimate_tokens", "labels": {}, "count": 3.0, "sum": 140.0, "quantiles": {"0": 10.0, "0.5": 40.0, "0.99": 90.0}}));
+        assert_eq!(v["histograms"][1]["labels"], json!({"qos": "gold", "outcome": "completed"}));
+        assert_eq!((v["histograms"][1]["count"].clone(), v["histograms"][1]["buckets"]["10"].clone()), (json!(1.0), json!(0.0)));
+        assert_eq!(v["histograms"][1]["buckets"]["+Inf"], json!(1.0));
+    }
+    #[test] fn escaped_label_values_parse() {
+        let (name, labels, value) = parse_sample(r#"x_total{reason="a \"b\", c",k="v"} 5"#).unwrap();
+        assert_eq!((name, value), ("x_total", 5.0));
+        assert_eq!(labels, [("reason".to_string(), "a \"b\", c".to_string()), ("k".into(), "v".into())]);
+    }
+}
@@ -0,0 +1,63 @@
+//! Offline replay of recorded frames through the scheduler, for reproducing routing bugs and load tests.
+//!
+//! Input is newline-delimited JSON frames. A line may carry an extra `ts_ms` field; with timing
+//! enabled the replay sleeps for the gap between consecutive timestamps before sending the next frame.
+
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+pub struct ReplayOptions { pub input: PathBuf, pub out: Option<PathBuf>, pub timing: bool }
+impl ReplayOptions {
+    /// `--replay <path> [--replay-out <path>] [--replay-timing]`, falling back to `ATP_REPLAY_FILE`,
+    /// `ATP_REPLAY_OUT` and `ATP_REPLAY_TIMING=true`. Returns `None` when no replay was requested.
+    pub fn from_env_and_args(args: impl Iterator<Item = String>) -> Option<Self> {
+        let (mut input, mut out, mut timing) = (None, None, false);
+        let mut args = args.peekable();
+        while let Some(a) = args.next() {
+            match a.as_str() {
+                "--replay" => input = args.next().map(PathBuf::from),
+                "--replay-out" => out = args.next().map(PathBuf::from),
+                "--replay-timing" => timing = true,
+                _ => {}
+            }
+        }
+        let input = input.or_else(|| std::env::var("ATP_REPLAY_FILE").ok().map(PathBuf::from))?;
+        let out = out.or_else(|| std::env::var("ATP_REPLAY_OUT").ok().map(PathBuf::from));
+        let timing = timing || std::env::var("ATP_REPLAY_TIMING").ok().as_deref() == Some("true");
+        Some(Self { input, out, timing })
+    }
+}
+
+/// Feeds every frame in `path` through the router and writes each reply line to `out`.
+/// Completes once all replayed requests have finished; returns the writer and the reply count.
+pub async fn run<W: Write + Send + 'static>(path: &Path, mut out: W, timing: bool) -> anyhow::Result<(W, usize)> {
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let (out_tx, mut out_rx) = mpsc::channel::<String>(128);
+    let writer = tokio::task::spawn_blocking(move || -> std::io::Result<(W, usize)> {
+        let mut n = 0;
+        while let Some(line) = out_rx.blocking_recv() { writeln!(out, "{}", line)?; n += 1; }
+        out.flush()?;
+        Ok((out, n))
+    });
+    let conn = crate::ConnState::uncapped().with_default_window(crate::load_default_window()?);
+    let mut last_ts: Option<u64> = None;
+    for line in file.lines() {
+        let line = line?;
+        if line.trim().is_empty() { continue; }
+        if timing {
+            let ts = serde_json::from_str::<serde_json::Value>(&line).ok().and_then(|v| v.get("ts_ms").and_then(|t| t.as_u64()));
+            if let (Some(prev), Some(ts)) = (last_ts, ts) { tokio::time::sleep(Duration::from_millis(ts.saturating_sub(prev))).await; }
+            if ts.is_some() { last_ts = ts; }
+        }
+        crate::ingest_text(&line, &out_tx, &conn).await;
+    }
+    drop(out_tx);
+    Ok(writer.await??)
+}
+
+#[cfg(test)]
+mod tests { use super::*;
+    #[test] fn options_from_args() { let o = ReplayOptions::from_env_and_args(["--replay", "in.ndjson", "--replay-out", "out.ndjson", "--replay-timing"].into_iter().map(String::from)).unwrap(); assert_eq!(o.input, PathBuf::from("in.ndjson")); assert_eq!(o.out, Some(PathBuf::from("out.ndjson"))); assert!(o.timing); }
+}
@@ -0,0 +1,36 @@
+//! Pre-routing frame transforms: operator hooks that rewrite or reject frames (redact prompts, inject default
+//! meta, normalize qos) after parsing and before control handling, sequencing and scheduling.
+//!
+//! Transforms are process-wide and run in registration order, each seeing the previous one's output.
+//! With none registered frames pass through unchanged.
+
+use atp_schema::Frame;
+use once_cell::sync::Lazy;
+use std::sync::{Arc, RwLock};
+
+/// Why a transform refused a frame; sent back to the client as `{"error":"frame_rejected","reason":...}`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RejectReason(pub String);
+
+pub trait FrameTransform: Send + Sync {
+    fn transform(&self, frame: Frame) -> Result<Frame, RejectReason>;
+}
+
+/// Passes frames through untouched.
+pub struct Noop;
+impl FrameTransform for Noop {
+    fn transform(&self, frame: Frame) -> Result<Frame, RejectReason> { Ok(frame) }
+}
+
+static TRANSFORMS: Lazy<RwLock<Vec<Arc<dyn FrameTransform>>>> = Lazy::new(|| RwLock::new(vec![]));
+
+/// Appends `t` to the chain applied to every inbound frame.
+pub fn register(t: impl FrameTransform + 'static) { TRANSFORMS.write().unwrap().push(Arc::new(t)); }
+/// Replaces the whole chain, as `RouterBuilder::build` does with the transforms it was given.
+pub(crate) fn install(chain: Vec<Arc<dyn FrameTransform>>) { *TRANSFORMS.write().unwrap() = chain; }
+
+/// Runs `frame` through every registered transform, stopping at the first rejection.
+pub(crate) fn apply(frame: Frame) -> Result<Frame, RejectReason> {
+    let chain = TRANSFORMS.read().unwrap().clone();
+    chain.iter().try_fold(frame, |f, t| t.transform(f))
+}
@@ -0,0 +1,17 @@
+//! Frame checksum throughput: verifying every fragment of a large message, the bulk path receivers hit.
+
+use atp_schema::{fragment_text_frame, validate_fragment_checksums, FrameBuilder};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+fn bulk_verification(c: &mut Criterion) {
+    let base = FrameBuilder::new("bench-session", "bench-stream").build().expect("frame");
+    let frags = fragment_text_frame(base, &"lorem ipsum dolor sit amet ".repeat(40_000), 8 * 1024);
+    let mut group = c.benchmark_group("checksum");
+    group.throughput(Throughput::Elements(frags.len() as u64));
+    group.bench_function("compute_fragment", |b| b.iter(|| frags[0].compute_checksum().expect("checksum")));
+    group.bench_function("validate_fragment_checksums", |b| b.iter(|| assert!(validate_fragment_checksums(&frags))));
+    group.finish();
+}
+
+criterion_group!(benches, bulk_verification);
+criterion_main!(benches);
@@ -3,12 +3,26 @@ use sha2::{Digest, Sha256};
 
 /// Default maximum bytes of text per fragment when no explicit policy is provided.
 pub const DEFAULT_MAX_FRAGMENT_BYTES: usize = 8 * 1024; // 8 KiB
+/// Default cap on fragments per reassembled message, so a flood of tiny MORE fragments can't grow without bound.
+pub const DEFAULT_MAX_FRAGMENTS: u32 = 4096;
+/// Default cap on the content bytes buffered for one message while its fragments arrive.
+pub const DEFAULT_MAX_MESSAGE_BYTES: usize = 4 * 1024 * 1024; // 4 MiB
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Window { pub max_parallel: u32, pub max_tokens: u64, pub max_usd_micros: u64 }
+/// An omitted window places no client-side limit; the router's own defaults and ceilings still apply.
+impl Default for Window {
+    fn default() -> Self { Window { max_parallel: u32::MAX, max_tokens: u64::MAX, max_usd_micros: u64::MAX } }
+}
+impl Window {
+    /// The tighter of two windows in every dimension.
+    pub fn intersect(&self, other: &Window) -> Window {
+        Window { max_parallel: self.max_parallel.min(other.max_parallel), max_tokens: self.max_tokens.min(other.max_tokens), max_usd_micros: self.max_usd_micros.min(other.max_usd_micros) }
+    }
+}
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CostEst { pub in_tokens: u64, pub out_tokens: u64, pub usd_micros: u64 }
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Meta {
     pub task_type: Option<String>,
     pub languages: Option<Vec<String>>,
@@ -18,6 +32,8 @@ pub struct Meta {
     pub tool_permissions: Option<Vec<String>>,
     pub environment_id: Option<String>,
     pub security_groups: Option<Vec<String>>,
+    /// Owning tenant; routers isolate window accounting per tenant.
+    pub tenant_id: Option<String>,
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Payload {
@@ -27,6 +43,10 @@ pub struct Payload {
     pub cost_est: Option<CostEst>,
     pub checksum: Option<String>,
     pub expiry_ms: Option<u64>,
+    /// On the terminal fragment of a fragmented message: checksum of the reassembled content string, so a valid
+    /// fragment spliced in from another message is caught. Omitted when absent, leaving frame checksums unchanged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_digest: Option<String>,
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Frame {
@@ -38,6 +58,7 @@ pub struct Frame {
     pub flags: Vec<String>,
     pub qos: String,
     pub ttl: u8,
+    #[serde(default)]
     pub window: Window,
     pub meta: Meta,
     pub payload: Payload,
@@ -47,68 +68,507 @@ pub struct Frame {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Finding { pub id: String, pub severity: Option<String>, pub claim: String, pub confidence: Option<f32>, pub provenance: Option<Vec<String>> }
 
+/// Merges findings reported by several sources, deduplicating by `id` in first-seen order.
+/// Duplicates keep the highest confidence, the first reported severity and the union of provenance.
+pub fn merge_findings(sets: &[Vec<Finding>]) -> Vec<Finding> {
+    let mut merged: Vec<Finding> = Vec::new();
+    for f in sets.iter().flatten() {
+        let Some(m) = merged.iter_mut().find(|m| m.id == f.id) else { merged.push(f.clone()); continue; };
+        m.confidence = match (m.confidence, f.confidence) { (Some(a), Some(b)) => Some(a.max(b)), (a, b) => a.or(b) };
+        if m.severity.is_none() { m.severity = f.severity.clone(); }
+        if let Some(extra) = &f.provenance {
+            let prov = m.provenance.get_or_insert_with(Vec::new);
+            for p in extra { if !prov.contains(p) { prov.push(p.clone()); } }
+        }
+    }
+    merged
+}
+
 pub fn fragment_text_frame(base: Frame, text: &str, max_fragment_bytes: usize) -> Vec<Frame> {
     if text.len() <= max_fragment_bytes { let mut f = base; f.flags.retain(|fl| fl != "MORE"); return vec![f.with_computed_checksum().expect("checksum")]; }
-    let bytes = text.as_bytes();
-    let total_chunks = (bytes.len() + max_fragment_bytes - 1) / max_fragment_bytes;
+    // Chunks are lossily decoded one by one, so a split code point reassembles as U+FFFD rather than `text`.
+    fragments_from_chunks(base, text.as_bytes().chunks(max_fragment_bytes).map(|c| String::from_utf8_lossy(c).into_owned()).collect())
+}
+
+/// Like [`fragment_text_frame`], but always carries `text` as `{"text": ...}` and sizes fragments so each serialized
+/// frame, envelope, checksums and JSON escaping included, stays within `max_wire_bytes`. Splits on char boundaries, so text round-trips exactly.
+/// Returns `None` when the envelope alone leaves no room for text.
+pub fn fragment_text_frame_for_wire(base: Frame, text: &str, max_wire_bytes: usize) -> Option<Vec<Frame>> {
+    let wire_len = |f: &Frame| serde_json::to_vec(f).map(|v| v.len()).unwrap_or(usize::MAX);
+    let mut single = base.clone();
+    single.payload.content = serde_json::json!({"text": text});
+    single.flags.retain(|fl| fl != "MORE");
+    let single = single.with_computed_checksum().ok()?;
+    if wire_len(&single) <= max_wire_bytes { return Some(vec![single]); }
+    // Worst-case envelope around an empty text: widest frag_seq, MORE, both checksums and the message digest.
+    let mut probe = base.clone();
+    probe.frag_seq = u32::MAX;
+    if !probe.flags.iter().any(|x| x=="MORE") { probe.flags.push("MORE".into()); }
+    probe.payload.content = serde_json::json!({"text": ""});
+    probe.payload.checksum = probe.payload.compute_checksum().ok();
+    probe.payload.message_digest = message_digest("").ok();
+    let budget = max_wire_bytes.checked_sub(wire_len(&probe.with_computed_checksum().ok()?))?;
+    if budget < 6 { return None; }
+    let mut chunks = vec![String::new()];
+    let mut used = 0;
+    for c in text.chars() {
+        let n = json_escaped_len(c);
+        if used + n > budget { chunks.push(String::new()); used = 0; }
+        chunks.last_mut().expect("chunk").push(c);
+        used += n;
+    }
+    Some(fragments_from_chunks(base, chunks))
+}
+
+/// Bytes `c` takes inside a serde_json string literal.
+fn json_escaped_len(c: char) -> usize {
+    match c { '"' | '\\' | '\n' | '\r' | '\t' | '\u{8}' | '\u{c}' => 2, c if (c as u32) < 0x20 => 6, c => c.len_utf8() }
+}
+
+/// One `{"text": chunk}` frame per chunk, MORE on all but the last, which carries the whole-message digest.
+fn fragments_from_chunks(base: Frame, chunks: Vec<String>) -> Vec<Frame> {
+    let reassembled: String = chunks.concat();
+    let total_chunks = chunks.len();
     let mut out = Vec::with_capacity(total_chunks);
-    for (i, chunk) in bytes.chunks(max_fragment_bytes).enumerate() {
+    for (i, chunk) in chunks.into_iter().enumerate() {
         let mut f = base.clone();
         f.frag_seq = i as u32;
-        f.payload.content = serde_json::json!({"text": String::from_utf8_lossy(chunk)});
+        f.payload.content = serde_json::json!({"text": chunk});
+        f.payload.checksum = f.payload.compute_checksum().ok();
         if i < total_chunks - 1 { if !f.flags.iter().any(|x| x=="MORE") { f.flags.push("MORE".into()); } } else { f.flags.retain(|fl| fl != "MORE"); }
+        if i == total_chunks - 1 { f.payload.message_digest = message_digest(&reassembled).ok(); }
         out.push(f.with_computed_checksum().expect("checksum"));
     }
     out
 }
 
-pub fn reassemble_text(frames: &[Frame]) -> Option<String> {
-    if frames.is_empty() { return None; }
-    for (idx, f) in frames.iter().enumerate() {
-        if f.frag_seq != idx as u32 { return None; }
-        if idx < frames.len()-1 && !f.flags.iter().any(|x| x=="MORE") { return None; }
-        if idx == frames.len()-1 && f.flags.iter().any(|x| x=="MORE") { return None; }
+/// Why a fragment sequence could not be reassembled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReassemblyError {
+    Empty,
+    OutOfOrder { expected: u32, got: u32 },
+    /// A fragment before the last lacks the MORE flag.
+    MissingMore { frag_seq: u32 },
+    /// The last fragment still carries MORE.
+    UnexpectedMore { frag_seq: u32 },
+    /// A fragment's content has no string under the expected key.
+    MissingKey { frag_seq: u32, key: String },
+    /// The reassembled content does not match the terminal fragment's `message_digest`.
+    DigestMismatch,
+    /// More fragments than the configured cap.
+    TooManyFragments { max: u32 },
+    /// More buffered content bytes than the configured cap.
+    TooLarge { max_bytes: usize },
+}
+impl std::fmt::Display for ReassemblyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "no fragments"),
+            Self::OutOfOrder { expected, got } => write!(f, "expected frag_seq {} but got {}", expected, got),
+            Self::MissingMore { frag_seq } => write!(f, "fragment {} is not last but lacks MORE", frag_seq),
+            Self::UnexpectedMore { frag_seq } => write!(f, "last fragment {} still carries MORE", frag_seq),
+            Self::MissingKey { frag_seq, key } => write!(f, "fragment {} has no string content[{:?}]", frag_seq, key),
+            Self::DigestMismatch => write!(f, "reassembled content does not match the message digest"),
+            Self::TooManyFragments { max } => write!(f, "message exceeds {} fragments", max),
+            Self::TooLarge { max_bytes } => write!(f, "message exceeds {} bytes", max_bytes),
+        }
     }
+}
+impl std::error::Error for ReassemblyError {}
+impl ReassemblyError {
+    /// Stable snake_case name of the variant, for metric labels.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::Empty => "empty",
+            Self::OutOfOrder { .. } => "out_of_order",
+            Self::MissingMore { .. } => "missing_more",
+            Self::UnexpectedMore { .. } => "unexpected_more",
+            Self::MissingKey { .. } => "missing_key",
+            Self::DigestMismatch => "digest_mismatch",
+            Self::TooManyFragments { .. } => "too_many_fragments",
+            Self::TooLarge { .. } => "too_large",
+        }
+    }
+}
+
+/// Joins `payload.content["text"]` across fragments; see [`reassemble_content`].
+pub fn reassemble_text(frames: &[Frame]) -> Result<String, ReassemblyError> { reassemble_content(frames, "text") }
+
+/// Joins the string under `key` in each fragment's content, checking `frag_seq` order and MORE flags, then the
+/// terminal fragment's `message_digest` when it carries one. At most [`DEFAULT_MAX_FRAGMENTS`] fragments are accepted.
+pub fn reassemble_content(frames: &[Frame], key: &str) -> Result<String, ReassemblyError> {
+    check_fragment_sequence(frames)?;
     let mut buf = String::new();
-    for f in frames { if let Some(obj) = f.payload.content.as_object() { if let Some(v) = obj.get("text") { if let Some(s) = v.as_str() { buf.push_str(s); } } } }
-    Some(buf)
+    for f in frames {
+        match f.payload.content.get(key).and_then(|v| v.as_str()) {
+            Some(s) => buf.push_str(s),
+            None => return Err(ReassemblyError::MissingKey { frag_seq: f.frag_seq, key: key.to_string() }),
+        }
+    }
+    match frames.last().and_then(|f| f.payload.message_digest.as_deref()) {
+        Some(digest) if !checksum_matches(digest, &buf) => Err(ReassemblyError::DigestMismatch),
+        _ => Ok(buf),
+    }
+}
+
+/// Checks that `frames` are fragments 0..n of one message: contiguous `frag_seq`, MORE on all but the last.
+fn check_fragment_sequence(frames: &[Frame]) -> Result<(), ReassemblyError> {
+    if frames.is_empty() { return Err(ReassemblyError::Empty); }
+    if frames.len() > DEFAULT_MAX_FRAGMENTS as usize { return Err(ReassemblyError::TooManyFragments { max: DEFAULT_MAX_FRAGMENTS }); }
+    for (idx, f) in frames.iter().enumerate() {
+        if f.frag_seq != idx as u32 { return Err(ReassemblyError::OutOfOrder { expected: idx as u32, got: f.frag_seq }); }
+        let more = f.flags.iter().any(|x| x=="MORE");
+        if idx < frames.len()-1 && !more { return Err(ReassemblyError::MissingMore { frag_seq: f.frag_seq }); }
+        if idx == frames.len()-1 && more { return Err(ReassemblyError::UnexpectedMore { frag_seq: f.frag_seq }); }
+    }
+    Ok(())
+}
+
+/// Reconstructs every message in a flat capture of interleaved frames. Frames are grouped by
+/// `(session_id, stream_id, msg_seq)` and ordered by `frag_seq` (a repeated `frag_seq` keeps its first copy); each
+/// group is checked like [`reassemble_content`], including the text digest when the last fragment carries one.
+/// Results come back in `(session_id, stream_id, msg_seq)` order, one per message.
+pub fn reassemble_all(frames: &[Frame]) -> Vec<Result<Vec<Frame>, ReassemblyError>> {
+    let mut groups: std::collections::BTreeMap<(&str, &str, u64), Vec<&Frame>> = std::collections::BTreeMap::new();
+    for f in frames { groups.entry((f.session_id.as_str(), f.stream_id.as_str(), f.msg_seq)).or_default().push(f); }
+    groups.into_values().map(|mut group| {
+        group.sort_by_key(|f| f.frag_seq);
+        group.dedup_by_key(|f| f.frag_seq);
+        let group: Vec<Frame> = group.into_iter().cloned().collect();
+        check_fragment_sequence(&group)?;
+        if group.last().is_some_and(|f| f.payload.message_digest.is_some()) { reassemble_text(&group)?; }
+        Ok(group)
+    }).collect()
 }
 
-#[derive(Default, Debug)]
-pub struct Reassembler { expected_next: u32, buffer: Vec<Frame>, complete: bool }
+/// How much of a fragmented message has been accepted so far. The total is unknown until the last
+/// fragment arrives, so only running counts are reported.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReassemblyProgress {
+    pub received: u32,
+    /// Length of each accepted fragment's `text` (its whole serialized `content` when it has no text).
+    pub bytes: usize,
+    pub last_seq: u32,
+}
+
+#[derive(Debug)]
+pub struct Reassembler { expected_next: u32, buffer: Vec<Frame>, complete: bool, progress: ReassemblyProgress, max_fragments: u32 }
+impl Default for Reassembler {
+    fn default() -> Self { Reassembler { expected_next: 0, buffer: Vec::new(), complete: false, progress: ReassemblyProgress::default(), max_fragments: DEFAULT_MAX_FRAGMENTS } }
+}
 impl Reassembler {
-    pub fn push(&mut self, frame: Frame) -> Option<Vec<Frame>> {
-        if self.complete { return None; }
-        if frame.frag_seq != self.expected_next { return None; }
+    /// Caps the fragments one message may span (default [`DEFAULT_MAX_FRAGMENTS`]).
+    pub fn with_max_fragments(mut self, max_fragments: u32) -> Self { self.max_fragments = max_fragments; self }
+    /// [`Reassembler::try_push`], with an aborted message reported as `None` like any incomplete one.
+    pub fn push(&mut self, frame: Frame) -> Option<Vec<Frame>> { self.try_push(frame).ok().flatten() }
+    /// Accepts the next in-order fragment, returning the whole message once its last fragment arrives. A fragment
+    /// past the cap aborts the message: buffered fragments are dropped and every later push is ignored.
+    pub fn try_push(&mut self, frame: Frame) -> Result<Option<Vec<Frame>>, ReassemblyError> {
+        if self.complete { return Ok(None); }
+        if frame.frag_seq != self.expected_next { return Ok(None); }
+        if self.progress.received >= self.max_fragments {
+            tracing::warn!(session_id=%frame.session_id, stream_id=%frame.stream_id, msg_seq=frame.msg_seq, max_fragments=self.max_fragments, "reassembly aborted: too many fragments");
+            self.complete = true;
+            self.buffer = Vec::new();
+            return Err(ReassemblyError::TooManyFragments { max: self.max_fragments });
+        }
         self.expected_next += 1;
         let is_last = !frame.flags.iter().any(|f| f=="MORE");
+        let content = &frame.payload.content;
+        self.progress.bytes += content.get("text").and_then(|t| t.as_str()).map(str::len).unwrap_or_else(|| content.to_string().len());
+        self.progress.received += 1;
+        self.progress.last_seq = frame.frag_seq;
         self.buffer.push(frame);
-        if is_last { self.complete = true; return Some(std::mem::take(&mut self.buffer)); }
-        None
+        if is_last { self.complete = true; return Ok(Some(std::mem::take(&mut self.buffer))); }
+        Ok(None)
+    }
+    /// Counters as of the last accepted fragment; out-of-order fragments don't move them.
+    pub fn progress(&self) -> ReassemblyProgress { self.progress }
+    /// Consumes the reassembler, returning any fragments buffered for an incomplete message.
+    pub fn take_partial(mut self) -> Vec<Frame> { std::mem::take(&mut self.buffer) }
+}
+impl Drop for Reassembler {
+    fn drop(&mut self) {
+        if !self.complete && !self.buffer.is_empty() {
+            let first = &self.buffer[0];
+            tracing::warn!(session_id=%first.session_id, stream_id=%first.stream_id, msg_seq=first.msg_seq, buffered=self.buffer.len(), "reassembler dropped with incomplete message");
+        }
     }
 }
 
+/// Digest used for frame and payload checksums, written as a `<name>:` prefix on the hex digest.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChecksumAlgorithm { #[default] Sha256, Blake3 }
+impl ChecksumAlgorithm {
+    pub fn name(self) -> &'static str { match self { Self::Sha256 => "sha256", Self::Blake3 => "blake3" } }
+    pub fn parse(s: &str) -> Option<Self> { match s { "sha256" => Some(Self::Sha256), "blake3" => Some(Self::Blake3), _ => None } }
+    /// Splits a stored checksum into its algorithm and hex digest; bare hex predates prefixes and is sha256.
+    /// Returns `None` for an unknown prefix.
+    pub fn split(checksum: &str) -> Option<(Self, &str)> {
+        match checksum.split_once(':') { Some((name, hex)) => Self::parse(name).map(|a| (a, hex)), None => Some((Self::Sha256, checksum)) }
+    }
+    fn digest_hex(self, bytes: &[u8]) -> String { let mut h = self.hasher(); h.update(bytes); h.finish_hex() }
+    fn hasher(self) -> StreamingHasher {
+        match self { Self::Sha256 => StreamingHasher::Sha256(Sha256::new()), Self::Blake3 => StreamingHasher::Blake3(Box::default()) }
+    }
+    /// `<name>:<hex>` digest of `value`'s canonical JSON encoding.
+    pub fn checksum<T: serde::Serialize + ?Sized>(self, value: &T) -> Result<String, serde_json::Error> {
+        Ok(format!("{}:{}", self.name(), self.digest_hex(&serde_json::to_vec(value)?)))
+    }
+}
+
+/// An `io::Write` sink that hashes whatever is serialized into it, so canonical JSON never has to be buffered.
+enum StreamingHasher { Sha256(Sha256), Blake3(Box<blake3::Hasher>) }
+impl StreamingHasher {
+    fn update(&mut self, bytes: &[u8]) {
+        match self { Self::Sha256(h) => h.update(bytes), Self::Blake3(h) => { h.update(bytes); } }
+    }
+    fn finish_hex(self) -> String {
+        match self { Self::Sha256(h) => format!("{:x}", h.finalize()), Self::Blake3(h) => h.finalize().to_hex().to_string() }
+    }
+}
+impl std::io::Write for StreamingHasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> { self.update(buf); Ok(buf.len()) }
+    fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+}
+
+/// Whether `checksum` (prefixed or bare hex) matches `value` under the algorithm it names.
+fn checksum_matches<T: serde::Serialize + ?Sized>(checksum: &str, value: &T) -> bool {
+    let Some((algo, hex)) = ChecksumAlgorithm::split(checksum) else { return false; };
+    serde_json::to_vec(value).map(|bytes| algo.digest_hex(&bytes).eq_ignore_ascii_case(hex)).unwrap_or(false)
+}
+
+/// SHA-256 of the canonical JSON encoding of a payload `content`.
+pub fn content_checksum(content: &serde_json::Value) -> Result<String, serde_json::Error> { ChecksumAlgorithm::Sha256.checksum(content) }
+/// SHA-256 of a whole reassembled message, as carried in [`Payload::message_digest`].
+pub fn message_digest(text: &str) -> Result<String, serde_json::Error> { ChecksumAlgorithm::Sha256.checksum(text) }
+
+impl Payload {
+    /// Hashes only `content`, so identical results share a checksum across frames regardless of type or confidence.
+    pub fn compute_checksum(&self) -> Result<String, serde_json::Error> { content_checksum(&self.content) }
+    pub fn with_computed_checksum(mut self) -> Result<Self, serde_json::Error> { self.checksum = Some(self.compute_checksum()?); Ok(self) }
+    pub fn verify_checksum(&self) -> bool { self.checksum.as_deref().is_some_and(|c| checksum_matches(c, &self.content)) }
+}
+
+/// What the frame checksum covers: the frame minus `checksum` and `sig`, encoded exactly as `serde_json::to_value`
+/// would (object keys sorted, `f32` widened to `f64`) but serialized straight into the hasher. Fields are declared
+/// in sorted key order; the exhaustive destructuring in `from_frame` stops a new `Frame` field from being skipped.
+#[derive(Serialize)]
+struct ChecksumInput<'a> {
+    flags: &'a [String], frag_seq: u32, meta: CanonicalMeta<'a>, msg_seq: u64, payload: CanonicalPayload<'a>,
+    qos: &'a str, session_id: &'a str, stream_id: &'a str, ttl: u8, v: u8, window: &'a Window,
+}
+#[derive(Serialize)]
+struct CanonicalMeta<'a> {
+    data_scope: &'a Option<Vec<String>>, environment_id: &'a Option<String>, languages: &'a Option<Vec<String>>, risk: &'a Option<String>,
+    security_groups: &'a Option<Vec<String>>, task_type: &'a Option<String>, tenant_id: &'a Option<String>, tool_permissions: &'a Option<Vec<String>>,
+    trace: &'a Option<serde_json::Value>,
+}
+#[derive(Serialize)]
+struct CanonicalPayload<'a> {
+    checksum: &'a Option<String>, confidence: Option<f64>, content: &'a serde_json::Value, cost_est: &'a Option<CostEst>, expiry_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_digest: &'a Option<String>,
+    r#type: &'a str,
+}
+impl<'a> ChecksumInput<'a> {
+    fn from_frame(frame: &'a Frame) -> Self {
+        let Frame { v, session_id, stream_id, msg_seq, frag_seq, flags, qos, ttl, window, meta, payload, sig: _, checksum: _ } = frame;
+        let Meta { task_type, languages, risk, data_scope, trace, tool_permissions, environment_id, security_groups, tenant_id } = meta;
+        let Payload { r#type, content, confidence, cost_est, checksum, expiry_ms, message_digest } = payload;
+        ChecksumInput {
+            flags, frag_seq: *frag_seq, msg_seq: *msg_seq, qos, session_id, stream_id, ttl: *ttl, v: *v, window,
+            meta: CanonicalMeta { data_scope, environment_id, languages, risk, security_groups, task_type, tenant_id, tool_permissions, trace },
+            payload: CanonicalPayload { checksum, confidence: confidence.map(f64::from), content, cost_est, expiry_ms: *expiry_ms, message_digest, r#type },
+        }
+    }
+}
+
+/// Scheduling class of a frame. Parsing is case-insensitive; frames carry the lowercase name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Qos { Gold, Silver, Bronze }
+impl Qos {
+    pub fn as_str(self) -> &'static str { match self { Self::Gold => "gold", Self::Silver => "silver", Self::Bronze => "bronze" } }
+    pub fn parse(s: &str) -> Option<Self> {
+        [Self::Gold, Self::Silver, Self::Bronze].into_iter().find(|q| q.as_str().eq_ignore_ascii_case(s))
+    }
+}
+impl Serialize for Qos {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> { serializer.serialize_str(self.as_str()) }
+}
+/// Unknown values are an error; callers that want a fallback parse [`Frame::qos`] themselves.
+impl<'de> Deserialize<'de> for Qos {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Self::parse(&s).ok_or_else(|| serde::de::Error::unknown_variant(&s, &["gold", "silver", "bronze"]))
+    }
+}
+
+/// A frame flag. Flags are case-sensitive on the wire; anything this version does not define is kept as `Other`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Flag { Ack, Candidates, Compressed, Delta, EstimateOnly, Fin, Jsonl, More, NoConsensus, Verbose, Other(String) }
+impl Flag {
+    /// The defined flags, in the same order as [`KNOWN_FLAGS`].
+    pub const KNOWN: [Flag; 10] = [Flag::Ack, Flag::Candidates, Flag::Compressed, Flag::Delta, Flag::EstimateOnly, Flag::Fin, Flag::Jsonl, Flag::More, Flag::NoConsensus, Flag::Verbose];
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Ack => "ACK", Self::Candidates => "CANDIDATES", Self::Compressed => "COMPRESSED", Self::Delta => "DELTA", Self::EstimateOnly => "ESTIMATE_ONLY",
+            Self::Fin => "FIN", Self::Jsonl => "JSONL", Self::More => "MORE", Self::NoConsensus => "NO_CONSENSUS", Self::Verbose => "VERBOSE",
+            Self::Other(s) => s,
+        }
+    }
+    pub fn parse(s: &str) -> Self { Self::KNOWN.into_iter().find(|f| f.as_str() == s).unwrap_or_else(|| Self::Other(s.to_string())) }
+    pub fn is_known(&self) -> bool { !matches!(self, Self::Other(_)) }
+}
+impl Serialize for Flag {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> { serializer.serialize_str(self.as_str()) }
+}
+impl<'de> Deserialize<'de> for Flag {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> { Ok(Self::parse(&String::deserialize(deserializer)?)) }
+}
+
+/// Every flag this protocol version defines, in canonical (byte) order.
+pub const KNOWN_FLAGS: &[&str] = &["ACK", "CANDIDATES", "COMPRESSED", "DELTA", "ESTIMATE_ONLY", "FIN", "JSONL", "MORE", "NO_CONSENSUS", "VERBOSE"];
+pub fn is_known_flag(flag: &str) -> bool { KNOWN_FLAGS.binary_search(&flag).is_ok() }
+
+/// A flag outside [`KNOWN_FLAGS`], rejected by strict normalization.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownFlag(pub String);
+impl std::fmt::Display for UnknownFlag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { write!(f, "unknown flag {:?}", self.0) }
+}
+impl std::error::Error for UnknownFlag {}
+
 impl Frame {
-    pub fn compute_checksum(&self) -> Result<String, serde_json::Error> {
-        let mut value = serde_json::to_value(self)?;
-        if let Some(obj) = value.as_object_mut() { obj.remove("checksum"); obj.remove("sig"); }
-        let canonical = serde_json::to_vec(&value)?;
-        let mut hasher = Sha256::new(); hasher.update(canonical); Ok(format!("{:x}", hasher.finalize()))
+    pub fn compute_checksum(&self) -> Result<String, serde_json::Error> { self.compute_checksum_with(ChecksumAlgorithm::Sha256) }
+    pub fn compute_checksum_with(&self, algo: ChecksumAlgorithm) -> Result<String, serde_json::Error> {
+        Ok(format!("{}:{}", algo.name(), self.checksum_hex(algo)?))
+    }
+    fn checksum_hex(&self, algo: ChecksumAlgorithm) -> Result<String, serde_json::Error> {
+        let mut hasher = algo.hasher();
+        // The serializer emits many tiny writes; batching them keeps per-call hasher overhead off the hot path.
+        let mut buffered = std::io::BufWriter::with_capacity(4096, &mut hasher);
+        serde_json::to_writer(&mut buffered, &ChecksumInput::from_frame(self))?;
+        std::io::Write::flush(&mut buffered).map_err(serde_json::Error::io)?;
+        drop(buffered);
+        Ok(hasher.finish_hex())
+    }
+    /// The qos as a [`Qos`], or `None` when it names no known class.
+    pub fn qos_enum(&self) -> Option<Qos> { Qos::parse(&self.qos) }
+    pub fn flags_enum(&self) -> Vec<Flag> { self.flags.iter().map(|f| Flag::parse(f)).collect() }
+    pub fn has_flag(&self, flag: Flag) -> bool { self.flags.iter().any(|f| f == flag.as_str()) }
+    /// Deduplicates and sorts `flags` so the same set always serializes, and so checksums, identically. In strict
+    /// mode the first unknown flag is an error and the flags are left as they were; otherwise unknown flags are kept.
+    pub fn normalize_flags(&mut self, strict: bool) -> Result<(), UnknownFlag> {
+        if strict { if let Some(f) = self.flags.iter().find(|f| !is_known_flag(f)) { return Err(UnknownFlag(f.clone())); } }
+        self.flags.sort_unstable();
+        self.flags.dedup();
+        Ok(())
+    }
+    /// Normalizes flags (leniently) before stamping, so frames differing only in flag order or repeats checksum alike.
+    pub fn with_computed_checksum(mut self) -> Result<Self, serde_json::Error> { let _ = self.normalize_flags(false); let c = self.compute_checksum()?; self.checksum = Some(c); Ok(self) }
+    /// Recomputes with whichever algorithm the stored checksum names, so frames stamped by older or newer peers still verify.
+    pub fn verify_checksum(&self) -> bool {
+        let Some((algo, hex)) = self.checksum.as_deref().and_then(ChecksumAlgorithm::split) else { return false; };
+        self.checksum_hex(algo).is_ok_and(|h| h.eq_ignore_ascii_case(hex))
     }
-    pub fn with_computed_checksum(mut self) -> Result<Self, serde_json::Error> { let c = self.compute_checksum()?; self.checksum = Some(c); Ok(self) }
-    pub fn verify_checksum(&self) -> bool { match (self.checksum.as_ref(), self.compute_checksum()) { (Some(existing), Ok(recalc)) => existing == &recalc, _ => false } }
+}
+
+/// Fluent construction of outgoing frames. Defaults: `v` 1, `msg_seq`/`frag_seq` 0, no flags, qos `silver`,
+/// ttl 8, a 4-parallel / 10k-token / 2M-usd-micro window, empty meta and an empty `text` payload.
+#[derive(Debug, Clone)]
+pub struct FrameBuilder { frame: Frame }
+impl FrameBuilder {
+    pub fn new(session_id: impl Into<String>, stream_id: impl Into<String>) -> Self {
+        FrameBuilder { frame: Frame {
+            v: 1, session_id: session_id.into(), stream_id: stream_id.into(), msg_seq: 0, frag_seq: 0, flags: vec![],
+            qos: "silver".into(), ttl: 8, window: Window { max_parallel: 4, max_tokens: 10_000, max_usd_micros: 2_000_000 }, meta: Meta::default(),
+            payload: Payload { r#type: "text".into(), content: serde_json::json!({"text": ""}), confidence: None, cost_est: None, checksum: None, expiry_ms: None, message_digest: None },
+            sig: None, checksum: None,
+        } }
+    }
+    pub fn msg_seq(mut self, msg_seq: u64) -> Self { self.frame.msg_seq = msg_seq; self }
+    pub fn frag_seq(mut self, frag_seq: u32) -> Self { self.frame.frag_seq = frag_seq; self }
+    pub fn flag(mut self, flag: impl Into<String>) -> Self { self.frame.flags.push(flag.into()); self }
+    pub fn qos(mut self, qos: impl Into<String>) -> Self { self.frame.qos = qos.into(); self }
+    pub fn ttl(mut self, ttl: u8) -> Self { self.frame.ttl = ttl; self }
+    pub fn window(mut self, window: Window) -> Self { self.frame.window = window; self }
+    pub fn meta(mut self, meta: Meta) -> Self { self.frame.meta = meta; self }
+    pub fn task_type(mut self, task_type: impl Into<String>) -> Self { self.frame.meta.task_type = Some(task_type.into()); self }
+    pub fn payload_type(mut self, ty: impl Into<String>) -> Self { self.frame.payload.r#type = ty.into(); self }
+    pub fn content(mut self, content: serde_json::Value) -> Self { self.frame.payload.content = content; self }
+    /// Sets a `{"text": ...}` payload.
+    pub fn text(self, text: impl Into<String>) -> Self { self.content(serde_json::json!({"text": text.into()})) }
+    pub fn confidence(mut self, confidence: f32) -> Self { self.frame.payload.confidence = Some(confidence); self }
+    pub fn sig(mut self, sig: impl Into<String>) -> Self { self.frame.sig = Some(sig.into()); self }
+    /// Finalizes the frame with its checksum computed.
+    pub fn build(self) -> Result<Frame, serde_json::Error> { self.frame.with_computed_checksum() }
 }
 
 pub fn validate_fragment_checksums(frames: &[Frame]) -> bool { frames.iter().all(|f| f.verify_checksum()) }
 
 #[cfg(test)]
 mod tests { use super::*; use proptest::prelude::*;
-    fn sample_frame() -> Frame { Frame { v:1, session_id:"sess1".into(), stream_id:"streamA".into(), msg_seq:42, frag_seq:0, flags: vec!["MORE".into()], qos:"gold".into(), ttl:5, window: Window{ max_parallel:4, max_tokens:10_000, max_usd_micros:2_000_000 }, meta: Meta{ task_type:Some("ask".into()), languages:None, risk:None, data_scope:None, trace:None, tool_permissions:None, environment_id:None, security_groups:None }, payload: Payload{ r#type:"text".into(), content: serde_json::json!({"text":"hello"}), confidence:Some(0.9), cost_est:None, checksum:None, expiry_ms:None }, sig:None, checksum:None } }
-    proptest! { #[test] fn prop_round_trip_random(msg_seq in 0u64..1_000_000, frag_seq in 0u32..1000, qos in prop_oneof![Just("gold".to_string()), Just("silver".to_string()), Just("bronze".to_string())], text in "[a-zA-Z0-9 ]{0,64}") { let frame = Frame { v:1, session_id:"sessX".into(), stream_id:"streamY".into(), msg_seq, frag_seq, flags: vec!["MORE".into()], qos: qos.clone(), ttl:5, window: Window{ max_parallel:8, max_tokens:50_000, max_usd_micros:5_000_000 }, meta: Meta{ task_type:Some("ask".into()), languages:None, risk:None, data_scope:None, trace:None, tool_permissions:None, environment_id:None, security_groups:None }, payload: Payload{ r#type:"text".into(), content: serde_json::json!({"text":text}), confidence:None, cost_est:None, checksum:None, expiry_ms:None }, sig:None, checksum:None }.with_computed_checksum().unwrap(); let json = serde_json::to_string(&frame).unwrap(); let back: Frame = serde_json::from_str(&json).unwrap(); prop_assert_eq!(frame.msg_seq, back.msg_seq); prop_assert_eq!(frame.frag_seq, back.frag_seq); let back_checksum_clone = back.checksum.clone(); prop_assert_eq!(frame.checksum, back_checksum_clone); let c2 = back.compute_checksum().unwrap(); prop_assert_eq!(back.checksum.unwrap(), c2); } }
+    fn sample_frame() -> Frame { Frame { v:1, session_id:"sess1".into(), stream_id:"streamA".into(), msg_seq:42, frag_seq:0, flags: vec!["MORE".into()], qos:"gold".into(), ttl:5, window: Window{ max_parallel:4, max_tokens:10_000, max_usd_micros:2_000_000 }, meta: Meta{ task_type:Some("ask".into()), languages:None, risk:None, data_scope:None, trace:None, tool_permissions:None, environment_id:None, security_groups:None, tenant_id:None }, payload: Payload{ r#type:"text".into(), content: serde_json::json!({"text":"hello"}), confidence:Some(0.9), cost_est:None, checksum:None, expiry_ms:None, message_digest:None }, sig:None, checksum:None } }
+    proptest! { #[test] fn prop_round_trip_random(msg_seq in 0u64..1_000_000, frag_seq in 0u32..1000, qos in prop_oneof![Just("gold".to_string()), Just("silver".to_string()), Just("bronze".to_string())], text in "[a-zA-Z0-9 ]{0,64}") { let frame = Frame { v:1, session_id:"sessX".into(), stream_id:"streamY".into(), msg_seq, frag_seq, flags: vec!["MORE".into()], qos: qos.clone(), ttl:5, window: Window{ max_parallel:8, max_tokens:50_000, max_usd_micros:5_000_000 }, meta: Meta{ task_type:Some("ask".into()), languages:None, risk:None, data_scope:None, trace:None, tool_permissions:None, environment_id:None, security_groups:None, tenant_id:None }, payload: Payload{ r#type:"text".into(), content: serde_json::json!({"text":text}), confidence:None, cost_est:None, checksum:None, expiry_ms:None, message_digest:None }, sig:None, checksum:None }.with_computed_checksum().unwrap(); let json = serde_json::to_string(&frame).unwrap(); let back: Frame = serde_json::from_str(&json).unwrap(); prop_assert_eq!(frame.msg_seq, back.msg_seq); prop_assert_eq!(frame.frag_seq, back.frag_seq); let back_checksum_clone = back.checksum.clone(); prop_assert_eq!(frame.checksum, back_checksum_clone); let c2 = back.compute_checksum().unwrap(); prop_assert_eq!(back.checksum.unwrap(), c2); } }
+    /// The checksum input as originally computed: the whole frame through `serde_json::Value`, minus `checksum` and `sig`.
+    fn value_checksum(frame: &Frame, algo: ChecksumAlgorithm) -> String { let mut v = serde_json::to_value(frame).unwrap(); let obj = v.as_object_mut().unwrap(); obj.remove("checksum"); obj.remove("sig"); algo.checksum(&v).unwrap() }
+    fn opt_strings() -> impl Strategy<Value = Option<Vec<String>>> { proptest::option::of(prop::collection::vec("\\PC{0,8}", 0..3)) }
+    prop_compose! { fn random_frame()(ids in ("\\PC{0,12}", "[a-z]{1,8}", any::<u64>(), any::<u32>(), any::<u8>(), any::<u8>()), flags in prop::collection::vec(prop_oneof![Just("MORE".to_string()), Just("FIN".to_string()), "[A-Z_]{1,6}"], 0..3), window in (any::<u32>(), any::<u64>(), any::<u64>()), meta in (proptest::option::of("\\PC{0,6}"), opt_strings(), opt_strings(), proptest::option::of(("[a-z]{1,4}", any::<i64>(), "\\PC{0,6}")), proptest::option::of("[a-z]{0,6}")), payload in ("\\PC{0,40}", proptest::option::of(any::<f32>()), proptest::option::of((any::<u64>(), any::<u64>(), any::<u64>())), proptest::option::of(any::<u64>()), any::<bool>(), any::<bool>()), sig in proptest::option::of("[a-f0-9]{0,8}")) -> Frame {
+        let trace = meta.3.map(|(k, n, s)| serde_json::json!({"z": s, k: n, "nested": {"b": [1.5, null, true], "a": n}}));
+        let mut f = sample_frame();
+        (f.session_id, f.stream_id, f.msg_seq, f.frag_seq, f.ttl, f.v) = ids;
+        f.flags = flags;
+        f.window = Window { max_parallel: window.0, max_tokens: window.1, max_usd_micros: window.2 };
+        f.meta = Meta { task_type: meta.0, languages: meta.1, risk: meta.4.clone(), data_scope: meta.2, trace, tool_permissions: None, environment_id: meta.4, security_groups: Some(vec![]), tenant_id: None };
+        let (text, confidence, cost, expiry_ms, with_digest, with_checksum) = payload;
+        f.payload = Payload { r#type: "agent.result.final".into(), content: serde_json::json!({"text": text, "n": expiry_ms, "list": [text, 0.25]}), confidence, cost_est: cost.map(|(i, o, u)| CostEst { in_tokens: i, out_tokens: o, usd_micros: u }), checksum: None, expiry_ms, message_digest: with_digest.then(|| message_digest(&text).unwrap()) };
+        if with_checksum { f.payload.checksum = f.payload.compute_checksum().ok(); }
+        f.sig = sig;
+        f
+    } }
+    proptest! { #[test] fn prop_streamed_checksum_matches_value_path(frame in random_frame()) { for algo in [ChecksumAlgorithm::Sha256, ChecksumAlgorithm::Blake3] { prop_assert_eq!(frame.compute_checksum_with(algo).unwrap(), value_checksum(&frame, algo)); } let stamped = frame.clone().with_computed_checksum().unwrap(); prop_assert!(stamped.verify_checksum()); let mut legacy = stamped.clone(); legacy.checksum = legacy.checksum.map(|c| c.trim_start_matches("sha256:").to_uppercase()); prop_assert!(legacy.verify_checksum()); } }
     #[test] fn round_trip_serialization() { let frame = sample_frame().with_computed_checksum().unwrap(); let json = serde_json::to_string(&frame).unwrap(); let de: Frame = serde_json::from_str(&json).unwrap(); assert_eq!(de.msg_seq, frame.msg_seq); assert_eq!(de.checksum, frame.checksum); }
     #[test] fn checksum_changes_on_mutation() { let mut frame = sample_frame().with_computed_checksum().unwrap(); let orig = frame.checksum.clone(); frame.payload.content = serde_json::json!({"text":"hello world"}); let new_sum = frame.compute_checksum().unwrap(); assert_ne!(orig.unwrap(), new_sum); }
     #[test] fn invalid_frame_missing_required_field() { let mut value = serde_json::to_value(sample_frame()).unwrap(); if let Some(obj) = value.as_object_mut() { obj.remove("session_id"); } let json = serde_json::to_string(&value).unwrap(); let de: Result<Frame, _> = serde_json::from_str(&json); assert!(de.is_err(), "Deserialization should fail without session_id"); }
     #[test] fn fragmentation_and_reassembly() { let base = sample_frame(); let text = "a".repeat(2050); let frags = fragment_text_frame(base, &text, 800); assert!(frags.len() >= 3); for (i,f) in frags.iter().enumerate() { if i < frags.len()-1 { assert!(f.flags.iter().any(|x| x=="MORE")); } else { assert!(!f.flags.iter().any(|x| x=="MORE")); } } let mut r = Reassembler::default(); let mut collected = Vec::new(); for f in frags.clone() { if let Some(done) = r.push(f) { collected = done; } } assert!(!collected.is_empty()); let re_text = reassemble_text(&collected).expect("reassembled"); assert_eq!(re_text, text); assert!(validate_fragment_checksums(&collected)); let mut r2 = Reassembler::default(); let mut out_none = 0; let mut rev = frags.clone(); rev.reverse(); for f in rev { if r2.push(f).is_none() { out_none += 1; } } assert!(out_none > 0); }
+    #[test] fn message_digest_catches_fragment_from_another_message() { let a = fragment_text_frame(sample_frame(), &"h".repeat(1200), 500); let mut other = sample_frame(); other.msg_seq = 43; let b = fragment_text_frame(other, &"i".repeat(1200), 500); assert_eq!(reassemble_text(&a).unwrap(), "h".repeat(1200)); let mut spliced = a.clone(); spliced[1] = b[1].clone(); assert!(validate_fragment_checksums(&spliced)); assert_eq!(reassemble_text(&spliced), Err(ReassemblyError::DigestMismatch)); let mut legacy = spliced.clone(); legacy.last_mut().unwrap().payload.message_digest = None; assert!(reassemble_text(&legacy).is_ok()); assert!(a[..2].iter().all(|f| f.payload.message_digest.is_none())); }
+    #[test] fn fragment_cap_aborts_reassembly() { let frags = fragment_text_frame(sample_frame(), &"j".repeat(100), 1); let mut r = Reassembler::default().with_max_fragments(10); for f in frags[..10].iter().cloned() { assert!(matches!(r.try_push(f), Ok(None))); } assert!(matches!(r.try_push(frags[10].clone()), Err(ReassemblyError::TooManyFragments { max: 10 }))); assert!(r.take_partial().is_empty()); let mut r = Reassembler::default().with_max_fragments(10); assert!(frags.iter().cloned().all(|f| r.push(f).is_none())); assert_eq!(r.progress().received, 10); let many = fragment_text_frame(sample_frame(), &"k".repeat(DEFAULT_MAX_FRAGMENTS as usize + 1), 1); assert_eq!(reassemble_text(&many), Err(ReassemblyError::TooManyFragments { max: DEFAULT_MAX_FRAGMENTS })); }
+    #[test] fn wire_fragments_fit_the_wire_limit() { let text = "say \"hi\"\n\t\u{1}日本語テキスト\\ ".repeat(200); let frags = fragment_text_frame_for_wire(sample_frame(), &text, 1024).unwrap(); assert!(frags.len() > 1); for f in &frags { let n = serde_json::to_vec(f).unwrap().len(); assert!(n <= 1024, "frag {} is {} bytes", f.frag_seq, n); } assert_eq!(reassemble_text(&frags).unwrap(), text); assert!(validate_fragment_checksums(&frags)); assert!(fragment_text_frame_for_wire(sample_frame(), &text, 200).is_none()); let short = fragment_text_frame_for_wire(sample_frame(), "hello", 1024).unwrap(); assert_eq!((short.len(), short[0].payload.content.clone()), (1, serde_json::json!({"text":"hello"}))); }
     #[test] fn fragmentation_missing_last_never_completes() { let base = sample_frame(); let text = "b".repeat(1500); let mut frags = fragment_text_frame(base, &text, 600); assert!(frags.len() > 2); frags.pop(); let mut r = Reassembler::default(); for f in frags { assert!(r.push(f).is_none()); } }
-    #[test] fn fragmentation_mid_fragment_missing_more_flag_detected() { let base = sample_frame(); let text = "c".repeat(1700); let mut frags = fragment_text_frame(base, &text, 500); assert!(frags.len() >= 3); if frags.len() > 2 { frags[1].flags.retain(|x| x!="MORE"); } assert!(reassemble_text(&frags).is_none()); }
+    #[test] fn take_partial_returns_buffered_fragments() { let base = sample_frame(); let text = "d".repeat(1500); let frags = fragment_text_frame(base, &text, 500); assert_eq!(frags.len(), 3); let mut r = Reassembler::default(); for f in frags.iter().take(2).cloned() { assert!(r.push(f).is_none()); } let partial = r.take_partial(); assert_eq!(partial.len(), 2); assert_eq!(partial[0].frag_seq, 0); assert_eq!(partial[1].frag_seq, 1); let mut done = Reassembler::default(); for f in frags { done.push(f); } assert!(done.take_partial().is_empty()); }
+    #[test] fn payload_checksum_tracks_content_only() { let a = sample_frame().payload; let mut b = a.clone(); b.r#type = "agent.result.final".into(); b.confidence = Some(0.1); assert_eq!(a.compute_checksum().unwrap(), b.compute_checksum().unwrap()); b.content = serde_json::json!({"text":"hello!"}); assert_ne!(a.compute_checksum().unwrap(), b.compute_checksum().unwrap()); let frags = fragment_text_frame(sample_frame(), &"e".repeat(1000), 400); assert!(frags.iter().all(|f| f.payload.checksum == f.payload.compute_checksum().ok())); assert_eq!(frags[0].payload.checksum, frags[1].payload.checksum); assert_ne!(frags[1].payload.checksum, frags[2].payload.checksum); }
+    #[test] fn builder_minimal_frame_has_defaults_and_checksum() { let f = FrameBuilder::new("s1", "st1").build().unwrap(); assert_eq!((f.v, f.msg_seq, f.frag_seq, f.ttl, f.qos.as_str()), (1, 0, 0, 8, "silver")); assert!(f.flags.is_empty()); assert!(f.meta.task_type.is_none()); assert_eq!(f.payload.r#type, "text"); assert!(f.checksum.is_some()); assert!(f.verify_checksum()); let json = serde_json::to_string(&f).unwrap(); assert!(serde_json::from_str::<Frame>(&json).unwrap().verify_checksum()); }
+    #[test] fn builder_setters_apply() { let f = FrameBuilder::new("s1", "st1").msg_seq(7).qos("gold").ttl(3).flag("MORE").task_type("ask").text("hi").confidence(0.5).build().unwrap(); assert_eq!((f.msg_seq, f.qos.as_str(), f.ttl), (7, "gold", 3)); assert_eq!(f.flags, ["MORE"]); assert_eq!(f.meta.task_type.as_deref(), Some("ask")); assert_eq!(f.payload.content, serde_json::json!({"text":"hi"})); assert_ne!(f.checksum, FrameBuilder::new("s1", "st1").build().unwrap().checksum); }
+    #[test] fn merge_findings_dedupes_by_id() { let f = |id: &str, conf: f32, prov: &str| Finding { id: id.into(), severity: None, claim: format!("claim {id}"), confidence: Some(conf), provenance: Some(vec![prov.into()]) }; let merged = merge_findings(&[vec![f("a", 0.4, "x"), f("b", 0.9, "x")], vec![f("a", 0.7, "y"), f("c", 0.5, "y")]]); assert_eq!(merged.iter().map(|m| m.id.as_str()).collect::<Vec<_>>(), ["a", "b", "c"]); assert_eq!(merged[0].confidence, Some(0.7)); assert_eq!(merged[0].provenance, Some(vec!["x".to_string(), "y".to_string()])); }
+    #[test] fn fragmentation_mid_fragment_missing_more_flag_detected() { let base = sample_frame(); let text = "c".repeat(1700); let mut frags = fragment_text_frame(base, &text, 500); assert!(frags.len() >= 3); if frags.len() > 2 { frags[1].flags.retain(|x| x!="MORE"); } assert_eq!(reassemble_text(&frags), Err(ReassemblyError::MissingMore { frag_seq: 1 })); }
+    #[test] fn fragment_missing_text_key_is_an_error() { let mut frags = fragment_text_frame(sample_frame(), &"f".repeat(1200), 500); frags[1].payload.content = serde_json::json!({"body": "fff"}); assert_eq!(reassemble_text(&frags), Err(ReassemblyError::MissingKey { frag_seq: 1, key: "text".into() })); frags.last_mut().unwrap().payload.message_digest = None; for f in frags.iter_mut() { let t = f.payload.content.get("text").cloned().unwrap_or(serde_json::json!("fff")); f.payload.content = serde_json::json!({"body": t}); } assert_eq!(reassemble_content(&frags, "body").unwrap().len(), 1200 - 500 + 3); }
+    #[test] fn checksum_algorithms_round_trip() { for algo in [ChecksumAlgorithm::Sha256, ChecksumAlgorithm::Blake3] { let mut f = sample_frame(); f.checksum = Some(f.compute_checksum_with(algo).unwrap()); assert!(f.checksum.as_deref().unwrap().starts_with(&format!("{}:", algo.name()))); assert!(f.verify_checksum()); let back: Frame = serde_json::from_str(&serde_json::to_string(&f).unwrap()).unwrap(); assert!(back.verify_checksum()); f.payload.content = serde_json::json!({"text":"tampered"}); assert!(!f.verify_checksum()); } let f = sample_frame(); assert_ne!(f.compute_checksum_with(ChecksumAlgorithm::Sha256).unwrap(), f.compute_checksum_with(ChecksumAlgorithm::Blake3).unwrap()); }
+    #[test] fn bare_hex_checksum_verifies_as_sha256() { let mut f = sample_frame(); let prefixed = f.compute_checksum().unwrap(); let (algo, hex) = ChecksumAlgorithm::split(&prefixed).unwrap(); assert_eq!(algo, ChecksumAlgorithm::Sha256); f.checksum = Some(hex.to_string()); assert!(f.verify_checksum()); let mut p = f.payload.clone().with_computed_checksum().unwrap(); p.checksum = p.checksum.map(|c| c.trim_start_matches("sha256:").to_string()); assert!(p.verify_checksum()); f.checksum = Some(format!("md5:{}", hex)); assert!(!f.verify_checksum()); }
+    #[test] fn reassembly_progress_advances_monotonically() { let frags = fragment_text_frame(sample_frame(), &"g".repeat(1300), 400); assert_eq!(frags.len(), 4); let mut r = Reassembler::default(); assert_eq!(r.progress(), ReassemblyProgress::default()); assert!(r.push(frags[2].clone()).is_none()); assert_eq!(r.progress().received, 0); let mut prev = r.progress(); for f in frags { let done = r.push(f); let p = r.progress(); assert_eq!(p.received, prev.received + 1); assert!(p.bytes > prev.bytes); assert_eq!(p.last_seq, p.received - 1); prev = p; if done.is_some() { assert_eq!(p.bytes, 1300); } } assert_eq!(prev.received, 4); }
+    #[test] fn omitted_window_is_unbounded() { let mut v = serde_json::to_value(sample_frame()).unwrap(); v.as_object_mut().unwrap().remove("window"); let f: Frame = serde_json::from_value(v).unwrap(); assert_eq!(f.window, Window::default()); assert_eq!(f.window.intersect(&sample_frame().window), sample_frame().window); }
+    #[test] fn normalize_flags_dedups_and_sorts() { let mut f = sample_frame(); f.flags = ["MORE", "ACK", "MORE", "X-CUSTOM", "ACK"].map(String::from).to_vec(); f.normalize_flags(false).unwrap(); assert_eq!(f.flags, ["ACK", "MORE", "X-CUSTOM"]); let mut known = sample_frame(); known.flags = vec!["FIN".into(), "FIN".into()]; known.normalize_flags(true).unwrap(); assert_eq!(known.flags, ["FIN"]); }
+    #[test] fn normalize_flags_is_order_independent_and_idempotent() { let mut sorted = KNOWN_FLAGS.iter().map(|f| f.to_string()).collect::<Vec<_>>(); sorted.sort(); assert_eq!(sorted, KNOWN_FLAGS); let mut a = sample_frame(); a.flags = vec!["VERBOSE".into(), "FIN".into(), "DELTA".into()]; let mut b = sample_frame(); b.flags = vec!["DELTA".into(), "VERBOSE".into(), "FIN".into(), "DELTA".into()]; let (a, b) = (a.with_computed_checksum().unwrap(), b.with_computed_checksum().unwrap()); assert_eq!(a.flags, b.flags); assert_eq!(a.checksum, b.checksum); let mut again = a.clone(); again.normalize_flags(true).unwrap(); assert_eq!(again.flags, a.flags); assert!(again.verify_checksum()); }
+    #[test] fn strict_normalization_rejects_unknown_flags() { let mut f = sample_frame(); f.flags = vec!["MORE".into(), "MORE".into(), "URGENT".into()]; assert_eq!(f.normalize_flags(true), Err(UnknownFlag("URGENT".into()))); assert_eq!(f.flags, ["MORE", "MORE", "URGENT"]); assert!(f.normalize_flags(false).is_ok()); assert_eq!(f.flags, ["MORE", "URGENT"]); assert!(!is_known_flag("more") && is_known_flag("NO_CONSENSUS")); }
+    #[test] fn qos_round_trips_and_parses_case_insensitively() { for (q, wire) in [(Qos::Gold, "gold"), (Qos::Silver, "silver"), (Qos::Bronze, "bronze")] { assert_eq!(serde_json::to_value(q).unwrap(), serde_json::json!(wire)); assert_eq!(serde_json::from_value::<Qos>(serde_json::json!(wire.to_uppercase())).unwrap(), q); let mut f = sample_frame(); f.qos = wire.to_uppercase(); assert_eq!(f.qos_enum(), Some(q)); } assert!(serde_json::from_value::<Qos>(serde_json::json!("platinum")).unwrap_err().to_string().contains("platinum")); let mut f = sample_frame(); f.qos = "golld".into(); assert_eq!(f.qos_enum(), None); }
+    #[test] fn flags_round_trip_and_keep_unknown_as_other() { assert_eq!(Flag::KNOWN.map(|f| f.as_str().to_string()), KNOWN_FLAGS); for flag in Flag::KNOWN { let wire = serde_json::to_value(&flag).unwrap(); assert_eq!(wire, serde_json::json!(flag.as_str())); assert_eq!(serde_json::from_value::<Flag>(wire).unwrap(), flag); } assert_eq!(serde_json::from_value::<Flag>(serde_json::json!("more")).unwrap(), Flag::Other("more".into())); let mut f = sample_frame(); f.flags = vec!["FIN".into(), "X-TRACE".into()]; assert_eq!(f.flags_enum(), [Flag::Fin, Flag::Other("X-TRACE".into())]); assert!(f.has_flag(Flag::Fin) && !f.has_flag(Flag::More) && !Flag::parse("X-TRACE").is_known()); assert_eq!(serde_json::to_value(f.flags_enum()).unwrap(), serde_json::json!(["FIN", "X-TRACE"])); }
+    fn interleaved_capture() -> (Vec<Frame>, String, String) {
+        let (a_text, b_text) = ("a".repeat(1300), "b".repeat(900));
+        let a = fragment_text_frame(sample_frame(), &a_text, 400);
+        let mut other = sample_frame(); other.msg_seq = 43;
+        let b = fragment_text_frame(other, &b_text, 400);
+        (a.into_iter().chain(b).collect(), a_text, b_text)
+    }
+    proptest! { #[test] fn prop_reassemble_all_splits_shuffled_messages(frames in Just(interleaved_capture().0).prop_shuffle()) {
+        let (_, a_text, b_text) = interleaved_capture();
+        let out = reassemble_all(&frames);
+        prop_assert_eq!(out.len(), 2);
+        let texts: Vec<String> = out.into_iter().map(|r| reassemble_text(&r.unwrap()).unwrap()).collect();
+        prop_assert_eq!(texts, vec![a_text, b_text]);
+    } }
+    #[test] fn reassemble_all_reports_each_message_separately() { let (mut frames, a_text, _) = interleaved_capture(); frames.retain(|f| !(f.msg_seq == 43 && f.frag_seq == 1)); frames.push(frames[1].clone()); frames.reverse(); let out = reassemble_all(&frames); assert_eq!(reassemble_text(out[0].as_ref().unwrap()).unwrap(), a_text); assert_eq!(out[1].as_ref().unwrap_err(), &ReassemblyError::OutOfOrder { expected: 1, got: 2 }); assert!(reassemble_all(&[]).is_empty()); }
+    #[test] fn window_intersect_takes_min_per_dimension() { let a = Window{ max_parallel: 8, max_tokens: 100, max_usd_micros: 5 }; let b = Window{ max_parallel: 2, max_tokens: 1000, max_usd_micros: 5 }; assert_eq!(a.intersect(&b), Window{ max_parallel: 2, max_tokens: 100, max_usd_micros: 5 }); assert_eq!(a.intersect(&b), b.intersect(&a)); }
+    /// Absent optional fields re-serialize as nulls, and `adapter` is a router-only envelope field, so neither counts as a change.
+    fn schema_fields(mut v: serde_json::Value) -> serde_json::Value { for k in ["meta", "payload"] { if let Some(o) = v[k].as_object_mut() { o.retain(|_, x| !x.is_null()); } } let top = v.as_object_mut().unwrap(); top.remove("adapter"); top.retain(|_, x| !x.is_null()); v }
+    #[test] fn golden_wire_frames_deserialize_unchanged() { let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/wire"); let mut frames = 0; for entry in std::fs::read_dir(dir).unwrap() { let path = entry.unwrap().path(); if path.extension().and_then(|e| e.to_str()) != Some("json") { continue; } let golden: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap(); if golden.get("payload").is_none() { continue; } let frame: Frame = serde_json::from_value(golden.clone()).unwrap_or_else(|e| panic!("{}: {e}", path.display())); assert_eq!(schema_fields(serde_json::to_value(&frame).unwrap()), schema_fields(golden), "{}", path.display()); frames += 1; } assert!(frames >= 5, "golden corpus missing from {dir}"); }
 }